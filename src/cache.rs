@@ -0,0 +1,251 @@
+use crate::{
+    package_spec::PackageSpec,
+    workspace::{self, CacheEntry, CacheEntryKind, PackageExt as _},
+    Context, OptCache,
+};
+use anyhow::bail;
+use itertools::Itertools as _;
+use prettytable::{format::FormatBuilder, row, Table};
+
+pub(crate) fn run(opt: OptCache, ctx: Context<'_>) -> anyhow::Result<()> {
+    let Context {
+        cwd,
+        cache_dir,
+        shell,
+    } = ctx;
+
+    match opt {
+        OptCache::List { spec } => {
+            let mut table = Table::new();
+
+            *table.get_format() = FormatBuilder::new().column_separator(' ').build();
+
+            for entry in workspace::cache_entries(&cache_dir)? {
+                if matches(&entry, spec.as_ref()) {
+                    table.add_row(row![entry.name, entry.key, describe(&entry)]);
+                }
+            }
+
+            for line in table.to_string().lines() {
+                writeln!(shell.out(), "{}", line.trim_end())?;
+            }
+        }
+        OptCache::Clear { spec } => {
+            let removed =
+                workspace::remove_cache_entries(&cache_dir, |e| matches(e, spec.as_ref()))?;
+            shell.status(
+                "Removed",
+                match removed.len() {
+                    1 => "1 cache entry".to_owned(),
+                    n => format!("{} cache entries", n),
+                },
+            )?;
+        }
+        OptCache::Refresh {
+            manifest_path,
+            spec,
+        } => {
+            let manifest_path = if let Some(manifest_path) = manifest_path {
+                cwd.join(manifest_path.strip_prefix(".").unwrap_or(&manifest_path))
+            } else {
+                workspace::locate_project(&cwd)?
+            };
+            let metadata = workspace::cargo_metadata(&manifest_path, &cwd, false)?;
+
+            let packages = metadata
+                .packages
+                .iter()
+                .filter(|p| p.source.is_some())
+                .filter(|p| spec.as_ref().is_none_or(|s| s.matches(p)))
+                .sorted_by_key(|p| &p.id)
+                .collect::<Vec<_>>();
+
+            if spec.is_some() && packages.is_empty() {
+                bail!("the spec did not match any package");
+            }
+
+            let (mut failures, mut skipped) = (0, 0);
+            for package in &packages {
+                // Nothing is cached for them, and they cannot be bundled either.
+                if package.chosen_license().is_none() {
+                    shell.warn(format!(
+                        "skipping `{}`: unsupported license: {}",
+                        package.id,
+                        package.license.as_deref().unwrap_or("(missing)"),
+                    ))?;
+                    skipped += 1;
+                    continue;
+                }
+                shell.status("Refreshing", &package.id)?;
+                if let Err(err) = workspace::refresh_cache(package, &cache_dir) {
+                    shell.warn(format!("{:#}", err))?;
+                    failures += 1;
+                }
+            }
+
+            if failures > 0 {
+                bail!(
+                    "could not refresh {}/{} package(s)",
+                    failures,
+                    packages.len() - skipped,
+                );
+            }
+            shell.status(
+                "Finished",
+                format!("{} package(s)", packages.len() - skipped),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn matches(entry: &CacheEntry, spec: Option<&PackageSpec>) -> bool {
+    spec.is_none_or(|spec| entry.matches(spec))
+}
+
+fn describe(entry: &CacheEntry) -> String {
+    match &entry.kind {
+        // In the format of `--mine`.
+        CacheEntryKind::Owners { host, users } => format!(
+            "owners: {}",
+            users
+                .iter()
+                .map(|user| format!("{}/{}", host, user))
+                .format(", "),
+        ),
+        CacheEntryKind::LicenseText { path } => format!("license text in `{}`", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{shell::Captured, Context, OptCache};
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    fn run(opt: OptCache, cwd: &Path, cache_dir: &Path) -> anyhow::Result<String> {
+        let stdout = Captured::default();
+        super::run(
+            opt,
+            Context {
+                cwd: cwd.to_owned(),
+                cache_dir: cache_dir.to_owned(),
+                shell: &mut stdout.shell(),
+            },
+        )?;
+        Ok(stdout.to_string_lossy())
+    }
+
+    #[test]
+    fn list_and_clear() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let cache_dir = &tempdir.path().join("cache");
+        let license_file = cache_dir.join("license-files").join("foo").join("abcdef");
+        cargo_util::paths::create_dir_all(license_file.with_file_name(""))?;
+        cargo_util::paths::write(&license_file, "MIT License\n")?;
+        cargo_util::paths::write(
+            cache_dir.join("owners.json"),
+            r#"{
+  "crates.io": { "foo": { "1.0.0": ["alice", "bob"] }, "bar": { "0.1.0": ["carol"] } },
+  "github.com": { "foo": { "abcdef": ["dave"] } },
+  "gitlab.com": { "baz": { "012345": ["erin"] } }
+}"#,
+        )?;
+
+        assert_eq!(
+            format!(
+                "bar 0.1.0  owners: crates.io/carol\n\
+                 baz 012345 owners: gitlab.com/erin\n\
+                 foo 1.0.0  owners: crates.io/alice, crates.io/bob\n\
+                 foo abcdef owners: github.com/dave\n\
+                 foo abcdef license text in `{}`\n",
+                license_file.display(),
+            ),
+            run(OptCache::List { spec: None }, tempdir.path(), cache_dir)?,
+        );
+        // License texts do not record versions.
+        assert_eq!(
+            format!(
+                "foo 1.0.0  owners: crates.io/alice, crates.io/bob\n\
+                 foo abcdef owners: github.com/dave\n\
+                 foo abcdef license text in `{}`\n",
+                license_file.display(),
+            ),
+            run(
+                OptCache::List {
+                    spec: Some("foo:1.0.0".parse().unwrap()),
+                },
+                tempdir.path(),
+                cache_dir,
+            )?,
+        );
+
+        run(
+            OptCache::Clear {
+                spec: Some("foo".parse().unwrap()),
+            },
+            tempdir.path(),
+            cache_dir,
+        )?;
+        assert!(!license_file.with_file_name("").exists());
+        assert_eq!(
+            "bar 0.1.0  owners: crates.io/carol\nbaz 012345 owners: gitlab.com/erin\n",
+            run(OptCache::List { spec: None }, tempdir.path(), cache_dir)?,
+        );
+
+        run(OptCache::Clear { spec: None }, tempdir.path(), cache_dir)?;
+        assert_eq!(
+            "",
+            run(OptCache::List { spec: None }, tempdir.path(), cache_dir)?,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_skips_unsupported_licenses() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let ws = &tempdir.path().join("ws");
+        let cache_dir = &tempdir.path().join("cache");
+        cargo_util::paths::create_dir_all(ws.join("src"))?;
+        cargo_util::paths::write(
+            ws.join("Cargo.toml"),
+            r#"[package]
+name = "app"
+version = "0.0.0"
+edition = "2018"
+
+[dependencies]
+unicode-ident = "=1.0.11"
+"#,
+        )?;
+        cargo_util::paths::write(ws.join("src").join("main.rs"), "fn main() {}\n")?;
+        // Generates the lockfile without accessing the network.
+        crate::workspace::cargo_metadata(&ws.join("Cargo.toml"), ws, true)?;
+
+        run(
+            OptCache::Refresh {
+                manifest_path: None,
+                spec: None,
+            },
+            ws,
+            cache_dir,
+        )?;
+        assert!(!cache_dir.exists());
+
+        let err = run(
+            OptCache::Refresh {
+                manifest_path: None,
+                spec: Some("itertools".parse().unwrap()),
+            },
+            ws,
+            cache_dir,
+        )
+        .unwrap_err();
+        assert_eq!("the spec did not match any package", err.to_string());
+        Ok(())
+    }
+}
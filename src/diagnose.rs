@@ -0,0 +1,381 @@
+use crate::{
+    shell::{Color, Shell},
+    workspace::TargetExt as _,
+    Minify, Remove, RootCrate,
+};
+use cargo_metadata as cm;
+use itertools::Itertools as _;
+use petgraph::visit::{Dfs, Reversed};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+};
+
+type LibsToBundle<'cm> = BTreeMap<&'cm cm::PackageId, (&'cm cm::Target, String)>;
+
+/// The transformations that can be turned off.
+#[derive(Clone)]
+pub(crate) struct Passes {
+    pub(crate) resolve_cfgs: bool,
+    pub(crate) remove: Vec<Remove>,
+    pub(crate) minify: Minify,
+    pub(crate) rustfmt: bool,
+    /// The following ones are for the libraries and are only turned off here.
+    pub(crate) translate_paths: bool,
+    pub(crate) modify_macros: bool,
+    pub(crate) resolve_preludes: bool,
+}
+
+impl Passes {
+    /// Returns the variants with one of the enabled transformations turned off, along with the
+    /// descriptions of them.
+    fn each_disabled(&self) -> Vec<(String, Self)> {
+        let mut variants = vec![];
+        if self.resolve_cfgs {
+            variants.push((
+                "`--no-resolve-cfgs`".to_owned(),
+                Self {
+                    resolve_cfgs: false,
+                    ..self.clone()
+                },
+            ));
+        }
+        for (remove, name) in [(Remove::Docs, "docs"), (Remove::Comments, "comments")] {
+            if self.remove.contains(&remove) {
+                variants.push((
+                    format!("no `--remove {}`", name),
+                    Self {
+                        remove: self
+                            .remove
+                            .iter()
+                            .copied()
+                            .filter(|&r| r != remove)
+                            .collect(),
+                        ..self.clone()
+                    },
+                ));
+            }
+        }
+        if self.minify != Minify::None {
+            variants.push((
+                "`--minify none`".to_owned(),
+                Self {
+                    minify: Minify::None,
+                    ..self.clone()
+                },
+            ));
+        }
+        if self.rustfmt {
+            variants.push((
+                "`--no-rustfmt`".to_owned(),
+                Self {
+                    rustfmt: false,
+                    ..self.clone()
+                },
+            ));
+        }
+        if self.translate_paths {
+            variants.push((
+                "the paths in the libraries untranslated".to_owned(),
+                Self {
+                    translate_paths: false,
+                    ..self.clone()
+                },
+            ));
+        }
+        if self.modify_macros {
+            variants.push((
+                "`macro_rules!` in the libraries unmodified".to_owned(),
+                Self {
+                    modify_macros: false,
+                    ..self.clone()
+                },
+            ));
+        }
+        if self.resolve_preludes {
+            variants.push((
+                "the pseudo preludes of the libraries unresolved".to_owned(),
+                Self {
+                    resolve_preludes: false,
+                    ..self.clone()
+                },
+            ));
+        }
+        variants
+    }
+
+    fn all_disabled() -> Self {
+        Self {
+            resolve_cfgs: false,
+            remove: vec![],
+            minify: Minify::None,
+            rustfmt: false,
+            translate_paths: false,
+            modify_macros: false,
+            resolve_preludes: false,
+        }
+    }
+}
+
+/// Bundles the code again with fewer crates and with fewer transformations to find out what broke
+/// it, and prints the smallest failing variant.
+///
+/// Each library is first bundled alone with its dependencies as if it were the root crate, from
+/// the one with the fewest dependencies. Then the first one that does not compile, or the whole
+/// code if every one compiles, is bundled with each of the enabled transformations turned off.
+#[allow(clippy::type_complexity)]
+pub(crate) fn diagnose<'cm>(
+    metadata: &'cm cm::Metadata,
+    root_crate: RootCrate<'cm>,
+    libs_to_bundle: &LibsToBundle<'cm>,
+    passes: &Passes,
+    bundle: &mut dyn FnMut(
+        RootCrate<'cm>,
+        &LibsToBundle<'cm>,
+        &Passes,
+        &mut Shell,
+    ) -> anyhow::Result<String>,
+    check: &mut dyn FnMut(RootCrate<'cm>, &str, &mut Shell) -> anyhow::Result<bool>,
+    shell: &mut Shell,
+) -> anyhow::Result<()> {
+    let quiet = &mut Shell::from_stdout(Box::new(io::sink()));
+
+    let mut compiles = |root_crate: RootCrate<'cm>,
+                        libs_to_bundle: &LibsToBundle<'cm>,
+                        passes: &Passes|
+     -> anyhow::Result<bool> {
+        // Failing to bundle is also a failure of the variant.
+        match bundle(root_crate, libs_to_bundle, passes, quiet) {
+            Ok(code) => check(root_crate, &code, quiet),
+            Err(_) => Ok(false),
+        }
+    };
+
+    let resolve_nodes = metadata
+        .resolve
+        .as_ref()
+        .map(|cm::Resolve { nodes, .. }| &nodes[..])
+        .unwrap_or(&[])
+        .iter()
+        .map(|node| (&node.id, node))
+        .collect::<HashMap<_, _>>();
+
+    let (graph, indices) = crate::normal_non_host_dep_graph(&resolve_nodes, libs_to_bundle);
+
+    let candidates = libs_to_bundle
+        .iter()
+        .filter(|(id, (krate, _))| krate.is_lib() && **id != &root_crate.package().id)
+        .map(|(&id, &(krate, _))| {
+            let mut libs = BTreeMap::new();
+            let mut dfs = Dfs::new(Reversed(&graph), indices[id]);
+            while let Some(next) = dfs.next(Reversed(&graph)) {
+                let dep = graph[next];
+                libs.insert(dep, libs_to_bundle[dep].clone());
+            }
+            libs.insert(id, (krate, krate.crate_name()));
+            (id, krate, libs)
+        })
+        .sorted_by_key(|(id, _, libs)| (libs.len(), *id))
+        .collect::<Vec<_>>();
+
+    let describe = |id: &cm::PackageId, libs: &LibsToBundle<'_>| {
+        let package = &metadata[id];
+        match libs.len() - 1 {
+            0 => format!("`{} v{}` alone", package.name, package.version),
+            n => format!(
+                "`{} v{}` with its {} dependency(ies)",
+                package.name, package.version, n,
+            ),
+        }
+    };
+
+    let mut culprit = None;
+    for (id, krate, libs) in &candidates {
+        shell.status("Diagnosing", describe(id, libs))?;
+        let root_crate = RootCrate::Lib(&metadata[id], krate);
+        if !compiles(root_crate, libs, passes)? {
+            culprit = Some((root_crate, libs, id));
+            break;
+        }
+    }
+
+    let (root_crate, libs_to_bundle) = if let Some((root_crate, libs, id)) = culprit {
+        shell.status_with_color(
+            "Found",
+            format!("{} does not compile", describe(id, libs)),
+            Color::Cyan,
+        )?;
+        (root_crate, libs)
+    } else {
+        shell.status_with_color(
+            "Found",
+            "every library compiles alone with its dependencies. diagnosing the whole code",
+            Color::Cyan,
+        )?;
+        (root_crate, libs_to_bundle)
+    };
+
+    let variants = passes.each_disabled();
+
+    let mut fixes = vec![];
+    for (option, passes) in &variants {
+        shell.status("Diagnosing", format!("with {}", option))?;
+        if compiles(root_crate, libs_to_bundle, passes)? {
+            fixes.push(&**option);
+        }
+    }
+
+    let mut compiles_only_with_all_disabled = false;
+    if fixes.is_empty() && variants.len() > 1 {
+        shell.status("Diagnosing", "with all of them")?;
+        compiles_only_with_all_disabled =
+            compiles(root_crate, libs_to_bundle, &Passes::all_disabled())?;
+    }
+
+    let finding = if !fixes.is_empty() {
+        format!(
+            "it compiles with {}. the transformation turned off is the cause",
+            fixes.iter().format(", or with "),
+        )
+    } else if compiles_only_with_all_disabled {
+        format!(
+            "it compiles only with all of {}. the transformations turned off are the cause \
+             together",
+            variants.iter().map(|(option, _)| option).format(", "),
+        )
+    } else {
+        "it does not compile with any transformation turned off. the cause is in the rest of the \
+         bundling, such as the edits to the root crate, or in the code itself"
+            .to_owned()
+    };
+    shell.status_with_color("Found", finding, Color::Cyan)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LibsToBundle, Passes};
+    use crate::{
+        shell::Shell,
+        workspace::{MetadataExt as _, PackageExt as _, TargetExt as _},
+        Minify, RootCrate,
+    };
+    use itertools::Itertools as _;
+    use pretty_assertions::assert_eq;
+    use std::{cell::RefCell, io};
+
+    #[test]
+    fn diagnose() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let ws = &tempdir.path().join("ws");
+        let manifest = |name: &str, deps: &[&str]| {
+            format!(
+                "[package]\nname = \"{}\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\n\
+                 [dependencies]\n{}",
+                name,
+                deps.iter()
+                    .map(|dep| format!("{} = {{ path = \"../{}\" }}\n", dep, dep))
+                    .join(""),
+            )
+        };
+        for (name, deps) in [
+            ("app", &["a", "c"][..]),
+            ("a", &["b"]),
+            ("b", &[]),
+            ("c", &[]),
+        ] {
+            let dir = ws.join(name);
+            cargo_util::paths::create_dir_all(dir.join("src"))?;
+            cargo_util::paths::write(dir.join("Cargo.toml"), manifest(name, deps))?;
+            let file = if name == "app" { "main.rs" } else { "lib.rs" };
+            cargo_util::paths::write(dir.join("src").join(file), "")?;
+        }
+        let metadata =
+            &crate::workspace::cargo_metadata(&ws.join("app").join("Cargo.toml"), ws, true)?;
+        let (target, package) = metadata.bin_target_by_name("app")?;
+        let root_crate = RootCrate::BinLike(package, target);
+        let libs_to_bundle = &metadata
+            .packages
+            .iter()
+            .filter(|p| p.name != "app")
+            .map(|p| {
+                let krate = p.lib_like_target().unwrap();
+                (&p.id, (krate, krate.crate_name()))
+            })
+            .collect::<LibsToBundle<'_>>();
+
+        // `a` breaks when its paths are translated.
+        let calls = RefCell::new(vec![]);
+        let describe = |root_crate: RootCrate<'_>, libs: &LibsToBundle<'_>, passes: &Passes| {
+            format!(
+                "{} [{}] translate_paths={} modify_macros={}",
+                root_crate.package().name,
+                libs.values().map(|(_, name)| name).format(", "),
+                passes.translate_paths,
+                passes.modify_macros,
+            )
+        };
+        let passes = &Passes {
+            resolve_cfgs: false,
+            remove: vec![],
+            minify: Minify::None,
+            rustfmt: false,
+            translate_paths: true,
+            modify_macros: true,
+            resolve_preludes: false,
+        };
+
+        let mut bundle = |root_crate, libs: &_, passes: &_, _: &mut _| {
+            let code = describe(root_crate, libs, passes);
+            calls.borrow_mut().push(code.clone());
+            Ok(code)
+        };
+
+        super::diagnose(
+            metadata,
+            root_crate,
+            libs_to_bundle,
+            passes,
+            &mut bundle,
+            &mut |_, code, _| Ok(!(code.contains("[a") && code.contains("translate_paths=true"))),
+            &mut Shell::from_stdout(Box::new(io::sink())),
+        )?;
+
+        assert_eq!(
+            vec![
+                "b [b] translate_paths=true modify_macros=true",
+                "c [c] translate_paths=true modify_macros=true",
+                "a [a, b] translate_paths=true modify_macros=true",
+                "a [a, b] translate_paths=false modify_macros=true",
+                "a [a, b] translate_paths=true modify_macros=false",
+            ],
+            calls.take(),
+        );
+
+        // Every library compiles alone, and the whole code compiles only with both turned off.
+        super::diagnose(
+            metadata,
+            root_crate,
+            libs_to_bundle,
+            passes,
+            &mut bundle,
+            &mut |_, code, _| Ok(!(code.starts_with("app ") && code.contains("=true"))),
+            &mut Shell::from_stdout(Box::new(io::sink())),
+        )?;
+
+        assert_eq!(
+            vec![
+                "b [b] translate_paths=true modify_macros=true",
+                "c [c] translate_paths=true modify_macros=true",
+                "a [a, b] translate_paths=true modify_macros=true",
+                "app [a, b, c] translate_paths=false modify_macros=true",
+                "app [a, b, c] translate_paths=true modify_macros=false",
+                "app [a, b, c] translate_paths=false modify_macros=false",
+            ],
+            calls.take(),
+        );
+        Ok(())
+    }
+}
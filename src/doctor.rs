@@ -0,0 +1,317 @@
+use crate::{
+    ra_proc_macro::MSRV,
+    rustfmt,
+    shell::{Color, Shell},
+    toolchain, Context, OptDoctor,
+};
+use anyhow::anyhow;
+use camino::Utf8PathBuf;
+use cargo_util::ProcessBuilder;
+use std::path::Path;
+
+pub(crate) fn run(opt: OptDoctor, ctx: Context<'_>) -> anyhow::Result<()> {
+    let OptDoctor {
+        toolchain_for_udeps,
+        toolchain_for_proc_macro_srv,
+    } = opt;
+
+    let Context {
+        cwd,
+        cache_dir,
+        shell,
+    } = ctx;
+
+    let cwd = &Utf8PathBuf::from_path_buf(cwd)
+        .map_err(|cwd| anyhow!("the current directory is not UTF-8: {}", cwd.display()))?;
+
+    let rustup_exe = toolchain::rustup_exe(cwd).ok();
+
+    // Runs `rustup` without printing anything, and returns whether it succeeded.
+    let rustup_succeeds = |args: &[&str]| {
+        rustup_exe.as_ref().is_some_and(|rustup_exe| {
+            ProcessBuilder::new(rustup_exe)
+                .args(args)
+                .cwd(cwd)
+                .exec_with_output()
+                .is_ok()
+        })
+    };
+
+    let rustup = match &rustup_exe {
+        Some(rustup_exe) => {
+            report(
+                shell,
+                Status::Ok,
+                format!("`rustup` ({})", rustup_exe.display()),
+                None,
+            )?;
+            true
+        }
+        None => {
+            report(
+                shell,
+                Status::Missing,
+                "`rustup` is not found",
+                Some("curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh".to_owned()),
+            )?;
+            false
+        }
+    };
+
+    let active_toolchain = rustup
+        .then(|| toolchain::active_toolchain(cwd).ok())
+        .flatten();
+
+    if let Some(active_toolchain) = &active_toolchain {
+        report(
+            shell,
+            Status::Ok,
+            format!("active toolchain: `{}`", active_toolchain),
+            None,
+        )?;
+    } else if rustup {
+        report(
+            shell,
+            Status::Missing,
+            "no active toolchain",
+            Some("rustup default stable".to_owned()),
+        )?;
+    }
+
+    if rustup {
+        let installed = rustup_succeeds(&["run", &toolchain_for_udeps, "rustc", "-V"]);
+        let udeps = installed
+            && rustup_succeeds(&["run", &toolchain_for_udeps, "cargo", "udeps", "--version"]);
+        if udeps {
+            report(
+                shell,
+                Status::Ok,
+                format!("`cargo-udeps` with `{}`", toolchain_for_udeps),
+                None,
+            )?;
+        } else if !installed {
+            report(
+                shell,
+                Status::Degraded,
+                format!(
+                    "`{}` is not installed for `cargo-udeps`. unused dependencies will be bundled",
+                    toolchain_for_udeps,
+                ),
+                Some(format!("rustup toolchain install {}", toolchain_for_udeps)),
+            )?;
+        } else {
+            report(
+                shell,
+                Status::Degraded,
+                format!(
+                    "`cargo-udeps` is not installed for `{}`. unused dependencies will be bundled",
+                    toolchain_for_udeps,
+                ),
+                Some(format!(
+                    "cargo +{} install cargo-udeps --locked",
+                    toolchain_for_udeps,
+                )),
+            )?;
+        }
+    }
+
+    if let Some(toolchain) = toolchain_for_proc_macro_srv.or_else(|| active_toolchain.clone()) {
+        let version = rustup_succeeds(&["run", &toolchain, "rustc", "-V"])
+            .then(|| toolchain::rustc_version(cwd, &toolchain))
+            .transpose()?;
+        match version {
+            Some(version) if version < MSRV => {
+                report(
+                    shell,
+                    Status::Missing,
+                    format!(
+                        "`{}` is Rust {}, but Rust ≧{} is required for expanding procedural \
+                         macros. pass `--toolchain-for-proc-macro-srv stable` after installing it",
+                        toolchain, version, MSRV,
+                    ),
+                    Some("rustup toolchain install stable --component rust-analyzer".to_owned()),
+                )?;
+            }
+            Some(_) => {
+                let srv = toolchain::rust_analyzer_proc_macro_srv_path(cwd, &toolchain)?;
+                let srv = Path::new(srv.as_os_str());
+                if srv.exists() {
+                    report(
+                        shell,
+                        Status::Ok,
+                        format!("`rust-analyzer-proc-macro-srv` ({})", srv.display()),
+                        None,
+                    )?;
+                } else {
+                    report(
+                        shell,
+                        Status::Missing,
+                        format!(
+                            "`rust-analyzer-proc-macro-srv` is not installed for `{}`. procedural \
+                             macros cannot be expanded",
+                            toolchain,
+                        ),
+                        Some(format!(
+                            "rustup component add rust-analyzer --toolchain {}",
+                            toolchain,
+                        )),
+                    )?;
+                }
+            }
+            None => {
+                report(
+                    shell,
+                    Status::Missing,
+                    format!(
+                        "`{}` is not installed. procedural macros cannot be expanded",
+                        toolchain,
+                    ),
+                    Some(format!(
+                        "rustup toolchain install {} --component rust-analyzer",
+                        toolchain,
+                    )),
+                )?;
+            }
+        }
+    }
+
+    match rustfmt::rustfmt_exe() {
+        Ok(rustfmt_exe) if rustfmt::is_available(&rustfmt_exe) => {
+            report(
+                shell,
+                Status::Ok,
+                format!("`rustfmt` ({})", rustfmt_exe.display()),
+                None,
+            )?;
+        }
+        Ok(rustfmt_exe) => {
+            report(
+                shell,
+                Status::Degraded,
+                format!(
+                    "`{}` is unavailable. the built-in formatter, which removes non-doc \
+                     comments, will be used",
+                    rustfmt_exe.display(),
+                ),
+                Some(match &active_toolchain {
+                    Some(toolchain) => {
+                        format!("rustup component add rustfmt --toolchain {}", toolchain)
+                    }
+                    None => "rustup component add rustfmt".to_owned(),
+                }),
+            )?;
+        }
+        Err(err) => {
+            report(shell, Status::Missing, err, None)?;
+        }
+    }
+
+    let writable = cargo_util::paths::create_dir_all(&cache_dir)
+        .and_then(|()| Ok(tempfile::tempfile_in(&cache_dir)?))
+        .is_ok();
+    if writable {
+        report(
+            shell,
+            Status::Ok,
+            format!("cache directory ({})", cache_dir.display()),
+            None,
+        )?;
+    } else {
+        report(
+            shell,
+            Status::Missing,
+            format!("cache directory ({}) is not writable", cache_dir.display()),
+            Some(format!(
+                "mkdir -p {0} && chmod u+w {0}",
+                cache_dir.display()
+            )),
+        )?;
+    }
+
+    return Ok(());
+
+    enum Status {
+        Ok,
+        Degraded,
+        Missing,
+    }
+
+    fn report(
+        shell: &mut Shell,
+        status: Status,
+        message: impl std::fmt::Display,
+        remedy: Option<String>,
+    ) -> anyhow::Result<()> {
+        let (status, color) = match status {
+            Status::Ok => ("OK", Color::Green),
+            Status::Degraded => ("Degraded", Color::Yellow),
+            Status::Missing => ("Missing", Color::Red),
+        };
+        shell.status_with_color(status, message, color)?;
+        if let Some(remedy) = remedy {
+            shell.status_with_color("", format!("run `{}`", remedy), color)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{shell::Captured, Context, OptDoctor};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn run() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let run = |cache_dir| -> anyhow::Result<_> {
+            let stderr = Captured::default();
+            super::run(
+                OptDoctor {
+                    toolchain_for_udeps: "cargo-equip-test-missing".to_owned(),
+                    toolchain_for_proc_macro_srv: Some("cargo-equip-test-missing".to_owned()),
+                },
+                Context {
+                    cwd: tempdir.path().to_owned(),
+                    cache_dir,
+                    shell: &mut stderr.stderr_shell(),
+                },
+            )?;
+            // The lines for `rustup` and `rustfmt` depend on the environment.
+            Ok(stderr
+                .to_string_lossy()
+                .lines()
+                .filter(|l| !(l.contains("rustup`") || l.contains("rustfmt")))
+                .filter(|l| !l.contains("active toolchain"))
+                .map(|l| format!("{}\n", l.trim_start()))
+                .collect::<String>())
+        };
+
+        let cache_dir = tempdir.path().join("cache");
+        assert_eq!(
+            format!(
+                "Degraded `cargo-equip-test-missing` is not installed for `cargo-udeps`. unused \
+                 dependencies will be bundled\n\
+                 run `rustup toolchain install cargo-equip-test-missing`\n\
+                 Missing `cargo-equip-test-missing` is not installed. procedural macros cannot be \
+                 expanded\n\
+                 run `rustup toolchain install cargo-equip-test-missing --component \
+                 rust-analyzer`\n\
+                 OK cache directory ({})\n",
+                cache_dir.display(),
+            ),
+            run(cache_dir.clone())?,
+        );
+
+        // A file cannot be the cache directory.
+        let cache_dir = tempdir.path().join("file");
+        cargo_util::paths::write(&cache_dir, "")?;
+        assert!(run(cache_dir.clone())?.ends_with(&format!(
+            "Missing cache directory ({0}) is not writable\n\
+             run `mkdir -p {0} && chmod u+w {0}`\n",
+            cache_dir.display(),
+        )));
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+use crate::shell::{Color, Shell};
+use cargo_metadata as cm;
+use itertools::{iproduct, Itertools as _};
+use petgraph::Direction;
+use std::{
+    collections::{hash_map, BTreeMap, HashMap, HashSet, VecDeque},
+    io,
+};
+
+type LibsToBundle<'cm> = BTreeMap<&'cm cm::PackageId, (&'cm cm::Target, String)>;
+
+/// Returns warnings for the crates bundled in more than one version, with the dependency paths
+/// that require each version.
+pub(crate) fn warnings(
+    metadata: &cm::Metadata,
+    root_package: &cm::Package,
+    libs_to_bundle: &LibsToBundle<'_>,
+) -> Vec<String> {
+    let resolve_nodes = resolve_nodes(metadata);
+    let (graph, indices) = crate::normal_non_host_dep_graph(&resolve_nodes, libs_to_bundle);
+
+    // Breadth-first search from the root, so that each path is one of the shortest.
+    let mut parents = HashMap::new();
+    let mut queue = if let Some(&index) = indices.get(&root_package.id) {
+        parents.insert(index, None);
+        VecDeque::from([index])
+    } else {
+        let mut queue = VecDeque::new();
+        for dep in resolve_nodes
+            .get(&root_package.id)
+            .into_iter()
+            .flat_map(|node| &node.deps)
+        {
+            if let Some(&index) = indices.get(&dep.pkg) {
+                if parents.insert(index, None).is_none() {
+                    queue.push_back(index);
+                }
+            }
+        }
+        queue
+    };
+    while let Some(index) = queue.pop_front() {
+        for dep in graph
+            .neighbors_directed(index, Direction::Incoming)
+            .sorted_by_key(|&i| graph[i])
+        {
+            if let hash_map::Entry::Vacant(entry) = parents.entry(dep) {
+                entry.insert(Some(index));
+                queue.push_back(dep);
+            }
+        }
+    }
+
+    duplicates(metadata, libs_to_bundle)
+        .into_iter()
+        .map(|versions| {
+            let lines = versions.iter().map(|id| {
+                let mut path = vec![];
+                let mut next = indices.get(id).copied();
+                while let Some(index) = next {
+                    let package = &metadata[graph[index]];
+                    path.push(format!("{} v{}", package.name, package.version));
+                    next = parents.get(&index).copied().flatten();
+                }
+                if !indices.contains_key(&root_package.id) {
+                    path.push(root_package.name.clone());
+                }
+                let (_, pseudo_extern_crate_name) = &libs_to_bundle[id];
+                format!(
+                    "\n  v{} as `{}`: {}",
+                    metadata[id].version,
+                    pseudo_extern_crate_name,
+                    path.iter().rev().format(" -> "),
+                )
+            });
+            format!(
+                "`{}` is bundled in {} versions, which are separate crates with incompatible \
+                 types{}",
+                metadata[versions[0]].name,
+                versions.len(),
+                lines.format(""),
+            )
+        })
+        .collect()
+}
+
+/// Makes the dependents of each version of the crates bundled in more than one version use
+/// another version, and keeps the changes with which the bundled code still compiles.
+///
+/// Returns the rewired metadata if any change is kept.
+#[allow(clippy::type_complexity)]
+pub(crate) fn unify(
+    metadata: &cm::Metadata,
+    root_package: &cm::Package,
+    libs_to_bundle: &dyn for<'a> Fn(&'a cm::Metadata) -> anyhow::Result<LibsToBundle<'a>>,
+    compiles: &mut dyn FnMut(&cm::Metadata, &LibsToBundle<'_>, &mut Shell) -> anyhow::Result<bool>,
+    shell: &mut Shell,
+) -> anyhow::Result<Option<cm::Metadata>> {
+    let quiet = &mut Shell::from_stdout(Box::new(io::sink()));
+
+    let mut unified = None::<cm::Metadata>;
+    let mut tried = HashSet::new();
+
+    loop {
+        let current = unified.as_ref().unwrap_or(metadata);
+        let libs = libs_to_bundle(current)?;
+
+        // Older versions are replaced with newer ones first.
+        let candidate = duplicates(current, &libs)
+            .into_iter()
+            .flat_map(|versions| {
+                iproduct!(versions.clone(), versions.into_iter().rev())
+                    .filter(|(from, to)| from != to)
+                    .collect::<Vec<_>>()
+            })
+            .map(|(from, to)| (from.clone(), to.clone()))
+            .find(|candidate| !tried.contains(candidate));
+
+        let Some((from, to)) = candidate else {
+            break;
+        };
+        tried.insert((from.clone(), to.clone()));
+
+        let dependents = resolve_nodes(current)
+            .into_values()
+            .filter(|node| {
+                (libs.contains_key(&node.id) || node.id == root_package.id)
+                    && node.deps.iter().any(|d| d.pkg == from)
+            })
+            .map(|node| &node.id)
+            .sorted()
+            .collect::<Vec<_>>();
+
+        let message = format!(
+            "`{}` v{} with v{} for {}",
+            current[&from].name,
+            current[&from].version,
+            current[&to].version,
+            dependents
+                .iter()
+                .map(|id| format!("`{} v{}`", current[id].name, current[id].version))
+                .format(", "),
+        );
+
+        shell.status("Unifying", &message)?;
+        let rewired = rewire(current, &dependents, &from, &to);
+        let compiles = {
+            let libs = libs_to_bundle(&rewired)?;
+            compiles(&rewired, &libs, quiet)?
+        };
+        if compiles {
+            shell.status_with_color("Unified", message, Color::Cyan)?;
+            unified = Some(rewired);
+        } else {
+            shell.status_with_color(
+                "Skipped",
+                format!("{}. the bundled code does not compile", message),
+                Color::Yellow,
+            )?;
+        }
+    }
+
+    Ok(unified)
+}
+
+/// Groups the crates to bundle that are in more than one version, sorted by the versions.
+fn duplicates<'cm>(
+    metadata: &cm::Metadata,
+    libs_to_bundle: &LibsToBundle<'cm>,
+) -> Vec<Vec<&'cm cm::PackageId>> {
+    libs_to_bundle
+        .keys()
+        .copied()
+        .into_group_map_by(|id| &metadata[id].name)
+        .into_iter()
+        .filter(|(_, ids)| ids.len() > 1)
+        .sorted_by_key(|&(name, _)| name)
+        .map(|(_, ids)| {
+            ids.into_iter()
+                .sorted_by_key(|id| &metadata[id].version)
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns a copy of the metadata in which `dependents` depend on `to` instead of `from`.
+fn rewire(
+    metadata: &cm::Metadata,
+    dependents: &[&cm::PackageId],
+    from: &cm::PackageId,
+    to: &cm::PackageId,
+) -> cm::Metadata {
+    let mut metadata = metadata.clone();
+    for node in metadata
+        .resolve
+        .iter_mut()
+        .flat_map(|cm::Resolve { nodes, .. }| nodes)
+        .filter(|node| dependents.contains(&&node.id))
+    {
+        for dep in &mut node.deps {
+            if dep.pkg == *from {
+                dep.pkg = to.clone();
+            }
+        }
+        for dep in &mut node.dependencies {
+            if dep == from {
+                *dep = to.clone();
+            }
+        }
+        node.dependencies.sort();
+        node.dependencies.dedup();
+    }
+    metadata
+}
+
+fn resolve_nodes(metadata: &cm::Metadata) -> HashMap<&cm::PackageId, &cm::Node> {
+    metadata
+        .resolve
+        .as_ref()
+        .map(|cm::Resolve { nodes, .. }| &nodes[..])
+        .unwrap_or(&[])
+        .iter()
+        .map(|node| (&node.id, node))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LibsToBundle;
+    use crate::{
+        shell::Shell,
+        workspace::{PackageExt as _, TargetExt as _},
+    };
+    use cargo_metadata as cm;
+    use itertools::Itertools as _;
+    use pretty_assertions::assert_eq;
+    use std::{collections::BTreeMap, io};
+
+    #[test]
+    fn warnings_and_unify() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let ws = &tempdir.path().join("ws");
+        for (dir, name, version, deps) in [
+            ("app", "app", "0.0.0", &["a", "dup02"][..]),
+            ("a", "a", "0.0.0", &["dup01"]),
+            ("dup01", "dup", "0.1.0", &[]),
+            ("dup02", "dup", "0.2.0", &[]),
+        ] {
+            let dir = ws.join(dir);
+            cargo_util::paths::create_dir_all(dir.join("src"))?;
+            cargo_util::paths::write(
+                dir.join("Cargo.toml"),
+                format!(
+                    "[package]\nname = \"{}\"\nversion = \"{}\"\nedition = \"2018\"\n\n\
+                     [dependencies]\n{}",
+                    name,
+                    version,
+                    deps.iter()
+                        .map(|dep| {
+                            let name = dep.trim_end_matches(char::is_numeric);
+                            format!("{} = {{ path = \"../{}\" }}\n", name, dep)
+                        })
+                        .join(""),
+                ),
+            )?;
+            let file = if name == "app" { "main.rs" } else { "lib.rs" };
+            cargo_util::paths::write(dir.join("src").join(file), "")?;
+        }
+        let metadata =
+            &crate::workspace::cargo_metadata(&ws.join("app").join("Cargo.toml"), ws, true)?;
+        let root_package = metadata.packages.iter().find(|p| p.name == "app").unwrap();
+
+        // The libraries reachable from `app`, named in the way of `libs_to_bundle`.
+        fn libs_to_bundle(metadata: &cm::Metadata) -> anyhow::Result<LibsToBundle<'_>> {
+            let resolve_nodes = super::resolve_nodes(metadata);
+            let root = metadata.resolve.as_ref().unwrap().root.as_ref().unwrap();
+            let mut libs = BTreeMap::new();
+            let mut stack = vec![root];
+            while let Some(id) = stack.pop() {
+                for dep in &resolve_nodes[id].deps {
+                    let package = &metadata[&dep.pkg];
+                    let krate = package.lib_like_target().unwrap();
+                    let name = if package.name == "dup" {
+                        format!("__dup_{}", package.version.to_string().replace('.', "_"))
+                    } else {
+                        krate.crate_name()
+                    };
+                    if libs.insert(&package.id, (krate, name)).is_none() {
+                        stack.push(&package.id);
+                    }
+                }
+            }
+            Ok(libs)
+        }
+        let dup_versions = |metadata: &cm::Metadata| -> anyhow::Result<_> {
+            Ok(libs_to_bundle(metadata)?
+                .keys()
+                .filter(|id| metadata[id].name == "dup")
+                .map(|id| metadata[id].version.to_string())
+                .collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            vec![
+                "`dup` is bundled in 2 versions, which are separate crates with incompatible \
+                 types\n  \
+                 v0.1.0 as `__dup_0_1_0`: app -> a v0.0.0 -> dup v0.1.0\n  \
+                 v0.2.0 as `__dup_0_2_0`: app -> dup v0.2.0"
+                    .to_owned(),
+            ],
+            super::warnings(metadata, root_package, &libs_to_bundle(metadata)?),
+        );
+
+        // Each version is tried in place of the other one, the older one first.
+        let mut tried = vec![];
+        let unified = super::unify(
+            metadata,
+            root_package,
+            &libs_to_bundle,
+            &mut |metadata, _, _| {
+                tried.push(dup_versions(metadata)?);
+                Ok(false)
+            },
+            &mut Shell::from_stdout(Box::new(io::sink())),
+        )?;
+        assert!(unified.is_none());
+        assert_eq!(vec![vec!["0.2.0"], vec!["0.1.0"]], tried);
+
+        let unified = super::unify(
+            metadata,
+            root_package,
+            &libs_to_bundle,
+            &mut |_, _, _| Ok(true),
+            &mut Shell::from_stdout(Box::new(io::sink())),
+        )?
+        .unwrap();
+        assert_eq!(vec!["0.2.0"], dup_versions(&unified)?);
+        assert_eq!(
+            Vec::<String>::new(),
+            super::warnings(&unified, root_package, &libs_to_bundle(&unified)?),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{package_spec::PackageSpec, shell::Shell};
+use anyhow::{anyhow, bail, Context as _};
+use cargo_metadata as cm;
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// How a judge compiles and runs submissions.
+///
+/// ```toml
+/// [workspace.metadata.cargo-equip.judges.my-judge]
+/// edition = "2021"
+/// rustc-args = ["-C", "opt-level=3"]
+/// time-limit = 2.0
+/// allow-licenses = ["MIT", "Apache-2.0", "CC0-1.0"]
+/// deny-licenses = ["GPL-3.0"]
+/// header = "header.txt"
+/// footer = "footer.txt"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Judge {
+    #[serde(default)]
+    pub(crate) edition: Option<String>,
+    #[serde(default)]
+    pub(crate) rustc_args: Vec<String>,
+    #[serde(default)]
+    pub(crate) time_limit: Option<f64>,
+    #[serde(default)]
+    pub(crate) allow_licenses: Vec<String>,
+    #[serde(default)]
+    pub(crate) deny_licenses: Vec<String>,
+    /// Relative to the workspace root.
+    #[serde(default)]
+    pub(crate) header: Option<PathBuf>,
+    /// Relative to the workspace root.
+    #[serde(default)]
+    pub(crate) footer: Option<PathBuf>,
+}
+
+impl Judge {
+    /// Finds a judge profile in `[workspace.metadata.cargo-equip.judges]`, then in the built-in
+    /// ones.
+    pub(crate) fn find(metadata: &cm::Metadata, name: &str) -> anyhow::Result<Self> {
+        if let Some(judge) = metadata
+            .workspace_metadata
+            .get("cargo-equip")
+            .and_then(|v| v.get("judges"))
+            .and_then(|v| v.get(name))
+        {
+            return serde_json::from_value(judge.clone()).with_context(|| {
+                format!(
+                    "could not parse `workspace.metadata.cargo-equip.judges.{}`",
+                    name,
+                )
+            });
+        }
+
+        match name {
+            "atcoder" => Ok(Self {
+                edition: Some("2021".to_owned()),
+                rustc_args: args(&["-C", "opt-level=3", "-C", "embed-bitcode=no"]),
+                time_limit: Some(2.0),
+                ..Self::default()
+            }),
+            "codingame" => Ok(Self {
+                edition: Some("2021".to_owned()),
+                rustc_args: args(&["-C", "opt-level=3"]),
+                ..Self::default()
+            }),
+            name => bail!(
+                "no such judge profile: `{}`. built-in ones are `atcoder` and `codingame`",
+                name,
+            ),
+        }
+    }
+}
+
+/// Reads the registry packages in the `Cargo.lock` of a judge as specs for their exact versions.
+pub(crate) fn exclude_specs_from_lockfile(path: &Path) -> anyhow::Result<Vec<PackageSpec>> {
+    #[derive(Deserialize)]
+    struct CargoLock {
+        #[serde(default)]
+        package: Vec<CargoLockPackage>,
+    }
+
+    #[derive(Deserialize)]
+    struct CargoLockPackage {
+        name: String,
+        version: Version,
+        source: Option<String>,
+    }
+
+    let CargoLock { package } = toml::from_str(&cargo_util::paths::read(path)?)
+        .with_context(|| format!("could not parse `{}`", path.display()))?;
+
+    package
+        .into_iter()
+        .flat_map(
+            |CargoLockPackage {
+                 name,
+                 version,
+                 source,
+             }| {
+                let source = source?;
+                let url = source
+                    .strip_prefix("registry+")
+                    .or_else(|| source.strip_prefix("sparse+"))?;
+                Some(format!("{}#{}:{}", url, name, version))
+            },
+        )
+        .map(|spec| parse_spec(&spec, path))
+        .collect()
+}
+
+/// Reads the crates.io dependencies in the `Cargo.toml` of a judge as specs for their version
+/// requirements.
+///
+/// `workspace = true` is resolved with `[workspace.dependencies]` of the same file. Dependencies
+/// whose requirements cannot be found are skipped with a warning.
+pub(crate) fn exclude_specs_from_manifest(
+    path: &Path,
+    shell: &mut Shell,
+) -> anyhow::Result<Vec<PackageSpec>> {
+    let manifest = toml::from_str::<toml::Table>(&cargo_util::paths::read(path)?)
+        .with_context(|| format!("could not parse `{}`", path.display()))?;
+
+    let workspace_deps = manifest
+        .get("workspace")
+        .and_then(|w| w.get("dependencies"))
+        .and_then(toml::Value::as_table);
+
+    let tables = manifest
+        .get("target")
+        .and_then(toml::Value::as_table)
+        .into_iter()
+        .flat_map(|targets| targets.values())
+        .map(|target| target.get("dependencies"))
+        .chain([manifest.get("dependencies")])
+        .flat_map(|deps| deps?.as_table())
+        .collect::<Vec<_>>();
+
+    let mut specs = vec![];
+    for (name_in_toml, dep) in tables.into_iter().flatten() {
+        let mut name = &**name_in_toml;
+        let mut dep = dep;
+        if let toml::Value::Table(table) = dep {
+            if table.get("workspace").and_then(toml::Value::as_bool) == Some(true) {
+                let Some(workspace_dep) = workspace_deps.and_then(|d| d.get(name_in_toml)) else {
+                    shell.warn(format!(
+                        "`{}` in `{}` is inherited from a workspace that is not in the file. \
+                         skipping",
+                        name_in_toml,
+                        path.display(),
+                    ))?;
+                    continue;
+                };
+                dep = workspace_dep;
+            }
+        }
+        let req = match dep {
+            toml::Value::String(req) => req,
+            toml::Value::Table(dep) => {
+                if ["path", "git", "registry"]
+                    .iter()
+                    .any(|k| dep.contains_key(*k))
+                {
+                    continue;
+                }
+                if let Some(package) = dep.get("package").and_then(toml::Value::as_str) {
+                    name = package;
+                }
+                let Some(req) = dep.get("version").and_then(toml::Value::as_str) else {
+                    shell.warn(format!(
+                        "`{}` in `{}` does not have `version`. skipping",
+                        name_in_toml,
+                        path.display(),
+                    ))?;
+                    continue;
+                };
+                req
+            }
+            _ => continue,
+        };
+        // Requirements in `Cargo.toml` may omit the operator, which specs do not.
+        let req = req.parse::<VersionReq>().with_context(|| {
+            format!(
+                "could not parse the requirement for `{}` in `{}`",
+                name_in_toml,
+                path.display(),
+            )
+        })?;
+        specs.push(parse_spec(
+            &format!(
+                "https://github.com/rust-lang/crates.io-index#{}@{}",
+                name, req
+            ),
+            path,
+        )?);
+    }
+    Ok(specs)
+}
+
+fn parse_spec(spec: &str, path: &Path) -> anyhow::Result<PackageSpec> {
+    spec.parse()
+        .map_err(|e| anyhow!("{}", e))
+        .with_context(|| format!("could not read `{}` in `{}`", spec, path.display()))
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|&s| s.to_owned()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shell::Shell;
+    use pretty_assertions::assert_eq;
+    use std::io;
+
+    #[test]
+    fn exclude_specs_from_lockfile() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let path = &tempdir.path().join("Cargo.lock");
+        cargo_util::paths::write(
+            path,
+            r#"version = 3
+
+[[package]]
+name = "ac-library-rs"
+version = "0.1.1"
+source = "git+https://github.com/rust-lang-ja/ac-library-rs?branch=replace-absolute-paths#0123456789abcdef0123456789abcdef01234567"
+
+[[package]]
+name = "either"
+version = "1.9.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "main"
+version = "0.1.0"
+
+[[package]]
+name = "proconio"
+version = "0.4.5"
+source = "sparse+https://index.crates.io/"
+"#,
+        )?;
+
+        let specs = super::exclude_specs_from_lockfile(path)?;
+        assert_eq!(
+            [
+                "https://github.com/rust-lang/crates.io-index#either:1.9.0",
+                "https://index.crates.io/#proconio:0.4.5",
+            ],
+            *specs.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn exclude_specs_from_manifest() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let path = &tempdir.path().join("Cargo.toml");
+        cargo_util::paths::write(
+            path,
+            r#"[package]
+name = "main"
+version = "0.1.0"
+
+[workspace.dependencies]
+itertools = "0.10"
+
+[dependencies]
+either = "1"
+fb = { package = "fixedbitset", version = "=0.3.0" }
+itertools = { workspace = true }
+local = { path = "../local" }
+num = { features = ["std"] }
+proconio = { workspace = true }
+
+[target.'cfg(unix)'.dependencies]
+rand = "*"
+"#,
+        )?;
+
+        let specs = super::exclude_specs_from_manifest(
+            path,
+            &mut Shell::from_stdout(Box::new(io::sink())),
+        )?;
+        assert_eq!(
+            [
+                "https://github.com/rust-lang/crates.io-index#rand@*",
+                "https://github.com/rust-lang/crates.io-index#either@^1",
+                "https://github.com/rust-lang/crates.io-index#fixedbitset@=0.3.0",
+                "https://github.com/rust-lang/crates.io-index#itertools@^0.10",
+            ],
+            *specs.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        );
+        Ok(())
+    }
+}
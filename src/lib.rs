@@ -2,29 +2,55 @@
 #![warn(rust_2018_idioms)]
 #![recursion_limit = "256"]
 
+mod cache;
 mod cargo_udeps;
+mod diagnose;
+mod doctor;
+mod duplicates;
+mod judge;
+mod package_spec;
 mod process;
+mod processed_libs;
 mod ra_proc_macro;
 mod rust;
 mod rustfmt;
+mod server;
+mod session;
 pub mod shell;
+mod size_report;
+mod template;
+mod testing;
 mod toolchain;
+mod tree;
+mod verify;
+mod workers;
 mod workspace;
 
 use crate::{
+    diagnose::Passes,
+    judge::Judge,
+    package_spec::PackageSpec,
+    process::ProcessBuilderExt as _,
+    processed_libs::{ProcessKey, ProcessedLib},
     ra_proc_macro::ProcMacroExpander,
     rust::CodeEdit,
+    session::Session,
     shell::Shell,
+    size_report::SizeReport,
+    template::Template,
+    workers::LibWorkers,
     workspace::{
-        Edition, MetadataExt as _, PackageExt as _, PackageIdExt as _, ResolveBehavior,
-        TargetExt as _,
+        Edition, LicensePolicy, MetadataExt as _, PackageExt as _, PackageIdExt as _,
+        ResolveBehavior, TargetExt as _,
     },
 };
-use anyhow::Context as _;
+use anyhow::{bail, Context as _};
+use camino::{Utf8Path, Utf8PathBuf};
 use cargo_metadata as cm;
+use cargo_util::ProcessBuilder;
+use chrono::SecondsFormat;
 use indoc::indoc;
 use itertools::{iproduct, Itertools as _};
-use krates::PkgSpec;
 use maplit::{btreeset, hashmap, hashset};
 use petgraph::{
     graph::{Graph, NodeIndex},
@@ -35,10 +61,14 @@ use quote::quote;
 use std::{
     cmp,
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsString,
     fmt::Debug,
+    io, panic,
     path::{Path, PathBuf},
     rc::Rc,
     str::FromStr,
+    thread,
+    time::Duration,
 };
 use structopt::{clap::AppSettings, StructOpt};
 
@@ -67,7 +97,14 @@ pub enum Opt {
     cargo equip [OPTIONS] --lib
     cargo equip [OPTIONS] --bin <NAME>
     cargo equip [OPTIONS] --example <NAME>
-    cargo equip [OPTIONS] --src <PATH>"#,
+    cargo equip [OPTIONS] --src <PATH>
+    cargo equip [OPTIONS] --file <PATH> [--deps-from <MANIFEST>]
+    cargo equip server
+    cargo equip test [OPTIONS]
+    cargo equip run [OPTIONS] [-- <ARGS>...]
+    cargo equip cache <list|clear|refresh> [SPEC]
+    cargo equip tree [OPTIONS] [--invert <SPEC>]
+    cargo equip doctor [OPTIONS]"#,
         )
     )]
     Equip(OptEquip),
@@ -75,6 +112,15 @@ pub enum Opt {
 
 #[derive(StructOpt, Debug)]
 pub struct OptEquip {
+    #[structopt(flatten)]
+    bundle: OptBundle,
+
+    #[structopt(subcommand)]
+    subcommand: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptBundle {
     /// Bundle the lib/bin/example target and its dependencies
     #[structopt(
         long,
@@ -100,13 +146,43 @@ pub struct OptEquip {
     #[structopt(long, value_name("NAME"))]
     example: Option<String>,
 
+    /// Bundle a file that is not a target
+    #[structopt(
+        long,
+        value_name("PATH"),
+        conflicts_with_all(&["src", "lib", "bin", "example", "verify_with"]),
+        long_help(indoc! {r#"
+            Bundle a file that is not a target, as a binary with the dependencies of `--deps-from`.
+
+            The file can be anywhere, such as a scratch solution outside any package. `--deps-from` defaults to the `Cargo.toml` found from the current directory. `cargo-udeps` is not run for it.
+        "#})
+    )]
+    file: Option<PathBuf>,
+
+    /// Cargo.toml whose dependencies `--file` uses
+    #[structopt(long, value_name("MANIFEST"), conflicts_with("manifest_path"))]
+    deps_from: Option<PathBuf>,
+
     /// Path to Cargo.toml
     #[structopt(long, value_name("PATH"))]
     manifest_path: Option<PathBuf>,
 
     /// Exclude library crates from bundling
-    #[structopt(long, value_name("SPEC"))]
-    exclude: Vec<PkgSpec>,
+    #[structopt(
+        long,
+        value_name("SPEC"),
+        long_help(indoc! {r#"
+            Exclude library crates from bundling.
+
+            In addition to the formats of `cargo pkgid` (`[<URL>#]<NAME>[@<VERSION>]`, `[<URL>#]<NAME>[:<VERSION>]` and `<URL>#<VERSION>`), a spec can be
+            - `<NAME>@<REQUIREMENT>` (e.g. `itertools@^0.10`) for the versions that meet the requirement, which must start with an operator,
+            - `<NAME>` with `*` and `?` (e.g. `num-*`), and
+            - `<NAME>` prefixed with `registry:`, `git:` or `path:` (e.g. `registry:*`) for the packages from the kind of source.
+
+            A warning is shown for each crate to bundle that matches a spec except for the version.
+        "#})
+    )]
+    exclude: Vec<PackageSpec>,
 
     /// Alias for `--exclude {crates available on AtCoder}`
     #[structopt(
@@ -147,6 +223,79 @@ pub struct OptEquip {
     )]
     exclude_codingame_crates: bool,
 
+    /// Exclude the registry packages in the `Cargo.lock` of a judge
+    #[structopt(
+        long,
+        value_name("PATH"),
+        long_help(indoc! {r#"
+            Exclude the registry packages in the `Cargo.lock` of a judge.
+
+            Each package is excluded only in the exact version. A warning is shown for each crate to bundle that the judge has only in other versions.
+        "#})
+    )]
+    exclude_from_lockfile: Option<PathBuf>,
+
+    /// Exclude the registry dependencies in the `Cargo.toml` of a judge
+    #[structopt(
+        long,
+        value_name("PATH"),
+        long_help(indoc! {r#"
+            Exclude the registry dependencies in the `Cargo.toml` of a judge.
+
+            Each dependency is excluded only in the versions that meet its requirement. A warning is shown for each crate to bundle whose version does not meet the requirement.
+        "#})
+    )]
+    exclude_from_manifest: Option<PathBuf>,
+
+    /// Bundle one version of each crate if the dependents of the others compile with it
+    #[structopt(
+        long,
+        long_help(indoc! {r#"
+            Bundle one version of each crate if the dependents of the others compile with it.
+
+            For each crate bundled in more than one version, the dependents of each version are made to use another version, newer ones first. A change is kept only if the bundled code still passes `cargo check`.
+        "#})
+    )]
+    unify_versions: bool,
+
+    /// Judge profile to use
+    #[structopt(
+        long,
+        value_name("NAME"),
+        long_help(indoc! {r#"
+            Judge profile to use.
+
+            Built-in profiles are `atcoder` and `codingame`. Profiles can be defined or overridden in `[workspace.metadata.cargo-equip.judges.<NAME>]` of the workspace manifest.
+        "#})
+    )]
+    judge: Option<String>,
+
+    /// Fail if a bundled crate is not available under these licenses
+    #[structopt(
+        long,
+        value_name("SPDX"),
+        number_of_values(1),
+        long_help(indoc! {r#"
+            Fail if a bundled crate is not available under these licenses.
+
+            Each value is an SPDX license identifier, optionally with `WITH <EXCEPTION>`. Crates with missing or unparsable `license` also fail. The root package is not checked. Overrides `allow-licenses` of the judge profile.
+        "#})
+    )]
+    allow_license: Vec<String>,
+
+    /// Fail if a bundled crate is only available under these licenses
+    #[structopt(
+        long,
+        value_name("SPDX"),
+        number_of_values(1),
+        long_help(indoc! {r#"
+            Fail if a bundled crate is only available under these licenses.
+
+            Each value is an SPDX license identifier, optionally with `WITH <EXCEPTION>`. Crates with missing or unparsable `license` also fail. The root package is not checked. Added to `deny-licenses` of the judge profile.
+        "#})
+    )]
+    deny_license: Vec<String>,
+
     /// Do not include license and copyright notices for the users
     #[structopt(
         long,
@@ -159,6 +308,10 @@ pub struct OptEquip {
                     Supported formats:
                     * github.com/{username}
                     * gitlab.com/{username}
+                    * {host}/{username} for Git repositories on other forges such as Codeberg and Gitea
+                    * {host}/{organization}/* for any Git repository of the organization
+                    * crates.io/{username} for packages owned by the user on crates.io
+                    * path:{directory} for packages in the directory
                 "#},
                 ' ',
             )
@@ -166,6 +319,17 @@ pub struct OptEquip {
     )]
     mine: Vec<User>,
 
+    /// Do not access the network to resolve licenses and owners
+    #[structopt(
+        long,
+        long_help(indoc! {r#"
+            Do not access the network to resolve licenses and owners.
+
+            Only `owners.json` and license files in the cache directory, `.cargo_vcs_info.json`, and files already in `~/.cargo/registry/src` or the Git checkouts of Cargo are used. `--offline` is also passed to `cargo metadata` and `cargo check`.
+        "#})
+    )]
+    offline: bool,
+
     /// [Deprecated] Alias for `--toolchain-for-udeps`
     #[structopt(long, value_name("TOOLCHAIN"), conflicts_with("toolchain_for_udeps"))]
     toolchain: Option<String>,
@@ -218,7 +382,7 @@ pub struct OptEquip {
     )]
     remove: Vec<Remove>,
 
-    /// Minify part of the output before emitting [default: none]  [possible values: none, libs, all]
+    /// Minify part of the output before emitting [default: none]  [possible values: none, libs, all, aggressive]
     #[structopt(
         long,
         value_name("MINIFY"),
@@ -231,6 +395,8 @@ pub struct OptEquip {
                 Minifies
                 - each expaned library with `--minify lib`.
                 - the whole code with `--minify all`.
+                - the whole code with `--minify aggressive`, additionally renaming local variables
+                  and generic parameters in the libraries to short names.
 
                 Not that the minification function is incomplete. Unnecessary spaces may be inserted.
             "#},
@@ -247,14 +413,80 @@ pub struct OptEquip {
     #[structopt(long)]
     no_rustfmt: bool,
 
+    /// Format the output with the `rustfmt.toml` or `.rustfmt.toml`
+    #[structopt(long, value_name("PATH"), conflicts_with("no_rustfmt"))]
+    rustfmt_config: Option<PathBuf>,
+
     /// Do not check the output before emitting
     #[structopt(long)]
     no_check: bool,
 
+    /// If the check fails, bundle again with fewer crates and transformations to find the cause
+    #[structopt(long, conflicts_with("no_check"))]
+    diagnose: bool,
+
+    /// Run the original and the bundled binaries on `*.in` in the directory and compare them
+    #[structopt(
+        long,
+        value_name("DIR"),
+        conflicts_with("lib"),
+        long_help(indoc! {r#"
+            Run the original and the bundled binaries on `*.in` in the directory and compare them.
+
+            Both are built with the `release` profile. Differences in STDOUT or exit status are reported as an error. Each run is killed after `time-limit` of the judge profile, or after 10 seconds, and reported as an error.
+        "#})
+    )]
+    verify_with: Option<PathBuf>,
+
     /// Write to the file instead of STDOUT
     #[structopt(short, long, value_name("PATH"))]
     output: Option<PathBuf>,
 
+    /// Write the list of the bundled crates and their license notices to the file
+    #[structopt(
+        long,
+        value_name("PATH"),
+        long_help(indoc! {r#"
+            Write the list of the bundled crates and their license notices to the file.
+
+            The file is written in Markdown, and the doc comment of the bundled code only refers to it.
+        "#})
+    )]
+    notices: Option<PathBuf>,
+
+    /// Prepend the template to the output
+    #[structopt(
+        long,
+        value_name("PATH"),
+        long_help(indoc! {r#"
+            Prepend the template to the output.
+
+            `{{root_target}}`, `{{git_sha}}`, `{{bundled_crates}}`, `{{toolchain}}` and `{{date}}` are expanded. `{{git_sha}}` is `HEAD` of the Git repository that contains the workspace root. Overrides `header` of the judge profile.
+        "#})
+    )]
+    header: Option<PathBuf>,
+
+    /// Append the template to the output
+    #[structopt(
+        long,
+        value_name("PATH"),
+        long_help(indoc! {r#"
+            Append the template to the output.
+
+            Placeholders are the same as `--header`. Overrides `footer` of the judge profile.
+        "#})
+    )]
+    footer: Option<PathBuf>,
+
+    /// Print the sizes of the parts of the output to STDERR  [possible values: table, json]
+    #[structopt(
+        long,
+        value_name("FORMAT"),
+        possible_values(SizeReportFormat::VARIANTS),
+        hide_possible_values(true)
+    )]
+    size_report: Option<SizeReportFormat>,
+
     /// [Deprecated] Alias for `--minify`
     #[structopt(
         long,
@@ -277,20 +509,191 @@ pub struct OptEquip {
     check: bool,
 }
 
+#[allow(clippy::large_enum_variant)]
+#[derive(StructOpt, Debug)]
+pub enum Subcommand {
+    /// Run a JSON-RPC server for editor integrations
+    #[structopt(long_about(indoc! {r#"
+        Run a JSON-RPC server for editor integrations.
+
+        The server reads requests from STDIN and writes responses to STDOUT, both framed with `Content-Length` headers as in the Language Server Protocol. Metadata, the procedural macro server and processed libraries are kept between requests.
+
+        Methods:
+        * `bundle` with `{"src": <PATH>, "args": [<ARG>...]?, "cwd": <PATH>?}` returns `{"code": <STRING>}`
+        * `shutdown`
+        * `exit` (notification)
+    "#}))]
+    Server,
+
+    /// Bundle the binary, compile the output like a judge, and test it with sample cases
+    #[structopt(long_about(indoc! {r#"
+        Bundle the binary, compile the output like a judge, and test it with sample cases.
+
+        The bundled code is compiled with the `release` profile and the edition and `rustc` arguments of the judge profile. Then it is run with every `*.in` file in the directory and the output is compared with the corresponding `*.out` file.
+    "#}))]
+    Test(OptTest),
+
+    /// Bundle the binary, compile the output like a judge, and run it
+    #[structopt(long_about(indoc! {r#"
+        Bundle the binary, compile the output like a judge, and run it.
+
+        The bundled code is compiled with the `release` profile and the edition and `rustc` arguments of the judge profile. Excluded crates are linked as resolved in the current lockfile. STDIN is passed through.
+    "#}))]
+    Run(OptRun),
+
+    /// Inspect or invalidate cached owners and license texts
+    #[structopt(long_about(indoc! {r#"
+        Inspect or invalidate cached owners and license texts.
+
+        They are stored in `owners.json` and `license-files` in the cache directory.
+    "#}))]
+    Cache(OptCache),
+
+    /// Print the crates to bundle as a tree
+    #[structopt(long_about(indoc! {r#"
+        Print the crates to bundle as a tree.
+
+        Each crate is annotated with its pseudo `extern crate` name, the features enabled by its parent in the tree, whether `cargo-udeps` kept it, and the `--exclude` spec that would remove it.
+    "#}))]
+    Tree(OptTree),
+
+    /// Check the environment without bundling anything
+    #[structopt(long_about(indoc! {r#"
+        Check the environment without bundling anything.
+
+        Each of `rustup`, the active toolchain, `cargo-udeps`, `rust-analyzer-proc-macro-srv`, `rustfmt` and the cache directory is reported as OK, degraded or missing, along with the command to fix it.
+    "#}))]
+    Doctor(OptDoctor),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptTest {
+    #[structopt(flatten)]
+    bundle: OptBundle,
+
+    /// Directory of sample cases [default: tests/<TARGET>]
+    #[structopt(long, value_name("DIR"))]
+    testcases: Option<PathBuf>,
+
+    /// Time limit in seconds [default: the one of the judge profile]
+    #[structopt(long, value_name("SECONDS"))]
+    time_limit: Option<f64>,
+
+    /// Accept floating-point numbers within the absolute or relative error
+    #[structopt(long, value_name("ERROR"))]
+    tolerance: Option<f64>,
+
+    /// Compare the outputs including whitespace
+    #[structopt(long)]
+    strict_whitespace: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptRun {
+    #[structopt(flatten)]
+    bundle: OptBundle,
+
+    /// Arguments for the binary
+    #[structopt(last(true), value_name("ARGS"))]
+    args: Vec<OsString>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptTree {
+    #[structopt(flatten)]
+    bundle: OptBundle,
+
+    /// Print the crates that depend on the crates matching the spec instead
+    #[structopt(short, long, value_name("SPEC"))]
+    invert: Option<PackageSpec>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct OptDoctor {
+    /// `nightly` toolchain for `cargo-udeps`
+    #[structopt(long, value_name("TOOLCHAIN"), default_value("nightly"))]
+    toolchain_for_udeps: String,
+
+    /// Toolchain for expanding procedural macros
+    #[structopt(long, value_name("TOOLCHAIN"))]
+    toolchain_for_proc_macro_srv: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum OptCache {
+    /// List cached owners and license texts
+    List {
+        /// Package to list [default: all]
+        spec: Option<PackageSpec>,
+    },
+
+    /// Remove cached owners and license texts
+    Clear {
+        /// Package to remove [default: all]
+        spec: Option<PackageSpec>,
+    },
+
+    /// Retrieve owners and license texts for the packages in the lockfile again
+    Refresh {
+        /// Path to Cargo.toml
+        #[structopt(long, value_name("PATH"))]
+        manifest_path: Option<PathBuf>,
+
+        /// Package to refresh [default: all]
+        spec: Option<PackageSpec>,
+    },
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum User {
     Github(String),
     GitlabCom(String),
+    /// A user on another forge, such as Codeberg or Gitea.
+    Forge(String, String),
+    /// Any repository of an organization on a forge.
+    Org(String, String),
+    CratesIo(String),
+    /// Packages in the directory.
+    Path(PathBuf),
+}
+
+impl User {
+    fn forge(host: &str, username: &str) -> Self {
+        match host {
+            "github.com" => Self::Github(username.to_owned()),
+            "gitlab.com" => Self::GitlabCom(username.to_owned()),
+            host => Self::Forge(host.to_owned(), username.to_owned()),
+        }
+    }
+
+    fn owns(&self, owner: &Self) -> bool {
+        match (self, owner) {
+            (Self::Path(dir), Self::Path(manifest_dir)) => manifest_dir.starts_with(dir),
+            (this, owner) => this == owner,
+        }
+    }
 }
 
 impl FromStr for User {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<Self, &'static str> {
-        return if let Some(username) = s.strip_prefix("github.com/") {
+        return if let Some(dir) = s.strip_prefix("path:") {
+            Ok(Self::Path(dir.into()))
+        } else if let Some(username) = s.strip_prefix("crates.io/") {
+            Ok(Self::CratesIo(username.to_owned()))
+        } else if let [host, org, "*"] = *s.split('/').collect::<Vec<_>>() {
+            Ok(Self::Org(host.to_owned(), org.to_owned()))
+        } else if let Some(username) = s.strip_prefix("github.com/") {
             Ok(Self::Github(username.to_owned()))
         } else if let Some(username) = s.strip_prefix("gitlab.com/") {
             Ok(Self::GitlabCom(username.to_owned()))
+        } else if let [host, username] = *s.split('/').collect::<Vec<_>>() {
+            if host.contains('.') {
+                Ok(Self::Forge(host.to_owned(), username.to_owned()))
+            } else {
+                Err(MSG)
+            }
         } else {
             Err(MSG)
         };
@@ -299,6 +702,10 @@ impl FromStr for User {
             Supported formats:
             * github.com/{username}
             * gitlab.com/{username}
+            * {host}/{username}
+            * {host}/{organization}/*
+            * crates.io/{username}
+            * path:{directory}
         "};
     }
 }
@@ -347,15 +754,38 @@ impl FromStr for Remove {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SizeReportFormat {
+    Table,
+    Json,
+}
+
+impl SizeReportFormat {
+    const VARIANTS: &'static [&'static str] = &["table", "json"];
+}
+
+impl FromStr for SizeReportFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            _ => Err(r#"expected "table", or "json""#),
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Minify {
     None,
     Libs,
     All,
+    Aggressive,
 }
 
 impl Minify {
-    const VARIANTS: &'static [&'static str] = &["none", "libs", "all"];
+    const VARIANTS: &'static [&'static str] = &["none", "libs", "all", "aggressive"];
 }
 
 impl FromStr for Minify {
@@ -366,7 +796,8 @@ impl FromStr for Minify {
             "none" => Ok(Self::None),
             "libs" => Ok(Self::Libs),
             "all" => Ok(Self::All),
-            _ => Err(r#"expected "none", "libs", or "all""#),
+            "aggressive" => Ok(Self::Aggressive),
+            _ => Err(r#"expected "none", "libs", "all", or "aggressive""#),
         }
     }
 }
@@ -496,8 +927,103 @@ static CODINGAME_CRATES: &[&str] = &[
 ];
 
 pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
-    let Opt::Equip(OptEquip {
+    let Opt::Equip(OptEquip { bundle, subcommand }) = opt;
+
+    let mut run_args = vec![];
+
+    let (opt, after_bundling) = match subcommand {
+        None => (bundle, AfterBundling::Check),
+        Some(Subcommand::Server) => return server::run(io::stdin().lock(), ctx),
+        Some(Subcommand::Cache(opt)) => return cache::run(opt, ctx),
+        Some(Subcommand::Doctor(opt)) => return doctor::run(opt, ctx),
+        Some(Subcommand::Test(OptTest {
+            bundle,
+            testcases,
+            time_limit,
+            tolerance,
+            strict_whitespace,
+        })) => (
+            bundle,
+            AfterBundling::Test {
+                testcases,
+                time_limit,
+                comparison: testing::Comparison {
+                    tolerance,
+                    strict_whitespace,
+                },
+            },
+        ),
+        Some(Subcommand::Run(OptRun { bundle, args })) => {
+            run_args = args;
+            (bundle, AfterBundling::Run)
+        }
+        Some(Subcommand::Tree(OptTree { bundle, invert })) => {
+            (bundle, AfterBundling::Tree { invert })
+        }
+    };
+
+    let Context {
+        cwd,
+        cache_dir,
+        shell,
+    } = ctx;
+
+    let output = opt.output.clone();
+    let emit = matches!(after_bundling, AfterBundling::Check);
+    let (code, executable) = equip(
+        opt,
+        after_bundling,
+        &cwd,
+        &cache_dir,
+        &mut Session::new(&cache_dir),
+        shell,
+    )?;
+
+    if let Some(output) = output {
+        let output = cwd.join(output);
+        cargo_util::paths::write(output, code)?;
+    } else if emit {
+        write!(shell.out(), "{}", code)?;
+    }
+
+    if let Some(executable) = executable {
+        let mut process = ProcessBuilder::new(executable);
+        process.args(&run_args).cwd(&cwd);
+        shell.status("Running", &process)?;
+        process.exec_replace()?;
+    }
+    Ok(())
+}
+
+/// What to do with the bundled code before emitting it.
+enum AfterBundling {
+    /// Check it unless `--no-check`, or verify it with `--verify-with`.
+    Check,
+    /// Compile it like the judge and test it with sample cases.
+    Test {
+        testcases: Option<PathBuf>,
+        time_limit: Option<f64>,
+        comparison: testing::Comparison,
+    },
+    /// Compile it like the judge and return the executable.
+    Run,
+    /// Print the crates to bundle as a tree instead of bundling them.
+    Tree { invert: Option<PackageSpec> },
+}
+
+/// Returns the bundled code, and the executable for `AfterBundling::Run`.
+fn equip(
+    opt: OptBundle,
+    after_bundling: AfterBundling,
+    cwd: &Path,
+    cache_dir: &Path,
+    session: &mut Session,
+    shell: &mut Shell,
+) -> anyhow::Result<(String, Option<Utf8PathBuf>)> {
+    let OptBundle {
         src,
+        file,
+        deps_from,
         lib,
         bin,
         example,
@@ -506,7 +1032,14 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
         exclude_atcoder_crates,
         exclude_atcoder_202301_crates,
         exclude_codingame_crates,
+        exclude_from_lockfile,
+        exclude_from_manifest,
+        unify_versions,
+        judge,
+        allow_license,
+        deny_license,
         mine,
+        offline,
         toolchain: deprecated_toolchain_opt,
         toolchain_for_udeps,
         toolchain_for_proc_macro_srv,
@@ -515,13 +1048,20 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
         minify,
         no_resolve_cfgs,
         no_rustfmt,
+        rustfmt_config,
         no_check,
-        output,
+        diagnose,
+        verify_with,
+        output: _,
+        notices,
+        header,
+        footer,
+        size_report,
         oneline: deprecated_oneline_opt,
         resolve_cfgs: deprecated_resolve_cfgs_flag,
         rustfmt: deprecated_rustfmt_flag,
         check: deprecated_check_flag,
-    }) = opt;
+    } = opt;
 
     let minify = match (minify, deprecated_oneline_opt) {
         (Minify::None, oneline) => oneline,
@@ -539,19 +1079,27 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
         if exclude_codingame_crates {
             exclude.extend(CODINGAME_CRATES.iter().map(|s| s.parse().unwrap()));
         }
+        if let Some(path) = &exclude_from_lockfile {
+            exclude.extend(judge::exclude_specs_from_lockfile(&cwd.join(path))?);
+        }
+        if let Some(path) = &exclude_from_manifest {
+            exclude.extend(judge::exclude_specs_from_manifest(&cwd.join(path), shell)?);
+        }
         exclude
     };
 
+    let mine = mine
+        .into_iter()
+        .map(|user| match user {
+            User::Path(dir) => User::Path(cargo_util::paths::normalize_path(&cwd.join(dir))),
+            user => user,
+        })
+        .collect::<Vec<_>>();
+
     let toolchain_for_udeps = deprecated_toolchain_opt
         .as_ref()
         .unwrap_or(&toolchain_for_udeps);
 
-    let Context {
-        cwd,
-        cache_dir,
-        shell,
-    } = ctx;
-
     if deprecated_toolchain_opt.is_some() {
         shell.warn("`--toolchain` was renamed to `--toolchain-for-udeps`")?;
     }
@@ -565,15 +1113,24 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
         shell.warn("`--check` is deprecated. the output is checked by default")?;
     }
 
-    let manifest_path = if let Some(manifest_path) = manifest_path {
+    if deps_from.is_some() && file.is_none() {
+        bail!("`--deps-from` is only for `--file`");
+    }
+
+    let manifest_path = if let Some(manifest_path) = deps_from.or(manifest_path) {
         cwd.join(manifest_path.strip_prefix(".").unwrap_or(&manifest_path))
     } else {
-        workspace::locate_project(&cwd)?
+        workspace::locate_project(cwd)?
     };
 
-    let metadata = workspace::cargo_metadata(&manifest_path, &cwd)?;
+    let metadata = &*session.metadata(&manifest_path, cwd, offline)?;
 
-    let (root, root_package) = if lib {
+    let loose_file_target;
+    let (root, root_package) = if let Some(file) = &file {
+        let (target, package) = metadata.loose_file_target(&cwd.join(file), &manifest_path)?;
+        loose_file_target = target;
+        Ok((&loose_file_target, package))
+    } else if lib {
         metadata.lib_target()
     } else if let Some(bin) = bin {
         metadata.bin_target_by_name(&bin)
@@ -596,26 +1153,192 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
         )?;
     }
 
-    let libs_to_bundle = {
-        let unused_deps = &if root.is_lib() {
-            hashset!()
-        } else {
-            match cargo_udeps::cargo_udeps(root_package, root, toolchain_for_udeps, shell) {
-                Ok(unused_deps) => unused_deps,
-                Err(warning) => {
-                    shell.warn(warning)?;
-                    hashset!()
-                }
+    let unused_deps = if root.is_lib() || file.is_some() {
+        None
+    } else {
+        match cargo_udeps::cargo_udeps(root_package, root, toolchain_for_udeps, shell) {
+            Ok(unused_deps) => Some(unused_deps),
+            Err(warning) => {
+                shell.warn(warning)?;
+                None
             }
-        };
-        let mut libs_to_bundle =
-            metadata.libs_to_bundle(&root_package.id, root.is_example(), unused_deps, &exclude)?;
-        if root.is_lib() {
-            libs_to_bundle.insert(&root_package.id, (root, root.crate_name()));
         }
-        libs_to_bundle
     };
 
+    let cfgs = session.cfgs(root_package.manifest_dir())?;
+
+    let libs_to_bundle = crate::libs_to_bundle(
+        metadata,
+        root_package,
+        root,
+        unused_deps.as_ref(),
+        &exclude,
+        &cfgs,
+    )?;
+
+    for warning in PackageSpec::mismatches(&exclude, libs_to_bundle.keys().map(|&id| &metadata[id]))
+    {
+        shell.warn(warning)?;
+    }
+
+    let judge = judge
+        .map(|name| Judge::find(metadata, &name))
+        .transpose()?
+        .unwrap_or_default();
+
+    let header = header
+        .map(|p| cwd.join(p))
+        .or_else(|| {
+            judge
+                .header
+                .as_ref()
+                .map(|p| metadata.workspace_root.as_std_path().join(p))
+        })
+        .map(|p| Template::read(&p))
+        .transpose()?;
+    let footer = footer
+        .map(|p| cwd.join(p))
+        .or_else(|| {
+            judge
+                .footer
+                .as_ref()
+                .map(|p| metadata.workspace_root.as_std_path().join(p))
+        })
+        .map(|p| Template::read(&p))
+        .transpose()?;
+
+    let root_crate = if root.is_lib() {
+        RootCrate::Lib(root_package, root)
+    } else {
+        RootCrate::BinLike(root_package, root)
+    };
+
+    let passes = Passes {
+        resolve_cfgs: !no_resolve_cfgs,
+        remove,
+        minify,
+        rustfmt: !no_rustfmt,
+        translate_paths: true,
+        modify_macros: true,
+        resolve_preludes: true,
+    };
+
+    let rustfmt_config = rustfmt_config.map(|p| cwd.join(p));
+
+    let mut bundle = |metadata: &cm::Metadata,
+                      root_crate: RootCrate<'_>,
+                      libs_to_bundle: &BTreeMap<&cm::PackageId, (&cm::Target, String)>,
+                      passes: &Passes,
+                      shell: &mut Shell| {
+        bundle(
+            metadata,
+            root_crate,
+            libs_to_bundle,
+            &mine,
+            offline,
+            &cargo_equip_mod_name,
+            notices.as_deref(),
+            header.as_ref(),
+            footer.as_ref(),
+            passes,
+            rustfmt_config.as_deref(),
+            toolchain_for_proc_macro_srv.as_deref(),
+            cache_dir,
+            session,
+            shell,
+        )
+    };
+
+    let unified_metadata = if unify_versions {
+        duplicates::unify(
+            metadata,
+            root_package,
+            &|metadata| {
+                crate::libs_to_bundle(
+                    metadata,
+                    root_package,
+                    root,
+                    unused_deps.as_ref(),
+                    &exclude,
+                    &cfgs,
+                )
+            },
+            &mut |metadata, libs_to_bundle, shell| {
+                let (code, _) = bundle(metadata, root_crate, libs_to_bundle, &passes, shell)?;
+                workspace::scratch_package_using_current_lockfile_and_cache(
+                    metadata,
+                    root_package,
+                    root,
+                    &exclude,
+                    &code,
+                    None,
+                    cache_dir,
+                    shell,
+                )?
+                .cargo_check_quietly()
+            },
+            shell,
+        )?
+    } else {
+        None
+    };
+
+    let (metadata, libs_to_bundle) = if let Some(metadata) = &unified_metadata {
+        let libs_to_bundle = crate::libs_to_bundle(
+            metadata,
+            root_package,
+            root,
+            unused_deps.as_ref(),
+            &exclude,
+            &cfgs,
+        )?;
+        (metadata, libs_to_bundle)
+    } else {
+        (metadata, libs_to_bundle)
+    };
+
+    for warning in duplicates::warnings(metadata, root_package, &libs_to_bundle) {
+        shell.warn(warning)?;
+    }
+
+    if let AfterBundling::Tree { invert } = &after_bundling {
+        tree::print(
+            metadata,
+            root_package,
+            root,
+            &libs_to_bundle,
+            unused_deps.as_ref(),
+            invert.as_ref(),
+            shell,
+        )?;
+        return Ok(("".to_owned(), None));
+    }
+
+    let license_policy = LicensePolicy::new(
+        if allow_license.is_empty() {
+            &judge.allow_licenses
+        } else {
+            &allow_license
+        },
+        &[&*judge.deny_licenses, &deny_license].concat(),
+    )?;
+    if !license_policy.is_empty() {
+        let violations = libs_to_bundle
+            .keys()
+            .filter(|&&id| *id != root_package.id)
+            .flat_map(|&id| {
+                let reason = license_policy.check(&metadata[id]).err()?;
+                Some(format!("- `{}`: {}\n", id, reason))
+            })
+            .join("");
+        if !violations.is_empty() {
+            bail!(
+                "the following crate(s) are not allowed by the license policy\n\n{}",
+                violations,
+            );
+        }
+    }
+
     let error_message = |head: &str| {
         let mut msg = head.to_owned();
 
@@ -631,7 +1354,7 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
             .join("");
 
         let crates_available_on_atcoder = iproduct!(libs_to_bundle.keys(), ATCODER_CRATES)
-            .filter(|(id, s)| s.parse::<PkgSpec>().unwrap().matches(&metadata[id]))
+            .filter(|(id, s)| s.parse::<PackageSpec>().unwrap().matches(&metadata[id]))
             .map(|(id, _)| format!("- `{}`\n", id))
             .join("");
 
@@ -646,68 +1369,164 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
         msg
     };
 
-    let code = bundle(
-        &metadata,
-        if root.is_lib() {
-            RootCrate::Lib(root_package, root)
-        } else {
-            RootCrate::BinLike(root_package, root)
-        },
-        &libs_to_bundle,
-        &mine,
-        &cargo_equip_mod_name,
-        !no_resolve_cfgs,
-        &remove,
-        minify,
-        !no_rustfmt,
-        toolchain_for_proc_macro_srv.as_deref(),
-        &cache_dir,
-        shell,
-    )
-    .with_context(|| error_message("could not bundle the code"))?;
+    let (code, notices_content) = bundle(metadata, root_crate, &libs_to_bundle, &passes, shell)
+        .with_context(|| error_message("could not bundle the code"))?;
 
-    if !no_check {
-        workspace::cargo_check_using_current_lockfile_and_cache(
-            &metadata,
+    if let Some(notices) = &notices {
+        cargo_util::paths::write(cwd.join(notices), notices_content)?;
+    }
+
+    if let Some(size_report) = size_report {
+        SizeReport::new(&code, &cargo_equip_mod_name)?.print(size_report, shell)?;
+    }
+
+    let compile_like_judge = |shell: &mut Shell| {
+        workspace::scratch_package_using_current_lockfile_and_cache(
+            metadata,
             root_package,
             root,
             &exclude,
             &code,
+            judge.edition.as_deref(),
+            cache_dir,
+            shell,
         )
-        .with_context(|| error_message("the bundled code was not valid"))?;
-    }
+        .and_then(|scratch| scratch.cargo_rustc_release(&judge.rustc_args))
+        .with_context(|| error_message("the bundled code was not valid"))
+    };
 
-    if let Some(output) = output {
-        let output = cwd.join(output);
-        cargo_util::paths::write(output, code)
-    } else {
-        write!(shell.out(), "{}", code)?;
-        Ok(())
-    }
+    let executable = match after_bundling {
+        AfterBundling::Check => {
+            if let Some(verify_with) = verify_with {
+                if root.is_lib() {
+                    bail!("`--verify-with` is not available for libraries");
+                }
+                let original = workspace::cargo_build_release(metadata, root_package, root)?;
+                let bundled = workspace::scratch_package_using_current_lockfile_and_cache(
+                    metadata,
+                    root_package,
+                    root,
+                    &exclude,
+                    &code,
+                    None,
+                    cache_dir,
+                    shell,
+                )
+                .and_then(|scratch| scratch.cargo_build_release())
+                .with_context(|| error_message("the bundled code was not valid"))?;
+                verify::verify_with(
+                    &cwd.join(verify_with),
+                    original.as_ref(),
+                    bundled.as_ref(),
+                    judge.time_limit.map(Duration::from_secs_f64),
+                    shell,
+                )?;
+            } else if !no_check {
+                let checked = workspace::scratch_package_using_current_lockfile_and_cache(
+                    metadata,
+                    root_package,
+                    root,
+                    &exclude,
+                    &code,
+                    None,
+                    cache_dir,
+                    shell,
+                )
+                .and_then(|scratch| scratch.cargo_check());
+
+                if checked.is_err() && diagnose {
+                    diagnose::diagnose(
+                        metadata,
+                        root_crate,
+                        &libs_to_bundle,
+                        &passes,
+                        &mut |root_crate, libs_to_bundle, passes, shell| {
+                            bundle(metadata, root_crate, libs_to_bundle, passes, shell)
+                                .map(|(code, _)| code)
+                        },
+                        &mut |root_crate, code, shell| {
+                            let (package, krate) = root_crate.split();
+                            workspace::scratch_package_using_current_lockfile_and_cache(
+                                metadata, package, krate, &exclude, code, None, cache_dir, shell,
+                            )?
+                            .cargo_check_quietly()
+                        },
+                        shell,
+                    )?;
+                }
+                checked.with_context(|| error_message("the bundled code was not valid"))?;
+            }
+            None
+        }
+        AfterBundling::Test {
+            testcases,
+            time_limit,
+            comparison,
+        } => {
+            if root.is_lib() {
+                bail!("`cargo equip test` is not available for libraries");
+            }
+            let exe = compile_like_judge(shell)?;
+            let testcases = testcases.map(|p| cwd.join(p)).unwrap_or_else(|| {
+                root_package
+                    .manifest_dir()
+                    .join("tests")
+                    .join(&root.name)
+                    .into()
+            });
+            let time_limit = time_limit.or(judge.time_limit).map(Duration::from_secs_f64);
+            testing::test(exe.as_ref(), &testcases, time_limit, &comparison, shell)?;
+            None
+        }
+        AfterBundling::Run => {
+            if root.is_lib() {
+                bail!("`cargo equip run` is not available for libraries");
+            }
+            Some(compile_like_judge(shell)?)
+        }
+        AfterBundling::Tree { .. } => unreachable!("should have returned"),
+    };
+
+    Ok((code, executable))
 }
 
 #[allow(clippy::too_many_arguments)]
-fn bundle(
-    metadata: &cm::Metadata,
+fn bundle<'cm>(
+    metadata: &'cm cm::Metadata,
     root_crate: RootCrate<'_>,
-    libs_to_bundle: &BTreeMap<&cm::PackageId, (&cm::Target, String)>,
+    libs_to_bundle: &BTreeMap<&'cm cm::PackageId, (&'cm cm::Target, String)>,
     mine: &[User],
+    offline: bool,
     cargo_equip_mod_name: &syn::Ident,
-    resolve_cfgs: bool,
-    remove: &[Remove],
-    minify: Minify,
-    rustfmt: bool,
+    notices: Option<&Path>,
+    header: Option<&Template>,
+    footer: Option<&Template>,
+    passes: &Passes,
+    rustfmt_config: Option<&Path>,
     toolchain_for_proc_macro_srv: Option<&str>,
     cache_dir: &Path,
+    session: &mut Session,
     shell: &mut Shell,
-) -> anyhow::Result<String> {
+) -> anyhow::Result<(String, String)> {
+    let Passes {
+        resolve_cfgs,
+        ref remove,
+        minify,
+        rustfmt,
+        translate_paths,
+        modify_macros,
+        resolve_preludes,
+    } = *passes;
+
     let cargo_check_message_format_json = |toolchain: &str, shell: &mut Shell| -> _ {
         let (package, krate) = root_crate.split();
-        workspace::cargo_check_message_format_json(toolchain, metadata, package, krate, shell)
-            .map(Rc::new)
+        workspace::cargo_check_message_format_json(
+            toolchain, metadata, package, krate, offline, shell,
+        )
+        .map(Rc::new)
     };
 
-    let active_toolchain = &*toolchain::active_toolchain(root_crate.package().manifest_dir())?;
+    let active_toolchain = &*session.active_toolchain(root_crate.package().manifest_dir())?;
     let toolchain_for_proc_macro_srv = toolchain_for_proc_macro_srv.unwrap_or(active_toolchain);
 
     let has_custom_build = libs_to_bundle
@@ -720,9 +1539,11 @@ fn bundle(
             let cargo_messages = cargo_check_message_format_json(active_toolchain, shell)?;
             (cargo_messages.clone(), Some(cargo_messages))
         } else {
-            let cargo_messages_for_out_dirs = has_custom_build
-                .then(|| cargo_check_message_format_json(active_toolchain, shell))
-                .unwrap_or_else(|| Ok(Default::default()))?;
+            let cargo_messages_for_out_dirs = if has_custom_build {
+                cargo_check_message_format_json(active_toolchain, shell)?
+            } else {
+                Default::default()
+            };
 
             let cargo_messages_for_proc_macro_dll_paths = has_proc_macro
                 .then(|| cargo_check_message_format_json(toolchain_for_proc_macro_srv, shell))
@@ -739,7 +1560,7 @@ fn bundle(
     let macro_expander = cargo_messages_for_proc_macro_dll_paths
         .as_ref()
         .map(|cargo_messages_for_proc_macro_dll_paths| {
-            let proc_macro_srv_exe = &toolchain::find_rust_analyzer_proc_macro_srv(
+            let proc_macro_srv = session.proc_macro_srv(
                 root_crate.package().manifest_dir(),
                 toolchain_for_proc_macro_srv,
             )?;
@@ -749,7 +1570,7 @@ fn bundle(
                 |p| libs_to_bundle.contains_key(p),
             );
 
-            ProcMacroExpander::spawn(proc_macro_srv_exe, proc_macro_crate_dylibs)
+            ProcMacroExpander::load(proc_macro_srv, proc_macro_crate_dylibs)
         })
         .transpose()?;
 
@@ -782,7 +1603,17 @@ fn bundle(
         let code = cargo_util::paths::read(bin_target.src_path.as_ref())?;
         if rust::find_skip_attribute(&code)? {
             shell.status("Found", "`#![cfg_attr(cargo_equip, cargo_equip::skip)]`")?;
-            return Ok(code);
+            // Nothing is bundled, but the output is still submitted.
+            let code = expand_templates(
+                code,
+                header,
+                footer,
+                metadata,
+                root_crate,
+                &BTreeMap::new(),
+                active_toolchain,
+            )?;
+            return Ok((code, "".to_owned()));
         }
         code
     } else {
@@ -811,182 +1642,285 @@ fn bundle(
         )?;
     }
 
-    let libs = libs_to_bundle
-        .iter()
-        .map(|(pkg, (krate, pseudo_extern_crate_name))| {
-            let mut edit = CodeEdit::new(cargo_equip_mod_name, &krate.src_path, || {
-                (krate.crate_name(), &pkg.repr)
-            })?;
-            if let Some(out_dir) = out_dirs.get(pkg) {
-                edit.expand_includes(out_dir)?;
-            }
-            Ok((*pkg, (*krate, &**pseudo_extern_crate_name, edit)))
-        })
-        .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
-
-    let (graph, indices) = normal_non_host_dep_graph(&resolve_nodes, libs_to_bundle);
-
-    let libs_using_proc_macros = {
-        let mut crates_using_proc_macros = BTreeMap::<_, HashSet<_>>::new();
-        for (pkg, names) in &proc_macro_names {
-            let (_, pseudo_extern_crate_name) = &libs_to_bundle[pkg];
-            for name in names {
-                crates_using_proc_macros
-                    .entry(&**name)
-                    .or_default()
-                    .insert(&**pseudo_extern_crate_name);
+    let translate_extern_crate_name = |lib_package: &cm::PackageId, dst: &str| -> _ {
+        let dst_package = metadata.dep_lib_by_extern_crate_name(lib_package, dst)?;
+        let (_, dst_pseudo_extern_crate_name) =
+            libs_to_bundle.get(&dst_package.id).unwrap_or_else(|| {
+                panic!(
+                    "missing `extern_crate_name` for `{}`. generated one should be given \
+                     beforehead. this is a bug",
+                    dst_package.id,
+                );
+            });
+        Some(dst_pseudo_extern_crate_name.clone())
+    };
+
+    // `syn` and `proc_macro2` values cannot be sent to other threads. Each library is parsed,
+    // scanned for what is needed to compute its `ProcessKey`, and processed in one worker thread.
+    let cargo_equip_mod_name_str = &*cargo_equip_mod_name.to_string();
+    let open = |pkg: &'cm cm::PackageId| {
+        let cargo_equip_mod_name =
+            &syn::Ident::new(cargo_equip_mod_name_str, proc_macro2::Span::call_site());
+        let (krate, _) = &libs_to_bundle[pkg];
+        open_lib(
+            cargo_equip_mod_name,
+            pkg,
+            krate,
+            out_dirs.get(pkg).map(|p| &**p),
+        )
+    };
+    let scan = |edit: &CodeEdit| {
+        (
+            edit.has_local_inner_macros_attr(),
+            edit.source_files().clone(),
+        )
+    };
+    let process = |pkg: &'cm cm::PackageId, edit, key: &ProcessKey| {
+        process_lib(edit, key, |dst| translate_extern_crate_name(pkg, dst))
+    };
+
+    let libs = thread::scope(|scope| -> anyhow::Result<_> {
+        let mut workers = LibWorkers::spawn(scope, libs_to_bundle.len(), &open, &scan, &process);
+
+        let mut has_local_inner_macros_attrs = BTreeMap::new();
+        let mut scanning = vec![];
+
+        for pkg in libs_to_bundle.keys() {
+            let out_dir = out_dirs.get(*pkg).map(|p| &**p);
+            if let Some(has_local_inner_macros_attr) = session.processed_libs.scan(pkg, out_dir) {
+                has_local_inner_macros_attrs.insert(*pkg, has_local_inner_macros_attr);
+            } else {
+                scanning.push(*pkg);
             }
         }
-        for goal in libs_to_bundle.keys() {
-            if metadata[goal].has_proc_macro() {
-                let mut dfs = Dfs::new(&graph, indices[goal]);
-                while let Some(next) = dfs.next(&graph) {
-                    let (_, pseudo_extern_crate_name) = &libs_to_bundle[graph[next]];
-                    for name in &proc_macro_names[goal] {
-                        crates_using_proc_macros
-                            .entry(name)
-                            .or_default()
-                            .insert(pseudo_extern_crate_name);
+
+        for (pkg, (has_local_inner_macros_attr, source_files)) in workers.scan(scanning)? {
+            let out_dir = out_dirs.get(pkg).map(|p| &**p);
+            session.processed_libs.record_scan(
+                pkg,
+                out_dir,
+                &source_files,
+                has_local_inner_macros_attr,
+            )?;
+            has_local_inner_macros_attrs.insert(pkg, has_local_inner_macros_attr);
+        }
+
+        let (graph, indices) = normal_non_host_dep_graph(&resolve_nodes, libs_to_bundle);
+
+        let libs_using_proc_macros = {
+            let mut crates_using_proc_macros = BTreeMap::<_, HashSet<_>>::new();
+            for (pkg, names) in &proc_macro_names {
+                let (_, pseudo_extern_crate_name) = &libs_to_bundle[pkg];
+                for name in names {
+                    crates_using_proc_macros
+                        .entry(&**name)
+                        .or_default()
+                        .insert(&**pseudo_extern_crate_name);
+                }
+            }
+            for goal in libs_to_bundle.keys() {
+                if metadata[goal].has_proc_macro() {
+                    let mut dfs = Dfs::new(&graph, indices[goal]);
+                    while let Some(next) = dfs.next(&graph) {
+                        let (_, pseudo_extern_crate_name) = &libs_to_bundle[graph[next]];
+                        for name in &proc_macro_names[goal] {
+                            crates_using_proc_macros
+                                .entry(name)
+                                .or_default()
+                                .insert(pseudo_extern_crate_name);
+                        }
                     }
                 }
             }
-        }
-        crates_using_proc_macros
-    };
+            crates_using_proc_macros
+        };
 
-    let libs_with_local_inner_macros = {
-        let mut libs_with_local_inner_macros = libs
-            .keys()
-            .map(|pkg| (*pkg, btreeset!()))
-            .collect::<HashMap<_, _>>();
-        for (goal, (_, pseudo_extern_crate_name, edit)) in &libs {
-            if edit.has_local_inner_macros_attr() {
-                libs_with_local_inner_macros
-                    .get_mut(*goal)
-                    .unwrap()
-                    .insert(&**pseudo_extern_crate_name);
-                let mut dfs = Dfs::new(&graph, indices[goal]);
-                while let Some(next) = dfs.next(&graph) {
+        let libs_with_local_inner_macros = {
+            let mut libs_with_local_inner_macros = libs_to_bundle
+                .keys()
+                .map(|pkg| (*pkg, btreeset!()))
+                .collect::<HashMap<_, _>>();
+            for (goal, (_, pseudo_extern_crate_name)) in libs_to_bundle {
+                if has_local_inner_macros_attrs[goal] {
                     libs_with_local_inner_macros
-                        .get_mut(graph[next])
+                        .get_mut(*goal)
                         .unwrap()
                         .insert(&**pseudo_extern_crate_name);
+                    let mut dfs = Dfs::new(&graph, indices[goal]);
+                    while let Some(next) = dfs.next(&graph) {
+                        libs_with_local_inner_macros
+                            .get_mut(graph[next])
+                            .unwrap()
+                            .insert(&**pseudo_extern_crate_name);
+                    }
                 }
             }
-        }
-        libs_with_local_inner_macros
-    };
+            libs_with_local_inner_macros
+        };
 
-    let libs = libs
-        .into_iter()
-        .map(
-            |(lib_package, (lib_target, pseudo_extern_crate_name, mut edit))| {
-                let lib_package: &cm::Package = &metadata[lib_package];
+        enum Lib<'a> {
+            Done(String, String, String),
+            Cached(&'a cm::PackageId, ProcessKey),
+            Processing(ProcessKey),
+        }
 
-                if let Some(names) = proc_macro_names.get(&lib_package.id) {
-                    debug_assert_eq!(["proc-macro".to_owned()], *lib_target.kind);
-                    let names = names
+        let mut libs = vec![];
+
+        for (lib_package, (lib_target, pseudo_extern_crate_name)) in libs_to_bundle {
+            let lib_package: &cm::Package = &metadata[lib_package];
+            let pseudo_extern_crate_name = &**pseudo_extern_crate_name;
+
+            if let Some(names) = proc_macro_names.get(&lib_package.id) {
+                debug_assert_eq!(["proc-macro".to_owned()], *lib_target.kind);
+                let names = names
+                    .iter()
+                    .map(|name| {
+                        let rename = format!(
+                            "{}_macro_def_{}_{}",
+                            cargo_equip_mod_name, pseudo_extern_crate_name, name,
+                        );
+                        (name, rename)
+                    })
+                    .collect::<Vec<_>>();
+                let crate_mod_content = format!(
+                    "pub use crate::{}::macros::{}::*;{}",
+                    cargo_equip_mod_name,
+                    pseudo_extern_crate_name,
+                    names
                         .iter()
-                        .map(|name| {
-                            let rename = format!(
-                                "{}_macro_def_{}_{}",
-                                cargo_equip_mod_name, pseudo_extern_crate_name, name,
+                        .map(|(name, rename)| {
+                            let msg = format!(
+                                "`{}` from `{} {}` should have been expanded",
+                                name, lib_package.name, lib_package.version,
                             );
-                            (name, rename)
+                            format!(
+                                "#[macro_export]macro_rules!{}\
+                                 {{($(_:tt)*)=>(::std::compile_error!({});)}}",
+                                rename,
+                                quote!(#msg),
+                            )
                         })
-                        .collect::<Vec<_>>();
-                    let crate_mod_content = format!(
-                        "pub use crate::{}::macros::{}::*;{}",
-                        cargo_equip_mod_name,
-                        pseudo_extern_crate_name,
-                        names
-                            .iter()
-                            .map(|(name, rename)| {
-                                let msg = format!(
-                                    "`{}` from `{} {}` should have been expanded",
-                                    name, lib_package.name, lib_package.version,
-                                );
-                                format!(
-                                    "#[macro_export]macro_rules!{}\
-                                     {{($(_:tt)*)=>(::std::compile_error!({});)}}",
-                                    rename,
-                                    quote!(#msg),
-                                )
-                            })
-                            .join("")
-                    );
-                    let macro_mod_content = format!(
-                        "pub use crate::{}{}{};",
-                        if names.len() == 1 { " " } else { "{" },
-                        names
-                            .iter()
-                            .map(|(name, rename)| format!("{} as {}", rename, name))
-                            .format(","),
-                        if names.len() == 1 { "" } else { "}" },
-                    );
-                    return Ok((
-                        pseudo_extern_crate_name,
-                        (
-                            lib_package,
+                        .join("")
+                );
+                let macro_mod_content = format!(
+                    "pub use crate::{}{}{};",
+                    if names.len() == 1 { " " } else { "{" },
+                    names
+                        .iter()
+                        .map(|(name, rename)| format!("{} as {}", rename, name))
+                        .format(","),
+                    if names.len() == 1 { "" } else { "}" },
+                );
+                libs.push((
+                    pseudo_extern_crate_name,
+                    lib_package,
+                    Lib::Done(crate_mod_content, macro_mod_content, "".to_owned()),
+                ));
+                continue;
+            }
+
+            let cm::Node { features, deps, .. } = resolve_nodes[&lib_package.id];
+
+            let out_dir = out_dirs.get(&lib_package.id).map(|p| &**p);
+
+            let extern_crate_name_translation = metadata
+                .libs_with_extern_crate_names(
+                    &lib_package.id,
+                    &libs_to_bundle.keys().copied().collect(),
+                )?
+                .into_iter()
+                .map(|(package_id, extern_crate_name)| {
+                    let (_, pseudo_extern_crate_name) =
+                        libs_to_bundle.get(package_id).with_context(|| {
+                            "could not translate pseudo extern crate names. this is a bug"
+                        })?;
+                    Ok((extern_crate_name, pseudo_extern_crate_name.clone()))
+                })
+                .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+
+            let key = ProcessKey {
+                cargo_equip_mod_name: cargo_equip_mod_name.to_string(),
+                pseudo_extern_crate_name: pseudo_extern_crate_name.to_owned(),
+                deps: deps
+                    .iter()
+                    .map(|cm::NodeDep { name, pkg, .. }| {
+                        let pseudo_extern_crate_name =
+                            libs_to_bundle.get(pkg).map(|(_, name)| name.clone());
+                        (name.clone(), pseudo_extern_crate_name)
+                    })
+                    .collect(),
+                extern_crate_name_translation,
+                libs_with_local_inner_macros: libs_with_local_inner_macros[&lib_package.id]
+                    .iter()
+                    .map(|&name| name.to_owned())
+                    .collect(),
+                features: features.clone(),
+                resolve_cfgs,
+                remove_docs: remove.contains(&Remove::Docs),
+                remove_comments: remove.contains(&Remove::Comments),
+                translate_paths,
+                modify_macros,
+                resolve_preludes,
+            };
+
+            let lib = if session
+                .processed_libs
+                .get(&lib_package.id, out_dir, &key)
+                .is_some()
+            {
+                Lib::Cached(&lib_package.id, key)
+            } else {
+                workers.process(&lib_package.id, key.clone());
+                Lib::Processing(key)
+            };
+            libs.push((pseudo_extern_crate_name, lib_package, lib));
+        }
+
+        let mut processed = workers.finish()?;
+
+        let libs = libs
+            .into_iter()
+            .map(|(pseudo_extern_crate_name, lib_package, lib)| {
+                let out_dir = out_dirs.get(&lib_package.id).map(|p| &**p);
+
+                let processed = match lib {
+                    Lib::Done(crate_mod_content, macro_mod_content, prelude_mod_content) => {
+                        ProcessedLib {
                             crate_mod_content,
                             macro_mod_content,
-                            "".to_owned(),
-                        ),
-                    ));
-                }
-
-                let cm::Node { features, .. } = resolve_nodes[&lib_package.id];
-
-                let translate_extern_crate_name = |dst: &_| -> _ {
-                    let dst_package =
-                        metadata.dep_lib_by_extern_crate_name(&lib_package.id, dst)?;
-                    let (_, dst_pseudo_extern_crate_name) =
-                        libs_to_bundle.get(&dst_package.id).unwrap_or_else(|| {
-                            panic!(
-                                "missing `extern_crate_name` for `{}`. generated one should be \
-                                 given beforehead. this is a bug",
-                                dst_package.id,
-                            );
-                        });
-                    Some(dst_pseudo_extern_crate_name.clone())
+                            prelude_mod_content,
+                            warnings: vec![],
+                        }
+                    }
+                    Lib::Cached(package_id, key) => session
+                        .processed_libs
+                        .get(package_id, out_dir, &key)
+                        .expect("should exist")
+                        .clone(),
+                    Lib::Processing(key) => {
+                        let processed = processed
+                            .remove(&lib_package.id)
+                            .expect("should have been processed");
+                        session.processed_libs.insert(
+                            &lib_package.id,
+                            out_dir,
+                            key,
+                            processed.clone(),
+                        )?;
+                        processed
+                    }
                 };
 
-                edit.translate_crate_path(pseudo_extern_crate_name)?;
-                edit.translate_extern_crate_paths(translate_extern_crate_name)?;
-                edit.process_extern_crates_in_lib(translate_extern_crate_name, shell)?;
-                let macro_mod_content = edit.modify_declarative_macros(pseudo_extern_crate_name)?;
-                let prelude_mod_content = edit.resolve_pseudo_prelude(
-                    pseudo_extern_crate_name,
-                    &libs_with_local_inner_macros[&lib_package.id],
-                    &{
-                        metadata
-                            .libs_with_extern_crate_names(
-                                &lib_package.id,
-                                &libs_to_bundle.keys().copied().collect(),
-                            )?
-                            .into_iter()
-                            .map(|(package_id, extern_crate_name)| {
-                                let (_, pseudo_extern_crate_name) =
-                                libs_to_bundle.get(package_id).with_context(|| {
-                                    "could not translate pseudo extern crate names. this is a bug"
-                                })?;
-                                Ok((extern_crate_name, pseudo_extern_crate_name.clone()))
-                            })
-                            .collect::<anyhow::Result<_>>()?
-                    },
-                )?;
-                if resolve_cfgs {
-                    edit.resolve_cfgs(features)?;
-                }
-                if remove.contains(&Remove::Docs) {
-                    edit.allow_missing_docs();
-                    edit.erase_docs()?;
-                }
-                if remove.contains(&Remove::Comments) {
-                    edit.erase_comments()?;
+                for warning in &processed.warnings {
+                    shell.warn(warning)?;
                 }
 
-                let crate_mod_content = edit.finish()?;
+                let ProcessedLib {
+                    crate_mod_content,
+                    macro_mod_content,
+                    prelude_mod_content,
+                    ..
+                } = processed;
 
                 Ok((
                     pseudo_extern_crate_name,
@@ -997,9 +1931,14 @@ fn bundle(
                         prelude_mod_content,
                     ),
                 ))
-            },
-        )
-        .collect::<anyhow::Result<Vec<(&str, (&cm::Package, String, String, String))>>>()?;
+            })
+            .collect::<anyhow::Result<Vec<(&str, (&cm::Package, String, String, String))>>>()?;
+
+        Ok((libs, libs_using_proc_macros, libs_with_local_inner_macros))
+    });
+    let (libs, libs_using_proc_macros, libs_with_local_inner_macros) = libs?;
+
+    let mut notices_content = "".to_owned();
 
     if !libs.is_empty() {
         if !root_crate.package().authors.is_empty() {
@@ -1019,7 +1958,7 @@ fn bundle(
                 )
             })?;
 
-        let doc = &{
+        let doc = {
             fn list_packages<'a>(
                 doc: &mut String,
                 title: &str,
@@ -1050,7 +1989,13 @@ fn bundle(
                     }
 
                     row.add_cell(if let Some(license) = &package.license {
-                        cell!(format!("licensed under `{}`", license))
+                        match package.chosen_license() {
+                            Some(chosen) if chosen != *license => cell!(format!(
+                                "licensed under `{}` (complying with `{}`)",
+                                license, chosen,
+                            )),
+                            _ => cell!(format!("licensed under `{}`", license)),
+                        }
                     } else {
                         cell!("licensed under **missing**")
                     });
@@ -1097,21 +2042,39 @@ fn bundle(
                     .map(|(_, (p, _, _, _))| (None, *p)),
             );
 
-            let notices = libs
+            let lib_packages = libs
                 .iter()
-                .filter(|(_, (p, _, _, _))| {
-                    p.has_lib() && !metadata.workspace_members.contains(&p.id)
-                })
-                .map(|(_, (lib_package, _, _, _))| {
-                    shell.status("Checking", format!("the license of `{}`", lib_package.id))?;
-                    lib_package
-                        .read_license_text(mine, cache_dir)
-                        .map(|license_text| {
-                            license_text.map(|license_text| (&lib_package.id, license_text))
-                        })
-                })
-                .flat_map(Result::transpose)
-                .collect::<Result<Vec<_>, _>>()?;
+                .map(|(_, (p, _, _, _))| *p)
+                .filter(|p| p.has_lib() && !metadata.workspace_members.contains(&p.id))
+                .collect::<Vec<_>>();
+
+            for lib_package in &lib_packages {
+                shell.status("Checking", format!("the license of `{}`", lib_package.id))?;
+            }
+
+            let (notices, errors): (Vec<_>, Vec<_>) = workers::map(&lib_packages, |lib_package| {
+                lib_package
+                    .read_license_text(mine, cache_dir, offline)
+                    .map(|license_text| {
+                        license_text.map(|license_text| (&lib_package.id, license_text))
+                    })
+            })
+            .into_iter()
+            .flat_map(Result::transpose)
+            .partition_result();
+
+            if offline && !errors.is_empty() {
+                bail!(
+                    "could not resolve the following without network\n\n{}",
+                    errors
+                        .iter()
+                        .map(|e| format!("- {}\n", e.root_cause()))
+                        .join(""),
+                );
+            }
+            if let Some(err) = errors.into_iter().next() {
+                return Err(err);
+            }
 
             if !notices.is_empty() {
                 doc += "\n # License and Copyright Notices\n";
@@ -1142,6 +2105,19 @@ fn bundle(
             doc
         };
 
+        let doc = &if let Some(notices) = notices {
+            for line in doc.lines() {
+                notices_content += line.strip_prefix(' ').unwrap_or(line);
+                notices_content += "\n";
+            }
+            format!(
+                " The bundled crates and their license notices are listed in `{}`.\n",
+                notices.display(),
+            )
+        } else {
+            doc
+        };
+
         code += "\n";
         code += &match root_crate {
             RootCrate::BinLike(..) => {
@@ -1151,9 +2127,27 @@ fn bundle(
         };
         code += "\n";
 
+        let shortened_crate_mod_contents = if minify == Minify::Aggressive {
+            libs.iter()
+                .map(
+                    |(_, (_, crate_mod_content, macro_mod_content, prelude_mod_content))| {
+                        rust::shorten_idents(
+                            crate_mod_content,
+                            &[macro_mod_content, prelude_mod_content],
+                        )
+                    },
+                )
+                .collect::<anyhow::Result<Vec<_>>>()?
+        } else {
+            libs.iter()
+                .map(|(_, (_, content, _, _))| content.clone())
+                .collect()
+        };
+
         let crate_mods = libs
             .iter()
-            .map(|(name, (_, content, _, _))| (*name, &**content))
+            .zip_eq(&shortened_crate_mod_contents)
+            .map(|((name, _), content)| (*name, &**content))
             .collect::<Vec<_>>();
 
         let macro_mods = libs
@@ -1260,21 +2254,175 @@ fn bundle(
         code += "}\n";
     }
 
-    if minify == Minify::All {
+    if matches!(minify, Minify::All | Minify::Aggressive) {
         code = rustminify::minify_file(&rust::parse_file(&code)?);
     }
 
+    code = expand_templates(
+        code,
+        header,
+        footer,
+        metadata,
+        root_crate,
+        libs_to_bundle,
+        active_toolchain,
+    )?;
+
     if rustfmt {
         code = rustfmt::rustfmt(
             &metadata.workspace_root,
             &code,
             &root_crate.package().edition,
+            rustfmt_config,
+            shell,
         )?;
     }
 
+    Ok((code, notices_content))
+}
+
+/// Prepends `header` and appends `footer`.
+fn expand_templates(
+    mut code: String,
+    header: Option<&Template>,
+    footer: Option<&Template>,
+    metadata: &cm::Metadata,
+    root_crate: RootCrate<'_>,
+    libs_to_bundle: &BTreeMap<&cm::PackageId, (&cm::Target, String)>,
+    active_toolchain: &str,
+) -> anyhow::Result<String> {
+    let value = |name: &str| -> anyhow::Result<_> {
+        Ok(match name {
+            "root_target" => Some(root_crate.split().1.name.clone()),
+            // `HEAD` of the repository that contains the workspace root, not of each library.
+            "git_sha" => Some(
+                ProcessBuilder::new("git")
+                    .args(&["rev-parse", "HEAD"])
+                    .cwd(&metadata.workspace_root)
+                    .read_stdout::<String>()?
+                    .trim()
+                    .to_owned(),
+            ),
+            "bundled_crates" => Some(
+                libs_to_bundle
+                    .keys()
+                    .filter(|&&id| *id != root_crate.package().id)
+                    .map(|&id| format!("{} v{}", metadata[id].name, metadata[id].version))
+                    .join(", "),
+            ),
+            "toolchain" => Some(active_toolchain.to_owned()),
+            "date" => Some(chrono::Local::now().to_rfc3339_opts(SecondsFormat::Secs, false)),
+            _ => None,
+        })
+    };
+    if let Some(header) = header {
+        code = header.expand(value)? + &code;
+    }
+    if let Some(footer) = footer {
+        if !code.ends_with('\n') {
+            code += "\n";
+        }
+        code += &footer.expand(value)?;
+    }
     Ok(code)
 }
 
+fn open_lib(
+    cargo_equip_mod_name: &syn::Ident,
+    pkg: &cm::PackageId,
+    krate: &cm::Target,
+    out_dir: Option<&Utf8Path>,
+) -> anyhow::Result<CodeEdit> {
+    let mut edit = CodeEdit::new(cargo_equip_mod_name, &krate.src_path, || {
+        (krate.crate_name(), &pkg.repr)
+    })?;
+    if let Some(out_dir) = out_dir {
+        edit.expand_includes(out_dir)?;
+    }
+    Ok(edit)
+}
+
+fn process_lib(
+    mut edit: CodeEdit,
+    key: &ProcessKey,
+    translate_extern_crate_name: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<ProcessedLib> {
+    let mut warnings = vec![];
+
+    if key.translate_paths {
+        edit.translate_crate_path(&key.pseudo_extern_crate_name)?;
+        edit.translate_extern_crate_paths(&translate_extern_crate_name)?;
+        edit.process_extern_crates_in_lib(&translate_extern_crate_name, &mut warnings)?;
+    }
+    let macro_mod_content = if key.modify_macros {
+        edit.modify_declarative_macros(&key.pseudo_extern_crate_name)?
+    } else {
+        "".to_owned()
+    };
+    let prelude_mod_content = if key.resolve_preludes {
+        edit.resolve_pseudo_prelude(
+            &key.pseudo_extern_crate_name,
+            &key.libs_with_local_inner_macros
+                .iter()
+                .map(|s| &**s)
+                .collect(),
+            &key.extern_crate_name_translation,
+        )?
+    } else {
+        "".to_owned()
+    };
+    if key.resolve_cfgs {
+        edit.resolve_cfgs(&key.features)?;
+    }
+    if key.remove_docs {
+        edit.allow_missing_docs();
+        edit.erase_docs()?;
+    }
+    if key.remove_comments {
+        edit.erase_comments()?;
+    }
+
+    Ok(ProcessedLib {
+        crate_mod_content: edit.finish()?,
+        macro_mod_content,
+        prelude_mod_content,
+        warnings,
+    })
+}
+
+fn join<T>(handle: thread::ScopedJoinHandle<'_, T>) -> T {
+    handle
+        .join()
+        .unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
+
+fn libs_to_bundle<'cm>(
+    metadata: &'cm cm::Metadata,
+    root_package: &cm::Package,
+    root: &cm::Target,
+    unused_deps: Option<&HashSet<String>>,
+    exclude: &[PackageSpec],
+    cfgs: &[cfg_expr::Expression],
+) -> anyhow::Result<BTreeMap<&'cm cm::PackageId, (&'cm cm::Target, String)>> {
+    let root_package = &metadata[&root_package.id];
+    let mut libs_to_bundle = metadata.libs_to_bundle(
+        &root_package.id,
+        root.is_example(),
+        unused_deps.unwrap_or(&hashset!()),
+        exclude,
+        cfgs,
+    )?;
+    if root.is_lib() {
+        let root = root_package
+            .targets
+            .iter()
+            .find(|t| t.is_lib())
+            .expect("should be the lib target");
+        libs_to_bundle.insert(&root_package.id, (root, root.crate_name()));
+    }
+    Ok(libs_to_bundle)
+}
+
 fn normal_non_host_dep_graph<'cm>(
     resolve_nodes: &HashMap<&'cm cm::PackageId, &cm::Node>,
     libs_to_bundle: &BTreeMap<&'cm cm::PackageId, (&cm::Target, String)>,
@@ -1333,3 +2481,264 @@ impl<'cm> RootCrate<'cm> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        diagnose::Passes, session::Session, shell::Shell, template::Template,
+        workspace::MetadataExt as _, AfterBundling, Minify, Opt, OptEquip, OptRun, RootCrate,
+        Subcommand, User,
+    };
+    use maplit::btreemap;
+    use pretty_assertions::assert_eq;
+    use std::{
+        io::{self, Write as _},
+        process::{Command, Stdio},
+    };
+    use structopt::StructOpt as _;
+
+    #[test]
+    fn bundle_skipped() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let pkg = &tempdir.path().join("app");
+        let main_rs = "#![cfg_attr(cargo_equip, cargo_equip::skip)]\nfn main() {}\n";
+        for (path, content) in [
+            (
+                "Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.0.0\"\nedition = \"2018\"\n",
+            ),
+            ("src/main.rs", main_rs),
+            ("header.txt", "// {{root_target}}: [{{bundled_crates}}]"),
+            ("footer.txt", "// end"),
+        ] {
+            cargo_util::paths::create_dir_all(pkg.join(path).parent().unwrap())?;
+            cargo_util::paths::write(pkg.join(path), content)?;
+        }
+        let metadata = crate::workspace::cargo_metadata(&pkg.join("Cargo.toml"), pkg, true)?;
+        let (target, package) = metadata.bin_target_by_name("app")?;
+        let cache_dir = &tempdir.path().join("cache");
+
+        let (code, notices) = super::bundle(
+            &metadata,
+            RootCrate::BinLike(package, target),
+            &btreemap!(),
+            &[],
+            true,
+            &syn::Ident::new("__cargo_equip", proc_macro2::Span::call_site()),
+            None,
+            Some(&Template::read(&pkg.join("header.txt"))?),
+            Some(&Template::read(&pkg.join("footer.txt"))?),
+            &Passes {
+                resolve_cfgs: true,
+                remove: vec![],
+                minify: Minify::None,
+                rustfmt: false,
+                translate_paths: true,
+                modify_macros: true,
+                resolve_preludes: true,
+            },
+            None,
+            None,
+            cache_dir,
+            &mut Session::new(cache_dir),
+            &mut Shell::from_stdout(Box::new(io::sink())),
+        )?;
+        assert_eq!(format!("// app: []\n{}// end\n", main_rs), code);
+        assert_eq!("", notices);
+        Ok(())
+    }
+
+    #[test]
+    fn equip_run() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let ws = &tempdir.path().join("ws");
+        for (path, content) in [
+            (
+                "Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\n\
+                 [dependencies]\nlib1 = { path = \"lib1\" }\n",
+            ),
+            (
+                "src/main.rs",
+                "use std::io::Read as _;\n\n\
+                 fn main() {\n    \
+                 let mut input = String::new();\n    \
+                 std::io::stdin().read_to_string(&mut input).unwrap();\n    \
+                 let n = input.trim().parse().unwrap();\n    \
+                 println!(\"{} {:?}\", lib1::double(n), std::env::args().nth(1));\n\
+                 }\n",
+            ),
+            (
+                "lib1/Cargo.toml",
+                "[package]\nname = \"lib1\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\
+                 license = \"CC0-1.0\"\n",
+            ),
+            (
+                "lib1/src/lib.rs",
+                "pub fn double(n: u32) -> u32 {\n    n * 2\n}\n",
+            ),
+        ] {
+            cargo_util::paths::create_dir_all(ws.join(path).parent().unwrap())?;
+            cargo_util::paths::write(ws.join(path), content)?;
+        }
+        let cache_dir = &tempdir.path().join("cache");
+
+        let opt = |args: &[&str]| {
+            let Opt::Equip(OptEquip { subcommand, .. }) = Opt::from_iter_safe(
+                ["cargo", "equip", "run", "--offline", "--no-rustfmt"]
+                    .iter()
+                    .chain(args),
+            )
+            .unwrap();
+            match subcommand {
+                Some(Subcommand::Run(OptRun { bundle, .. })) => bundle,
+                _ => unreachable!(),
+            }
+        };
+        let equip = |args: &[&str]| {
+            super::equip(
+                opt(args),
+                AfterBundling::Run,
+                ws,
+                cache_dir,
+                &mut Session::new(cache_dir),
+                &mut Shell::from_stdout(Box::new(io::sink())),
+            )
+        };
+
+        let (code, exe) = equip(&["--bin", "app"])?;
+        assert!(code.contains("pub mod lib1 {"));
+        let mut child = Command::new(exe.unwrap())
+            .arg("foo")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(b"21\n")?;
+        let output = child.wait_with_output()?;
+        assert_eq!("42 Some(\"foo\")\n", String::from_utf8(output.stdout)?);
+
+        let err = equip(&["--manifest-path", "lib1/Cargo.toml", "--lib"]).unwrap_err();
+        assert_eq!(
+            "`cargo equip run` is not available for libraries",
+            err.to_string(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn equip_notices() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let ws = &tempdir.path().join("ws");
+        for (path, content) in [
+            (
+                "ws/Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\n\
+                 [dependencies]\nlib1 = { path = \"../lib1\" }\n",
+            ),
+            ("ws/src/main.rs", "fn main() {\n    lib1::f();\n}\n"),
+            (
+                "lib1/Cargo.toml",
+                "[package]\nname = \"lib1\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\
+                 license = \"MIT\"\n",
+            ),
+            ("lib1/LICENSE", "Copyright (c) lib1 authors\n"),
+            ("lib1/src/lib.rs", "pub fn f() {}\n"),
+        ] {
+            let path = tempdir.path().join(path);
+            cargo_util::paths::create_dir_all(path.parent().unwrap())?;
+            cargo_util::paths::write(path, content)?;
+        }
+        let cache_dir = &tempdir.path().join("cache");
+
+        let Opt::Equip(OptEquip { bundle: opt, .. }) = Opt::from_iter_safe(&[
+            "cargo",
+            "equip",
+            "--offline",
+            "--no-rustfmt",
+            "--no-check",
+            "--bin",
+            "app",
+            "--notices",
+            "NOTICES.md",
+        ])?;
+        let (code, _) = super::equip(
+            opt,
+            AfterBundling::Check,
+            ws,
+            cache_dir,
+            &mut Session::new(cache_dir),
+            &mut Shell::from_stdout(Box::new(io::sink())),
+        )?;
+
+        let doc = code
+            .lines()
+            .flat_map(|l| l.strip_prefix("///"))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["The bundled crates and their license notices are listed in `NOTICES.md`."],
+            doc,
+        );
+        assert!(!code.contains("lib1 authors"));
+        let notices = cargo_util::paths::read(&ws.join("NOTICES.md"))?;
+        assert!(notices.starts_with("# Bundled libraries\n"), "{}", notices);
+        assert!(notices.contains("# License and Copyright Notices\n"));
+        assert!(notices.contains("\n    Copyright (c) lib1 authors\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn user_owns() {
+        let user = |s: &str| s.parse::<User>().unwrap();
+
+        for (mine, owner, expected) in [
+            ("github.com/alice", User::Github("alice".to_owned()), true),
+            ("github.com/alice", User::Github("bob".to_owned()), false),
+            (
+                "github.com/alice",
+                User::CratesIo("alice".to_owned()),
+                false,
+            ),
+            (
+                "gitlab.com/alice",
+                User::GitlabCom("alice".to_owned()),
+                true,
+            ),
+            ("gitlab.com/alice", User::Github("alice".to_owned()), false),
+            (
+                "codeberg.org/alice",
+                User::forge("codeberg.org", "alice"),
+                true,
+            ),
+            (
+                "codeberg.org/alice",
+                User::forge("git.example.com", "alice"),
+                false,
+            ),
+            (
+                "codeberg.org/team/*",
+                User::Org("codeberg.org".to_owned(), "team".to_owned()),
+                true,
+            ),
+            (
+                "codeberg.org/team/*",
+                User::forge("codeberg.org", "team"),
+                false,
+            ),
+            ("crates.io/alice", User::CratesIo("alice".to_owned()), true),
+            ("crates.io/alice", User::Github("alice".to_owned()), false),
+            ("path:/libs", User::Path("/libs/a".into()), true),
+            ("path:/libs", User::Path("/libs".into()), true),
+            ("path:/libs", User::Path("/libs2/a".into()), false),
+            ("path:/libs/a", User::Path("/libs".into()), false),
+        ] {
+            assert_eq!(expected, user(mine).owns(&owner), "{} {:?}", mine, owner);
+        }
+    }
+}
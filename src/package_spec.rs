@@ -0,0 +1,318 @@
+use crate::workspace::PackageExt as _;
+use cargo_metadata as cm;
+use itertools::Itertools as _;
+use semver::{Version, VersionReq};
+use std::{fmt, str::FromStr};
+
+/// A spec for `--exclude`, `tree --invert` and `cache`.
+///
+/// In addition to the formats of `cargo pkgid` (`[<url>#]<name>[:<version>]`,
+/// `[<url>#]<name>[@<version>]` and `<url>#<version>`), it accepts
+/// - `<name>@<requirement>` for versions that meet the requirement, which must start with an
+///   operator,
+/// - `*` and `?` in `<name>`, and
+/// - `registry:`, `git:` or `path:` in place of `<url>#`.
+///
+/// ```text
+/// itertools@^0.10
+/// num-*
+/// registry:*
+/// https://github.com/rust-lang/crates.io-index#proconio@0.4.5
+/// ```
+#[derive(Debug)]
+pub struct PackageSpec {
+    repr: String,
+    source: Option<SourceFilter>,
+    name: String,
+    version: VersionFilter,
+}
+
+#[derive(Debug, PartialEq)]
+enum SourceFilter {
+    Registry,
+    Git,
+    Path,
+    Url(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum VersionFilter {
+    Any,
+    Exact(Version),
+    Req(VersionReq),
+}
+
+impl PackageSpec {
+    pub(crate) fn matches(&self, package: &cm::Package) -> bool {
+        self.matches_name_and_source(package)
+            && self.matches_name_and_version(&package.name, Some(&package.version))
+    }
+
+    /// Matches the name, and the version if it is known. The source is not checked.
+    pub(crate) fn matches_name_and_version(&self, name: &str, version: Option<&Version>) -> bool {
+        glob_match(&self.name, name)
+            && match (&self.version, version) {
+                (VersionFilter::Exact(expected), Some(version)) => expected == version,
+                (VersionFilter::Req(req), Some(version)) => req.matches(version),
+                (VersionFilter::Any, _) | (_, None) => true,
+            }
+    }
+
+    fn matches_name_and_source(&self, package: &cm::Package) -> bool {
+        let source = package.source.as_ref().map(|s| &*s.repr);
+        glob_match(&self.name, &package.name)
+            && match &self.source {
+                None => true,
+                Some(SourceFilter::Registry) => {
+                    source.is_some_and(|s| s.starts_with("registry+") || s.starts_with("sparse+"))
+                }
+                Some(SourceFilter::Git) => source.is_some_and(|s| s.starts_with("git+")),
+                Some(SourceFilter::Path) => source.is_none(),
+                Some(SourceFilter::Url(url)) => {
+                    if let Some(source) = source {
+                        normalize_url(source) == *url
+                    } else {
+                        normalize_url(&format!("file://{}", package.manifest_dir())) == *url
+                    }
+                }
+            }
+    }
+
+    /// Returns warnings for the packages to bundle whose names are excluded in other versions.
+    pub(crate) fn mismatches<'a>(
+        specs: &[Self],
+        packages: impl IntoIterator<Item = &'a cm::Package>,
+    ) -> Vec<String> {
+        packages
+            .into_iter()
+            .flat_map(|package| {
+                let specs = specs
+                    .iter()
+                    .filter(|s| s.version != VersionFilter::Any)
+                    .filter(|s| s.matches_name_and_source(package))
+                    .collect::<Vec<_>>();
+                (!specs.is_empty()).then(|| {
+                    format!(
+                        "`{}` is resolved to v{}, which does not match {}. it will be bundled",
+                        package.name,
+                        package.version,
+                        specs.iter().map(|s| format!("`{}`", s)).format(", "),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+impl FromStr for PackageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (source, name, version) = if let Some((url, rest)) = s.split_once('#') {
+            let url = normalize_url(url);
+            if let Ok(version) = rest.parse::<Version>() {
+                // `<url>#<version>`. The name is the last segment of the URL.
+                let name = url.rsplit('/').next().unwrap_or_default().to_owned();
+                (
+                    Some(SourceFilter::Url(url)),
+                    name,
+                    VersionFilter::Exact(version),
+                )
+            } else {
+                let (name, version) = parse_name_and_version(rest)?;
+                (Some(SourceFilter::Url(url)), name, version)
+            }
+        } else {
+            let (source, rest) = match s.split_once(':') {
+                Some(("registry", rest)) if rest.parse::<Version>().is_err() => {
+                    (Some(SourceFilter::Registry), rest)
+                }
+                Some(("git", rest)) if rest.parse::<Version>().is_err() => {
+                    (Some(SourceFilter::Git), rest)
+                }
+                Some(("path", rest)) if rest.parse::<Version>().is_err() => {
+                    (Some(SourceFilter::Path), rest)
+                }
+                _ => (None, s),
+            };
+            let (name, version) = parse_name_and_version(rest)?;
+            (source, name, version)
+        };
+
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ['-', '_', '*', '?'].contains(&c))
+        {
+            return Err(format!("invalid package name pattern: `{}`", name));
+        }
+
+        return Ok(Self {
+            repr: s.to_owned(),
+            source,
+            name,
+            version,
+        });
+
+        fn parse_name_and_version(s: &str) -> Result<(String, VersionFilter), String> {
+            if let Some((name, version)) = s.split_once('@') {
+                let version = if let Ok(version) = version.parse() {
+                    VersionFilter::Exact(version)
+                } else if version.starts_with(['=', '^', '~', '>', '<', '*']) {
+                    VersionFilter::Req(
+                        version
+                            .parse()
+                            .map_err(|e| format!("invalid requirement: {}", e))?,
+                    )
+                } else {
+                    return Err(format!(
+                        "invalid version: `{}`. a requirement must start with an operator (e.g. \
+                         `^{}`)",
+                        version, version,
+                    ));
+                };
+                Ok((name.to_owned(), version))
+            } else if let Some((name, version)) = s.split_once(':') {
+                let version = version
+                    .parse()
+                    .map_err(|e| format!("invalid version: {}", e))?;
+                Ok((name.to_owned(), VersionFilter::Exact(version)))
+            } else {
+                Ok((s.to_owned(), VersionFilter::Any))
+            }
+        }
+    }
+}
+
+impl fmt::Display for PackageSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.repr)
+    }
+}
+
+/// Removes the kind (`registry+` etc.), the query, the fragment and the trailing `/`.
+fn normalize_url(url: &str) -> String {
+    let url = match url.split_once('+') {
+        Some((kind, url)) if !kind.contains(':') => url,
+        _ => url,
+    };
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    url.trim_end_matches('/').to_owned()
+}
+
+/// Matches `text` against `pattern` with `*` and `?`.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    backtrack = Some((bp, bt + 1));
+                    p = bp + 1;
+                    t = bt + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PackageSpec, SourceFilter, VersionFilter};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn glob_match() {
+        for (pattern, text, expected) in [
+            ("itertools", "itertools", true),
+            ("itertools", "itertool", false),
+            ("num-*", "num-traits", true),
+            ("num-*", "num", false),
+            ("*", "", true),
+            ("*-*", "ac-library-rs", true),
+            ("?", "a", true),
+            ("?", "ab", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYbZ", false),
+        ] {
+            assert_eq!(
+                expected,
+                super::glob_match(pattern, text),
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn parse() {
+        fn test(spec: &str, source: Option<SourceFilter>, name: &str, version: VersionFilter) {
+            let spec = spec.parse::<PackageSpec>().unwrap();
+            assert_eq!(
+                (source, name, version),
+                (spec.source, &*spec.name, spec.version),
+            );
+        }
+
+        let url = || SourceFilter::Url("https://github.com/rust-lang/crates.io-index".to_owned());
+        let exact = |v: &str| VersionFilter::Exact(v.parse().unwrap());
+        let req = |r: &str| VersionFilter::Req(r.parse().unwrap());
+
+        test("either", None, "either", VersionFilter::Any);
+        test("either:1.9.0", None, "either", exact("1.9.0"));
+        test("either@1.9.0", None, "either", exact("1.9.0"));
+        test("either@^1.9", None, "either", req("^1.9"));
+        test("either@>=1, <2", None, "either", req(">=1, <2"));
+        test("num-*", None, "num-*", VersionFilter::Any);
+        test(
+            "registry:*",
+            Some(SourceFilter::Registry),
+            "*",
+            VersionFilter::Any,
+        );
+        test(
+            "git:foo@1.0.0",
+            Some(SourceFilter::Git),
+            "foo",
+            exact("1.0.0"),
+        );
+        test(
+            "https://github.com/rust-lang/crates.io-index#either:1.9.0",
+            Some(url()),
+            "either",
+            exact("1.9.0"),
+        );
+        test(
+            "registry+https://github.com/rust-lang/crates.io-index#either@1.9.0",
+            Some(url()),
+            "either",
+            exact("1.9.0"),
+        );
+        test(
+            "path+file:///ws/either#1.9.0",
+            Some(SourceFilter::Url("file:///ws/either".to_owned())),
+            "either",
+            exact("1.9.0"),
+        );
+
+        assert!("either@1.9".parse::<PackageSpec>().is_err());
+        assert!("either@1".parse::<PackageSpec>().is_err());
+        assert!("either@^x".parse::<PackageSpec>().is_err());
+        assert!("either:^1".parse::<PackageSpec>().is_err());
+        assert!("".parse::<PackageSpec>().is_err());
+        assert!("foo.bar".parse::<PackageSpec>().is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata as cm;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+
+/// The number of outputs kept for each library. The oldest ones are dropped first.
+const MAX_OUTPUTS_PER_ENTRY: usize = 8;
+
+/// Outputs of `CodeEdit`s for library crates, kept while their source files stay the same.
+///
+/// Each entry is also saved to `<cache-dir>/processed-libs/<hash>.json` so that it can be reused
+/// between runs.
+pub(crate) struct ProcessedLibs {
+    cache_dir: PathBuf,
+    entries: HashMap<EntryKey, Entry>,
+}
+
+impl ProcessedLibs {
+    pub(crate) fn new(cache_dir: &Path) -> Self {
+        Self {
+            cache_dir: cache_dir.join("processed-libs"),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns `has_local_inner_macros_attr` of the library if none of the source files have been
+    /// changed.
+    pub(crate) fn scan(
+        &mut self,
+        package_id: &cm::PackageId,
+        out_dir: Option<&Utf8Path>,
+    ) -> Option<bool> {
+        let key = EntryKey::new(package_id, out_dir);
+        if !self.entries.contains_key(&key) {
+            let entry = self.load(&key)?;
+            self.entries.insert(key.clone(), entry);
+        }
+        let entry = &self.entries[&key];
+        if entry
+            .source_files
+            .iter()
+            .all(|(path, expected)| digest(path).as_ref() == Some(expected))
+        {
+            Some(entry.has_local_inner_macros_attr)
+        } else {
+            self.entries.remove(&key);
+            None
+        }
+    }
+
+    pub(crate) fn record_scan(
+        &mut self,
+        package_id: &cm::PackageId,
+        out_dir: Option<&Utf8Path>,
+        source_files: &BTreeSet<Utf8PathBuf>,
+        has_local_inner_macros_attr: bool,
+    ) -> anyhow::Result<()> {
+        let source_files = source_files
+            .iter()
+            .map(|path| digest(path).map(|digest| (path.clone(), digest)))
+            .collect::<Option<_>>();
+
+        let key = EntryKey::new(package_id, out_dir);
+
+        if let Some(source_files) = source_files {
+            let entry = Entry {
+                cargo_equip_version: env!("CARGO_PKG_VERSION").to_owned(),
+                key: key.clone(),
+                source_files,
+                has_local_inner_macros_attr,
+                outputs: vec![],
+            };
+            Self::save(&self.cache_dir, &entry)?;
+            self.entries.insert(key, entry);
+        } else {
+            self.entries.remove(&key);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get(
+        &self,
+        package_id: &cm::PackageId,
+        out_dir: Option<&Utf8Path>,
+        key: &ProcessKey,
+    ) -> Option<&ProcessedLib> {
+        self.entries
+            .get(&EntryKey::new(package_id, out_dir))?
+            .outputs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, processed)| processed)
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        package_id: &cm::PackageId,
+        out_dir: Option<&Utf8Path>,
+        key: ProcessKey,
+        processed: ProcessedLib,
+    ) -> anyhow::Result<()> {
+        if let Some(entry) = self.entries.get_mut(&EntryKey::new(package_id, out_dir)) {
+            entry.outputs.retain(|(k, _)| *k != key);
+            entry.outputs.push((key, processed));
+            if entry.outputs.len() > MAX_OUTPUTS_PER_ENTRY {
+                let excess = entry.outputs.len() - MAX_OUTPUTS_PER_ENTRY;
+                entry.outputs.drain(..excess);
+            }
+            Self::save(&self.cache_dir, entry)?;
+        }
+        Ok(())
+    }
+
+    fn load(&self, key: &EntryKey) -> Option<Entry> {
+        let json = std::fs::read_to_string(key.path(&self.cache_dir)).ok()?;
+        serde_json::from_str::<Entry>(&json)
+            .ok()
+            .filter(|e| e.cargo_equip_version == env!("CARGO_PKG_VERSION") && e.key == *key)
+    }
+
+    /// Writes to a temporary file and renames it, so that an interrupted run does not leave a
+    /// broken file.
+    fn save(cache_dir: &Path, entry: &Entry) -> anyhow::Result<()> {
+        cargo_util::paths::create_dir_all(cache_dir)?;
+        let mut file = NamedTempFile::new_in(cache_dir)?;
+        file.write_all(
+            serde_json::to_string(entry)
+                .expect("should not fail")
+                .as_ref(),
+        )?;
+        file.persist(entry.key.path(cache_dir))?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+struct EntryKey {
+    package_id: String,
+    out_dir: Option<Utf8PathBuf>,
+}
+
+impl EntryKey {
+    fn new(package_id: &cm::PackageId, out_dir: Option<&Utf8Path>) -> Self {
+        Self {
+            package_id: package_id.repr.clone(),
+            out_dir: out_dir.map(ToOwned::to_owned),
+        }
+    }
+
+    fn path(&self, cache_dir: &Path) -> PathBuf {
+        let digest = md5::compute(serde_json::to_string(self).expect("should not fail"));
+        cache_dir.join(format!("{:x}.json", digest))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    cargo_equip_version: String,
+    key: EntryKey,
+    source_files: BTreeMap<Utf8PathBuf, String>,
+    has_local_inner_macros_attr: bool,
+    outputs: Vec<(ProcessKey, ProcessedLib)>,
+}
+
+/// Everything other than the source files that affects the output.
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub(crate) struct ProcessKey {
+    pub(crate) cargo_equip_mod_name: String,
+    pub(crate) pseudo_extern_crate_name: String,
+    pub(crate) deps: Vec<(String, Option<String>)>,
+    pub(crate) extern_crate_name_translation: BTreeMap<String, String>,
+    pub(crate) libs_with_local_inner_macros: BTreeSet<String>,
+    pub(crate) features: Vec<String>,
+    pub(crate) resolve_cfgs: bool,
+    pub(crate) remove_docs: bool,
+    pub(crate) remove_comments: bool,
+    pub(crate) translate_paths: bool,
+    pub(crate) modify_macros: bool,
+    pub(crate) resolve_preludes: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct ProcessedLib {
+    pub(crate) crate_mod_content: String,
+    pub(crate) macro_mod_content: String,
+    pub(crate) prelude_mod_content: String,
+    pub(crate) warnings: Vec<String>,
+}
+
+fn digest(path: &Utf8Path) -> Option<String> {
+    let content = std::fs::read(path).ok()?;
+    Some(format!("{:x}", md5::compute(content)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProcessKey, ProcessedLib, ProcessedLibs};
+    use camino::Utf8PathBuf;
+    use cargo_metadata as cm;
+    use maplit::btreeset;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn cache_hits() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let cache_dir = &tempdir.path().join("cache");
+        let src = Utf8PathBuf::from_path_buf(tempdir.path().join("lib.rs")).unwrap();
+        cargo_util::paths::write(&src, "pub fn f() {}\n")?;
+
+        let pkg = &cm::PackageId {
+            repr: "lib 0.1.0 (path+file:///lib)".to_owned(),
+        };
+        let key = |remove_docs| ProcessKey {
+            cargo_equip_mod_name: "__cargo_equip".to_owned(),
+            pseudo_extern_crate_name: "lib".to_owned(),
+            deps: vec![],
+            extern_crate_name_translation: Default::default(),
+            libs_with_local_inner_macros: btreeset!(),
+            features: vec![],
+            resolve_cfgs: true,
+            remove_docs,
+            remove_comments: false,
+            translate_paths: true,
+            modify_macros: true,
+            resolve_preludes: true,
+        };
+        let processed = ProcessedLib {
+            crate_mod_content: "pub fn f(){}".to_owned(),
+            macro_mod_content: "".to_owned(),
+            prelude_mod_content: "".to_owned(),
+            warnings: vec![],
+        };
+        let crate_mod_content = |libs: &ProcessedLibs, remove_docs| {
+            libs.get(pkg, None, &key(remove_docs))
+                .map(|p| p.crate_mod_content.clone())
+        };
+
+        let mut libs = ProcessedLibs::new(cache_dir);
+        assert_eq!(None, libs.scan(pkg, None));
+        libs.record_scan(pkg, None, &btreeset!(src.clone()), true)?;
+        libs.insert(pkg, None, key(false), processed)?;
+        assert_eq!(
+            Some("pub fn f(){}".to_owned()),
+            crate_mod_content(&libs, false)
+        );
+        assert_eq!(None, crate_mod_content(&libs, true));
+
+        // Another run reads the entry from the cache directory.
+        let mut libs = ProcessedLibs::new(cache_dir);
+        assert_eq!(Some(true), libs.scan(pkg, None));
+        assert_eq!(
+            Some("pub fn f(){}".to_owned()),
+            crate_mod_content(&libs, false)
+        );
+
+        cargo_util::paths::write(&src, "pub fn g() {}\n")?;
+        let mut libs = ProcessedLibs::new(cache_dir);
+        assert_eq!(None, libs.scan(pkg, None));
+        assert_eq!(None, crate_mod_content(&libs, false));
+        Ok(())
+    }
+    #[test]
+    fn eviction_and_invalidation() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let cache_dir = &tempdir.path().join("cache");
+        let src = Utf8PathBuf::from_path_buf(tempdir.path().join("lib.rs")).unwrap();
+        cargo_util::paths::write(&src, "pub fn f() {}\n")?;
+
+        let pkg = &cm::PackageId {
+            repr: "lib 0.1.0 (path+file:///lib)".to_owned(),
+        };
+        let out_dir = Utf8PathBuf::from_path_buf(tempdir.path().join("out")).unwrap();
+        let key = |i: usize| ProcessKey {
+            cargo_equip_mod_name: "__cargo_equip".to_owned(),
+            pseudo_extern_crate_name: "lib".to_owned(),
+            deps: vec![],
+            extern_crate_name_translation: Default::default(),
+            libs_with_local_inner_macros: btreeset!(),
+            features: vec![format!("f{}", i)],
+            resolve_cfgs: true,
+            remove_docs: false,
+            remove_comments: false,
+            translate_paths: true,
+            modify_macros: true,
+            resolve_preludes: true,
+        };
+        let processed = |i: usize| ProcessedLib {
+            crate_mod_content: format!("pub fn f{}(){{}}", i),
+            macro_mod_content: "".to_owned(),
+            prelude_mod_content: "".to_owned(),
+            warnings: vec![],
+        };
+        let cached = |libs: &ProcessedLibs| {
+            (0..=super::MAX_OUTPUTS_PER_ENTRY)
+                .filter(|&i| libs.get(pkg, None, &key(i)).is_some())
+                .collect::<Vec<_>>()
+        };
+
+        let mut libs = ProcessedLibs::new(cache_dir);
+        libs.record_scan(pkg, None, &btreeset!(src.clone()), false)?;
+        for i in 0..=super::MAX_OUTPUTS_PER_ENTRY {
+            libs.insert(pkg, None, key(i), processed(i))?;
+        }
+        // The oldest output is dropped.
+        assert_eq!(
+            (1..=super::MAX_OUTPUTS_PER_ENTRY).collect::<Vec<_>>(),
+            cached(&libs),
+        );
+        // Outputs for another `OUT_DIR` are kept apart, and are not recorded without a scan.
+        assert_eq!(None, libs.scan(pkg, Some(&out_dir)));
+        libs.insert(pkg, Some(&out_dir), key(0), processed(0))?;
+        assert!(libs.get(pkg, Some(&out_dir), &key(0)).is_none());
+
+        // Entries written by other versions of cargo-equip are ignored.
+        let mut libs = ProcessedLibs::new(cache_dir);
+        assert_eq!(Some(false), libs.scan(pkg, None));
+        assert_eq!(
+            (1..=super::MAX_OUTPUTS_PER_ENTRY).collect::<Vec<_>>(),
+            cached(&libs),
+        );
+        let path = super::EntryKey::new(pkg, None).path(&cache_dir.join("processed-libs"));
+        let json = std::fs::read_to_string(&path)?.replace(
+            &format!(r#""cargo_equip_version":"{}""#, env!("CARGO_PKG_VERSION")),
+            r#""cargo_equip_version":"0.0.0""#,
+        );
+        cargo_util::paths::write(&path, json)?;
+        let mut libs = ProcessedLibs::new(cache_dir);
+        assert_eq!(None, libs.scan(pkg, None));
+        assert_eq!(Vec::<usize>::new(), cached(&libs));
+        Ok(())
+    }
+}
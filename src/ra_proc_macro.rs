@@ -8,7 +8,10 @@ use ra_ap_proc_macro_api::{
 };
 use ra_ap_tt::{self as tt, DelimiterKind, Leaf, TokenId};
 use semver::Version;
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
 
 pub(crate) const MSRV: Version = Version::new(1, 64, 0);
 
@@ -44,13 +47,20 @@ pub struct ProcMacroExpander<'msg> {
     attr: BTreeMap<String, (&'msg cm::PackageId, ProcMacro)>,
 }
 
+pub(crate) fn spawn_server(proc_macro_srv_exe: &AbsPath) -> anyhow::Result<ProcMacroServer> {
+    ProcMacroServer::spawn(proc_macro_srv_exe.to_path_buf()).with_context(|| {
+        format!(
+            "could not spawn `{}`",
+            Path::new(proc_macro_srv_exe.as_os_str()).display(),
+        )
+    })
+}
+
 impl<'msg> ProcMacroExpander<'msg> {
-    pub(crate) fn spawn(
-        proc_macro_srv_exe: &AbsPath,
+    pub(crate) fn load(
+        server: &ProcMacroServer,
         dylib_paths: &BTreeMap<&'msg cm::PackageId, &'msg AbsPath>,
     ) -> anyhow::Result<Self> {
-        let server = ProcMacroServer::spawn(proc_macro_srv_exe.to_path_buf())?;
-
         let mut custom_derive = btreemap!();
         let mut func_like = btreemap!();
         let mut attr = btreemap!();
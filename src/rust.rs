@@ -1,14 +1,15 @@
-use crate::{ra_proc_macro::ProcMacroExpander, shell::Shell};
+use crate::ra_proc_macro::ProcMacroExpander;
 use anyhow::{anyhow, bail, Context as _};
 use camino::{Utf8Path, Utf8PathBuf};
 use fixedbitset::FixedBitSet;
 use if_chain::if_chain;
 use itertools::Itertools as _;
-use maplit::btreemap;
+use maplit::{btreemap, btreeset};
 use proc_macro2::{LineColumn, Span, TokenStream, TokenTree};
 use quote::{quote, ToTokens};
 use std::{
     borrow::Cow,
+    cmp,
     collections::{BTreeMap, BTreeSet, VecDeque},
     env, mem,
     ops::Range,
@@ -20,21 +21,21 @@ use syn::{
     punctuated::{Pair, Punctuated},
     spanned::Spanned,
     visit::{self, Visit},
-    Arm, AttrStyle, Attribute, BareFnArg, ConstParam, Expr, ExprArray, ExprAssign, ExprAssignOp,
-    ExprAsync, ExprAwait, ExprBinary, ExprBlock, ExprBox, ExprBreak, ExprCall, ExprCast,
-    ExprClosure, ExprContinue, ExprField, ExprForLoop, ExprGroup, ExprIf, ExprIndex, ExprLet,
-    ExprLit, ExprLoop, ExprMacro, ExprMatch, ExprMethodCall, ExprParen, ExprPath, ExprRange,
-    ExprReference, ExprRepeat, ExprReturn, ExprStruct, ExprTry, ExprTryBlock, ExprTuple, ExprType,
-    ExprUnary, ExprUnsafe, ExprWhile, ExprYield, Field, FieldPat, FieldValue, ForeignItemFn,
-    ForeignItemMacro, ForeignItemStatic, ForeignItemType, Ident, ImplItemConst, ImplItemMacro,
-    ImplItemMethod, ImplItemType, Item, ItemConst, ItemEnum, ItemExternCrate, ItemFn,
-    ItemForeignMod, ItemImpl, ItemMacro, ItemMacro2, ItemMod, ItemStatic, ItemStruct, ItemTrait,
-    ItemTraitAlias, ItemType, ItemUnion, ItemUse, LifetimeDef, Lit, LitStr, Local, Macro, Meta,
-    MetaList, MetaNameValue, NestedMeta, PatBox, PatIdent, PatLit, PatMacro, PatOr, PatPath,
-    PatRange, PatReference, PatRest, PatSlice, PatStruct, PatTuple, PatTupleStruct, PatType,
-    PatWild, PathSegment, Receiver, Token, TraitItemConst, TraitItemMacro, TraitItemMethod,
-    TraitItemType, TypeParam, UseGroup, UseName, UsePath, UseRename, UseTree, Variadic, Variant,
-    VisRestricted,
+    Arm, AttrStyle, Attribute, BareFnArg, Block, ConstParam, Expr, ExprArray, ExprAssign,
+    ExprAssignOp, ExprAsync, ExprAwait, ExprBinary, ExprBlock, ExprBox, ExprBreak, ExprCall,
+    ExprCast, ExprClosure, ExprContinue, ExprField, ExprForLoop, ExprGroup, ExprIf, ExprIndex,
+    ExprLet, ExprLit, ExprLoop, ExprMacro, ExprMatch, ExprMethodCall, ExprParen, ExprPath,
+    ExprRange, ExprReference, ExprRepeat, ExprReturn, ExprStruct, ExprTry, ExprTryBlock, ExprTuple,
+    ExprType, ExprUnary, ExprUnsafe, ExprWhile, ExprYield, Field, FieldPat, FieldValue, Fields,
+    FnArg, ForeignItemFn, ForeignItemMacro, ForeignItemStatic, ForeignItemType, GenericParam,
+    Generics, Ident, ImplItemConst, ImplItemMacro, ImplItemMethod, ImplItemType, Item, ItemConst,
+    ItemEnum, ItemExternCrate, ItemFn, ItemForeignMod, ItemImpl, ItemMacro, ItemMacro2, ItemMod,
+    ItemStatic, ItemStruct, ItemTrait, ItemTraitAlias, ItemType, ItemUnion, ItemUse, LifetimeDef,
+    Lit, LitStr, Local, Macro, Member, Meta, MetaList, MetaNameValue, NestedMeta, Pat, PatBox,
+    PatIdent, PatLit, PatMacro, PatOr, PatPath, PatRange, PatReference, PatRest, PatSlice,
+    PatStruct, PatTuple, PatTupleStruct, PatType, PatWild, PathSegment, QSelf, Receiver, Signature,
+    Stmt, Token, TraitItemConst, TraitItemMacro, TraitItemMethod, TraitItemType, TypeParam,
+    TypePath, UseGroup, UseName, UsePath, UseRename, UseTree, Variadic, Variant, VisRestricted,
 };
 
 pub(crate) fn find_skip_attribute(code: &str) -> anyhow::Result<bool> {
@@ -264,6 +265,562 @@ pub(crate) fn allow_unused_imports_for_seemingly_proc_macros(
     }
 }
 
+/// Renames local variables and generic parameters in the content of a pseudo extern crate to short
+/// names.
+///
+/// Each binding is resolved following the lexical scopes and renamed on its own, so items, fields
+/// and paths are never touched. A binding is kept as it is if
+/// - its name appears in a macro invocation, in `macro_rules!` or in `reserved_code` (e.g. the
+///   `macros` and `preludes` modules),
+/// - it is used as a shorthand field (`S { x }`), or
+/// - its name starts with `_`, or is also the name of a constant, a static or a unit struct.
+pub(crate) fn shorten_idents(code: &str, reserved_code: &[&str]) -> anyhow::Result<String> {
+    let file = &parse_file(code)?;
+
+    let idents = |code: &str| -> anyhow::Result<_> {
+        let token_stream = code
+            .parse::<TokenStream>()
+            .map_err(|e| anyhow!("{}", e))
+            .with_context(|| "broke the code during modification")?;
+        let mut idents = btreeset!();
+        collect_idents(token_stream, &mut idents);
+        Ok(idents)
+    };
+
+    let mut kept = btreeset!();
+    for code in reserved_code {
+        kept.extend(idents(code)?);
+    }
+    let mut existing = idents(code)?;
+    existing.extend(kept.iter().cloned());
+    existing.extend(KEYWORDS.iter().chain(PRELUDE).map(|&s| s.to_owned()));
+
+    let mut visitor = Visitor {
+        bindings: vec![],
+        frames: vec![],
+        pending: None,
+        kept,
+        unit: 0,
+        block_depth: 0,
+    };
+    visitor.visit_file(file);
+    let Visitor { bindings, kept, .. } = visitor;
+
+    let mut replacements = btreemap!();
+    let units = bindings
+        .iter()
+        .filter(|b| {
+            !b.shorthand
+                && !b.name.starts_with('_')
+                && !b.name.starts_with("r#")
+                && !kept.contains(&b.name)
+        })
+        .into_group_map_by(|b| b.unit);
+    for (_, bindings) in units.into_iter().sorted_by_key(|&(unit, _)| unit) {
+        // Bindings in different units never see each other, so the names are reused.
+        let (mut lower, mut upper) = (0, 0);
+        for binding in bindings
+            .into_iter()
+            .sorted_by_key(|b| cmp::Reverse(b.spans.len()))
+        {
+            let upper_case = binding.name.starts_with(|c: char| c.is_ascii_uppercase());
+            let new_name = loop {
+                let n = if upper_case { &mut upper } else { &mut lower };
+                let new_name = nth_name(*n, upper_case);
+                *n += 1;
+                if !existing.contains(&new_name) {
+                    break new_name;
+                }
+            };
+            for span in &binding.spans {
+                replacements.insert((span.start(), span.end()), new_name.clone());
+            }
+        }
+    }
+    return Ok(replace_ranges(code, replacements));
+
+    static KEYWORDS: &[&str] = &[
+        "_", "abstract", "as", "async", "await", "become", "box", "break", "const", "continue",
+        "crate", "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if",
+        "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv",
+        "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true",
+        "try", "type", "typeof", "union", "unsafe", "unsized", "use", "virtual", "where", "while",
+        "yield", "main",
+    ];
+
+    static PRELUDE: &[&str] = &[
+        "alloc",
+        "core",
+        "std",
+        "proc_macro",
+        "test",
+        "bool",
+        "char",
+        "str",
+        "i8",
+        "i16",
+        "i32",
+        "i64",
+        "i128",
+        "isize",
+        "u8",
+        "u16",
+        "u32",
+        "u64",
+        "u128",
+        "usize",
+        "f32",
+        "f64",
+        "Copy",
+        "Send",
+        "Sized",
+        "Sync",
+        "Unpin",
+        "Drop",
+        "Fn",
+        "FnMut",
+        "FnOnce",
+        "drop",
+        "Box",
+        "ToOwned",
+        "Clone",
+        "PartialEq",
+        "PartialOrd",
+        "Eq",
+        "Ord",
+        "AsRef",
+        "AsMut",
+        "Into",
+        "From",
+        "Default",
+        "Iterator",
+        "Extend",
+        "IntoIterator",
+        "DoubleEndedIterator",
+        "ExactSizeIterator",
+        "Option",
+        "Some",
+        "None",
+        "Result",
+        "Ok",
+        "Err",
+        "String",
+        "ToString",
+        "Vec",
+        "TryFrom",
+        "TryInto",
+        "FromIterator",
+    ];
+
+    /// Collects the identifiers and the words in format strings.
+    fn collect_idents(token_stream: TokenStream, acc: &mut BTreeSet<String>) {
+        let mut prev_is_quote = false;
+        for tt in token_stream {
+            match &tt {
+                TokenTree::Group(group) => collect_idents(group.stream(), acc),
+                TokenTree::Ident(ident) if !prev_is_quote => {
+                    acc.insert(ident.to_string());
+                }
+                TokenTree::Literal(literal) => {
+                    // Inline format arguments.
+                    let literal = literal.to_string();
+                    if literal.contains('{') {
+                        acc.extend(
+                            literal
+                                .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                                .filter(|s| !s.is_empty())
+                                .map(ToOwned::to_owned),
+                        );
+                    }
+                }
+                _ => {}
+            }
+            prev_is_quote = matches!(&tt, TokenTree::Punct(p) if p.as_char() == '\'');
+        }
+    }
+
+    fn nth_name(mut n: usize, upper_case: bool) -> String {
+        let mut name = vec![];
+        loop {
+            name.push(if upper_case { b'A' } else { b'a' } + (n % 26) as u8);
+            if n < 26 {
+                break;
+            }
+            n = n / 26 - 1;
+        }
+        name.reverse();
+        String::from_utf8(name).expect("should be ASCII")
+    }
+
+    fn is_lower(ident: &Ident) -> bool {
+        ident
+            .to_string()
+            .starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+    }
+
+    /// Names that items in a block put into the scope.
+    fn item_names(item: &Item, acc: &mut Vec<String>) {
+        let ident = match item {
+            Item::Const(ItemConst { ident, .. })
+            | Item::Enum(ItemEnum { ident, .. })
+            | Item::ExternCrate(ItemExternCrate { ident, .. })
+            | Item::Fn(ItemFn {
+                sig: Signature { ident, .. },
+                ..
+            })
+            | Item::Mod(ItemMod { ident, .. })
+            | Item::Static(ItemStatic { ident, .. })
+            | Item::Struct(ItemStruct { ident, .. })
+            | Item::Trait(ItemTrait { ident, .. })
+            | Item::TraitAlias(ItemTraitAlias { ident, .. })
+            | Item::Type(ItemType { ident, .. })
+            | Item::Union(ItemUnion { ident, .. }) => ident,
+            Item::Use(ItemUse { tree, .. }) => return use_tree_names(tree, acc),
+            _ => return,
+        };
+        acc.push(ident.to_string());
+
+        fn use_tree_names(tree: &UseTree, acc: &mut Vec<String>) {
+            match tree {
+                UseTree::Path(UsePath { tree, .. }) => use_tree_names(tree, acc),
+                UseTree::Name(UseName { ident })
+                | UseTree::Rename(UseRename { rename: ident, .. }) => acc.push(ident.to_string()),
+                UseTree::Glob(_) => {}
+                UseTree::Group(UseGroup { items, .. }) => {
+                    for item in items {
+                        use_tree_names(item, acc);
+                    }
+                }
+            }
+        }
+    }
+
+    struct Binding {
+        name: String,
+        /// The outermost item that contains the binding.
+        unit: usize,
+        spans: Vec<Span>,
+        shorthand: bool,
+    }
+
+    /// A scope. `None` is for items declared in blocks, which shadow the outer bindings.
+    #[derive(Default)]
+    struct Frame {
+        /// Whether the frame is of an item, which cannot see the bindings outside of it.
+        is_item: bool,
+        locals: BTreeMap<String, Option<usize>>,
+        generics: BTreeMap<String, Option<usize>>,
+    }
+
+    struct Visitor {
+        bindings: Vec<Binding>,
+        frames: Vec<Frame>,
+        /// The bindings in the pattern being visited.
+        pending: Option<BTreeMap<String, usize>>,
+        kept: BTreeSet<String>,
+        unit: usize,
+        block_depth: usize,
+    }
+
+    impl Visitor {
+        fn frame(&mut self) -> &mut Frame {
+            self.frames.last_mut().expect("should not be empty")
+        }
+
+        fn with_frame(&mut self, is_item: bool, f: impl FnOnce(&mut Self)) {
+            if is_item && self.block_depth == 0 {
+                self.unit += 1;
+            }
+            self.frames.push(Frame {
+                is_item,
+                ..Frame::default()
+            });
+            f(self);
+            self.frames.pop();
+        }
+
+        fn new_binding(&mut self, ident: &Ident) -> usize {
+            self.bindings.push(Binding {
+                name: ident.to_string(),
+                unit: self.unit,
+                spans: vec![ident.span()],
+                shorthand: false,
+            });
+            self.bindings.len() - 1
+        }
+
+        fn resolve(&self, ident: &Ident, value: bool) -> Option<usize> {
+            let name = ident.to_string();
+            for frame in self.frames.iter().rev() {
+                if value {
+                    if let Some(&id) = frame.locals.get(&name) {
+                        return id;
+                    }
+                }
+                if let Some(&id) = frame.generics.get(&name) {
+                    return id;
+                }
+                if frame.is_item {
+                    break;
+                }
+            }
+            None
+        }
+
+        fn use_(&mut self, ident: &Ident, value: bool) {
+            if let Some(id) = self.resolve(ident, value) {
+                self.bindings[id].spans.push(ident.span());
+            }
+        }
+
+        fn declare_generics(&mut self, generics: &Generics) {
+            for param in &generics.params {
+                if let GenericParam::Type(TypeParam { ident, .. })
+                | GenericParam::Const(ConstParam { ident, .. }) = param
+                {
+                    let id = self.new_binding(ident);
+                    self.frame().generics.insert(ident.to_string(), Some(id));
+                }
+            }
+        }
+
+        /// Visits `pat` and adds the bindings in it to the innermost frame.
+        fn bind(&mut self, pat: &Pat) {
+            let outer = self.pending.replace(btreemap!());
+            self.visit_pat(pat);
+            let bound = mem::replace(&mut self.pending, outer).expect("should be `Some`");
+            self.frame()
+                .locals
+                .extend(bound.into_iter().map(|(name, id)| (name, Some(id))));
+        }
+
+        /// Resolves the first segment of `path` unless it is qualified.
+        fn visit_qualified_path(&mut self, qself: Option<&QSelf>, path: &syn::Path, value: bool) {
+            if let Some(qself) = qself {
+                self.visit_qself(qself);
+            } else if path.leading_colon.is_none() {
+                if let Some(first) = path.segments.first() {
+                    let value = value && path.segments.len() == 1 && first.arguments.is_empty();
+                    self.use_(&first.ident, value);
+                }
+            }
+            for segment in &path.segments {
+                self.visit_path_arguments(&segment.arguments);
+            }
+        }
+    }
+
+    impl Visit<'_> for Visitor {
+        fn visit_attribute(&mut self, _: &Attribute) {}
+
+        fn visit_vis_restricted(&mut self, _: &VisRestricted) {}
+
+        fn visit_macro(&mut self, i: &Macro) {
+            collect_idents(i.tokens.clone(), &mut self.kept);
+        }
+
+        fn visit_item_macro(&mut self, i: &ItemMacro) {
+            if let Some(ident) = &i.ident {
+                self.kept.insert(ident.to_string());
+            }
+            self.visit_macro(&i.mac);
+        }
+
+        fn visit_item_macro2(&mut self, i: &ItemMacro2) {
+            collect_idents(i.rules.clone(), &mut self.kept);
+        }
+
+        fn visit_item(&mut self, i: &Item) {
+            match i {
+                Item::Const(ItemConst { ident, .. }) | Item::Static(ItemStatic { ident, .. }) => {
+                    // They may appear in patterns.
+                    self.kept.insert(ident.to_string());
+                }
+                Item::Struct(ItemStruct {
+                    ident,
+                    fields: Fields::Unit,
+                    ..
+                }) => {
+                    self.kept.insert(ident.to_string());
+                }
+                _ => {}
+            }
+            self.with_frame(true, |this| {
+                match i {
+                    Item::Enum(ItemEnum { generics, .. })
+                    | Item::Impl(ItemImpl { generics, .. })
+                    | Item::Struct(ItemStruct { generics, .. })
+                    | Item::Trait(ItemTrait { generics, .. })
+                    | Item::TraitAlias(ItemTraitAlias { generics, .. })
+                    | Item::Type(ItemType { generics, .. })
+                    | Item::Union(ItemUnion { generics, .. }) => this.declare_generics(generics),
+                    _ => {}
+                }
+                visit::visit_item(this, i);
+            });
+        }
+
+        fn visit_impl_item_method(&mut self, i: &ImplItemMethod) {
+            self.with_frame(false, |this| visit::visit_impl_item_method(this, i));
+        }
+
+        fn visit_trait_item_method(&mut self, i: &TraitItemMethod) {
+            self.with_frame(false, |this| visit::visit_trait_item_method(this, i));
+        }
+
+        fn visit_signature(&mut self, i: &Signature) {
+            self.declare_generics(&i.generics);
+            self.visit_generics(&i.generics);
+            for input in &i.inputs {
+                if let FnArg::Typed(PatType { pat, ty, .. }) = input {
+                    self.visit_type(ty);
+                    self.bind(pat);
+                }
+            }
+            self.visit_return_type(&i.output);
+        }
+
+        fn visit_block(&mut self, i: &Block) {
+            self.block_depth += 1;
+            self.with_frame(false, |this| {
+                let mut names = vec![];
+                for stmt in &i.stmts {
+                    if let Stmt::Item(item) = stmt {
+                        item_names(item, &mut names);
+                    }
+                }
+                for name in names {
+                    this.frame().locals.insert(name.clone(), None);
+                    this.frame().generics.insert(name, None);
+                }
+                visit::visit_block(this, i);
+            });
+            self.block_depth -= 1;
+        }
+
+        fn visit_local(&mut self, i: &Local) {
+            if let Some((_, init)) = &i.init {
+                self.visit_expr(init);
+            }
+            self.bind(&i.pat);
+        }
+
+        fn visit_expr_closure(&mut self, i: &ExprClosure) {
+            self.with_frame(false, |this| {
+                for input in &i.inputs {
+                    this.bind(input);
+                }
+                this.visit_return_type(&i.output);
+                this.visit_expr(&i.body);
+            });
+        }
+
+        fn visit_arm(&mut self, i: &Arm) {
+            self.with_frame(false, |this| {
+                this.bind(&i.pat);
+                if let Some((_, guard)) = &i.guard {
+                    this.visit_expr(guard);
+                }
+                this.visit_expr(&i.body);
+            });
+        }
+
+        fn visit_expr_if(&mut self, i: &ExprIf) {
+            self.with_frame(false, |this| {
+                this.visit_expr(&i.cond);
+                this.visit_block(&i.then_branch);
+            });
+            if let Some((_, else_branch)) = &i.else_branch {
+                self.visit_expr(else_branch);
+            }
+        }
+
+        fn visit_expr_while(&mut self, i: &ExprWhile) {
+            self.with_frame(false, |this| {
+                this.visit_expr(&i.cond);
+                this.visit_block(&i.body);
+            });
+        }
+
+        fn visit_expr_let(&mut self, i: &ExprLet) {
+            self.visit_expr(&i.expr);
+            self.bind(&i.pat);
+        }
+
+        fn visit_expr_for_loop(&mut self, i: &ExprForLoop) {
+            self.visit_expr(&i.expr);
+            self.with_frame(false, |this| {
+                this.bind(&i.pat);
+                this.visit_block(&i.body);
+            });
+        }
+
+        fn visit_pat_ident(&mut self, i: &PatIdent) {
+            // `Foo` without a subpattern is probably a constant or a unit struct.
+            if i.subpat.is_some() || is_lower(&i.ident) {
+                let name = i.ident.to_string();
+                match self.pending.as_ref().map(|p| p.get(&name).copied()) {
+                    // Outside the patterns handled here. Leave the name as it is.
+                    None => {
+                        self.kept.insert(name);
+                    }
+                    // The same name in `|` patterns.
+                    Some(Some(id)) => self.bindings[id].spans.push(i.ident.span()),
+                    Some(None) => {
+                        let id = self.new_binding(&i.ident);
+                        self.pending
+                            .as_mut()
+                            .expect("should be `Some`")
+                            .insert(name, id);
+                    }
+                }
+            }
+            if let Some((_, subpat)) = &i.subpat {
+                self.visit_pat(subpat);
+            }
+        }
+
+        fn visit_field_pat(&mut self, i: &FieldPat) {
+            self.visit_pat(&i.pat);
+            if let (Member::Named(ident), None) = (&i.member, i.colon_token) {
+                let id = self
+                    .pending
+                    .as_ref()
+                    .and_then(|p| p.get(&ident.to_string()).copied());
+                if let Some(id) = id {
+                    self.bindings[id].shorthand = true;
+                }
+            }
+        }
+
+        fn visit_field_value(&mut self, i: &FieldValue) {
+            if let (Member::Named(ident), None) = (&i.member, i.colon_token) {
+                if let Some(id) = self.resolve(ident, true) {
+                    self.bindings[id].shorthand = true;
+                }
+            } else {
+                self.visit_expr(&i.expr);
+            }
+        }
+
+        fn visit_path(&mut self, i: &syn::Path) {
+            self.visit_qualified_path(None, i, false);
+        }
+
+        fn visit_expr_path(&mut self, i: &ExprPath) {
+            self.visit_qualified_path(i.qself.as_ref(), &i.path, true);
+        }
+
+        fn visit_pat_path(&mut self, i: &PatPath) {
+            self.visit_qualified_path(i.qself.as_ref(), &i.path, false);
+        }
+
+        fn visit_type_path(&mut self, i: &TypePath) {
+            self.visit_qualified_path(i.qself.as_ref(), &i.path, false);
+        }
+    }
+}
+
 fn set_span(mask: &mut [FixedBitSet], span: Span, p: bool) {
     let i1 = span.start().line - 1;
     if span.start().line == span.end().line {
@@ -305,31 +862,40 @@ pub(crate) fn process_bin<'cm>(
     edit.finish()
 }
 
-pub(crate) struct CodeEdit<'opt> {
-    cargo_equip_mod_name: &'opt Ident,
+pub(crate) struct CodeEdit {
+    cargo_equip_mod_name: Ident,
     has_local_inner_macros_attr: bool,
+    source_files: BTreeSet<Utf8PathBuf>,
     string: String,
     file: syn::File,
     replacements: BTreeMap<(LineColumn, LineColumn), String>,
 }
 
-impl<'opt> CodeEdit<'opt> {
+impl CodeEdit {
     pub(crate) fn new<'cm>(
-        cargo_equip_mod_name: &'opt Ident,
+        cargo_equip_mod_name: &Ident,
         src_path: &Utf8Path,
         err_context: impl FnOnce() -> (String, &'cm str),
     ) -> anyhow::Result<Self> {
         return (|| {
-            Self::from_code(cargo_equip_mod_name, &expand_mods(src_path, 0)?)
-                .map_err(anyhow::Error::from)
+            let mut source_files = btreeset!();
+            let code = &expand_mods(src_path, 0, &mut source_files)?;
+            let mut edit = Self::from_code(cargo_equip_mod_name, code)?;
+            edit.source_files = source_files;
+            Ok::<_, anyhow::Error>(edit)
         })()
         .with_context(|| {
             let (crate_name, package_id) = err_context();
             format!("could not expand `{}` from `{}`", crate_name, package_id)
         });
 
-        fn expand_mods(src_path: &Utf8Path, depth: usize) -> anyhow::Result<String> {
+        fn expand_mods(
+            src_path: &Utf8Path,
+            depth: usize,
+            source_files: &mut BTreeSet<Utf8PathBuf>,
+        ) -> anyhow::Result<String> {
             let content = cargo_util::paths::read(src_path.as_ref())?;
+            source_files.insert(src_path.to_owned());
 
             let syn::File { items, .. } = syn::parse_file(&content)
                 .map_err(|e| anyhow!("{:?}", e))
@@ -386,7 +952,7 @@ impl<'opt> CodeEdit<'opt> {
                     if let Some(path) = paths.iter().find(|p| p.exists()) {
                         let start = semi.span().start();
                         let end = semi.span().end();
-                        let content = expand_mods(path, depth + 1)?;
+                        let content = expand_mods(path, depth + 1, source_files)?;
                         let content = indent_code(&content, depth + 1);
                         let content = format!(" {{\n{}{}}}", content, "    ".repeat(depth + 1));
                         Ok(((start, end), content))
@@ -400,11 +966,12 @@ impl<'opt> CodeEdit<'opt> {
         }
     }
 
-    fn from_code(cargo_equip_mod_name: &'opt Ident, string: &str) -> syn::Result<Self> {
+    fn from_code(cargo_equip_mod_name: &Ident, string: &str) -> syn::Result<Self> {
         let file = syn::parse_file(string)?;
         return Ok(Self {
-            cargo_equip_mod_name,
+            cargo_equip_mod_name: cargo_equip_mod_name.clone(),
             has_local_inner_macros_attr: check_local_inner_macros(&file),
+            source_files: btreeset!(),
             string: string.to_owned(),
             file,
             replacements: btreemap!(),
@@ -448,6 +1015,11 @@ impl<'opt> CodeEdit<'opt> {
         self.has_local_inner_macros_attr
     }
 
+    /// Files read so far, including the ones `include!`d.
+    pub(crate) fn source_files(&self) -> &BTreeSet<Utf8PathBuf> {
+        &self.source_files
+    }
+
     pub(crate) fn finish(mut self) -> anyhow::Result<String> {
         self.apply()?;
         Ok(self.string)
@@ -474,7 +1046,7 @@ impl<'opt> CodeEdit<'opt> {
         self.apply()?;
         Visitor {
             replacements: &mut self.replacements,
-            cargo_equip_mod_name: self.cargo_equip_mod_name,
+            cargo_equip_mod_name: &self.cargo_equip_mod_name,
             is_lib_to_bundle,
         }
         .visit_file(&self.file);
@@ -541,7 +1113,7 @@ impl<'opt> CodeEdit<'opt> {
     pub(crate) fn process_extern_crates_in_lib(
         &mut self,
         convert_extern_crate_name: impl FnMut(&str) -> Option<String>,
-        shell: &mut Shell,
+        warnings: &mut Vec<String>,
     ) -> anyhow::Result<()> {
         self.apply()?;
 
@@ -553,19 +1125,19 @@ impl<'opt> CodeEdit<'opt> {
                 ..
             }) = item
             {
-                shell.warn(format!(
+                warnings.push(format!(
                     "declaring `extern crate .. as ..` in a root module is not recommended: \
                      `{} extern crate {} as {}`",
                     vis.to_token_stream(),
                     ident,
                     rename,
-                ))?;
+                ));
             }
         }
 
         Visitor {
             replacements: &mut self.replacements,
-            cargo_equip_mod_name: self.cargo_equip_mod_name,
+            cargo_equip_mod_name: &self.cargo_equip_mod_name,
             convert_extern_crate_name,
         }
         .visit_file(&self.file);
@@ -899,6 +1471,7 @@ impl<'opt> CodeEdit<'opt> {
         Visitor {
             out_dir,
             replacements: &mut self.replacements,
+            source_files: &mut self.source_files,
         }
         .visit_file(&self.file);
         return Ok(());
@@ -906,6 +1479,7 @@ impl<'opt> CodeEdit<'opt> {
         struct Visitor<'a> {
             out_dir: &'a Utf8Path,
             replacements: &'a mut BTreeMap<(LineColumn, LineColumn), String>,
+            source_files: &'a mut BTreeSet<Utf8PathBuf>,
         }
 
         impl Visitor<'_> {
@@ -959,6 +1533,7 @@ impl<'opt> CodeEdit<'opt> {
                                 if let Ok(content) = cargo_util::paths::read(path.as_ref()) {
                                     self.replacements
                                         .insert((i.span().start(), i.span().end()), content);
+                                    self.source_files.insert(path);
                                 }
                             }
                         }
@@ -975,7 +1550,7 @@ impl<'opt> CodeEdit<'opt> {
         self.apply()?;
         Visitor {
             replacements: &mut self.replacements,
-            cargo_equip_mod_name: self.cargo_equip_mod_name,
+            cargo_equip_mod_name: &self.cargo_equip_mod_name,
             translate_extern_crate_name,
         }
         .visit_file(&self.file);
@@ -1047,7 +1622,7 @@ impl<'opt> CodeEdit<'opt> {
         self.apply()?;
         Visitor {
             extern_crate_name,
-            cargo_equip_mod_name: self.cargo_equip_mod_name,
+            cargo_equip_mod_name: &self.cargo_equip_mod_name,
             replacements: &mut self.replacements,
         }
         .visit_file(&self.file);
@@ -1138,7 +1713,7 @@ impl<'opt> CodeEdit<'opt> {
             {
                 replace_dollar_crates(
                     tokens.clone(),
-                    self.cargo_equip_mod_name,
+                    &self.cargo_equip_mod_name,
                     pseudo_extern_crate_name,
                     &mut self.replacements,
                 );
@@ -1677,6 +2252,7 @@ mod tests {
     use crate::rust::CodeEdit;
     use pretty_assertions::assert_eq;
     use proc_macro2::Span;
+    use std::{env, process::Command};
     use syn::Ident;
 
     thread_local! {
@@ -1770,4 +2346,214 @@ fn main() {
 "#,
         )
     }
+
+    #[test]
+    fn shorten_idents() -> anyhow::Result<()> {
+        static FOREIGN: &str = r#"pub mod foreign {
+    pub struct Complex {
+        pub re: f64,
+        pub im: f64,
+    }
+}
+
+"#;
+
+        fn test(input: &str, expected: &str, bin: &str) -> anyhow::Result<()> {
+            let actual = crate::rust::shorten_idents(input, &[])?;
+            assert_eq!(expected, actual);
+            for lib in [input, &actual] {
+                assert_compiles(&format!("{}pub mod lib {{\n{}}}\n\n{}", FOREIGN, lib, bin))?;
+            }
+            Ok(())
+        }
+
+        fn assert_compiles(code: &str) -> anyhow::Result<()> {
+            let dir = tempfile::Builder::new()
+                .prefix("cargo-equip-test-")
+                .tempdir()?;
+            let path = dir.path().join("lib.rs");
+            std::fs::write(&path, code)?;
+            let output = Command::new(env::var_os("RUSTC").unwrap_or_else(|| "rustc".into()))
+                .args([
+                    "--edition",
+                    "2018",
+                    "--crate-type",
+                    "lib",
+                    "--emit",
+                    "metadata",
+                ])
+                .arg("--out-dir")
+                .arg(dir.path())
+                .arg(&path)
+                .output()?;
+            assert!(
+                output.status.success(),
+                "{}\n{}",
+                code,
+                String::from_utf8_lossy(&output.stderr),
+            );
+            Ok(())
+        }
+
+        test(
+            r#"pub fn solve(input_numbers: &[i64]) -> i64 {
+    let accumulator = Accumulator::new(input_numbers);
+    compute_total(&accumulator) + accumulator.offset
+}
+
+pub struct Accumulator<Element> {
+    values: Vec<Element>,
+    offset: i64,
+}
+
+impl<Element: Copy> Accumulator<Element> {
+    fn new(values: &[Element]) -> Self {
+        let offset = values.len() as i64;
+        Self {
+            values: values.to_vec(),
+            offset,
+        }
+    }
+}
+
+fn compute_total(accumulator: &Accumulator<i64>) -> i64 {
+    let named_in_format = accumulator.values.len();
+    println!("{named_in_format}");
+    accumulator.values.iter().sum()
+}
+"#,
+            r#"pub fn solve(b: &[i64]) -> i64 {
+    let a = Accumulator::new(b);
+    compute_total(&a) + a.offset
+}
+
+pub struct Accumulator<A> {
+    values: Vec<A>,
+    offset: i64,
+}
+
+impl<A: Copy> Accumulator<A> {
+    fn new(a: &[A]) -> Self {
+        let offset = a.len() as i64;
+        Self {
+            values: a.to_vec(),
+            offset,
+        }
+    }
+}
+
+fn compute_total(a: &Accumulator<i64>) -> i64 {
+    let named_in_format = a.values.len();
+    println!("{named_in_format}");
+    a.values.iter().sum()
+}
+"#,
+            "pub fn bin() -> i64 {\n    lib::solve(&[1, 2])\n}\n",
+        )?;
+
+        // Shorthand fields of a struct from another crate.
+        test(
+            r#"use crate::foreign::Complex;
+
+pub fn polar(re: f64, theta: f64) -> Complex {
+    let im = re * theta.sin();
+    let re = re * theta.cos();
+    Complex { re, im }
+}
+
+pub fn conj(value: Complex) -> Complex {
+    let Complex { re, im: imaginary } = value;
+    Complex { re, im: -imaginary }
+}
+"#,
+            r#"use crate::foreign::Complex;
+
+pub fn polar(a: f64, b: f64) -> Complex {
+    let im = a * b.sin();
+    let re = a * b.cos();
+    Complex { re, im }
+}
+
+pub fn conj(a: Complex) -> Complex {
+    let Complex { re, im: b } = a;
+    Complex { re, im: -b }
+}
+"#,
+            "",
+        )?;
+
+        // Fields of a struct from another crate.
+        test(
+            r#"pub fn norm(value: &crate::foreign::Complex) -> f64 {
+    let re = value.re * value.re;
+    let im = value.im * value.im;
+    (re + im).sqrt()
+}
+"#,
+            r#"pub fn norm(a: &crate::foreign::Complex) -> f64 {
+    let b = a.re * a.re;
+    let c = a.im * a.im;
+    (b + c).sqrt()
+}
+"#,
+            "",
+        )?;
+
+        // Items defined by macros, and generic parameters used in macros.
+        test(
+            r#"macro_rules! define_getter {
+    ($name:ident, $value:expr) => {
+        pub fn $name() -> i64 {
+            $value
+        }
+    };
+}
+
+macro_rules! zero {
+    () => {
+        Element::default()
+    };
+}
+
+define_getter!(answer, 42);
+
+pub fn twice() -> i64 {
+    let answer = answer();
+    let multiplier = 2;
+    answer * multiplier
+}
+
+pub fn zeros<Element: Default>(len: usize) -> Vec<Element> {
+    (0..len).map(|_| zero!()).collect()
+}
+"#,
+            r#"macro_rules! define_getter {
+    ($name:ident, $value:expr) => {
+        pub fn $name() -> i64 {
+            $value
+        }
+    };
+}
+
+macro_rules! zero {
+    () => {
+        Element::default()
+    };
+}
+
+define_getter!(answer, 42);
+
+pub fn twice() -> i64 {
+    let answer = answer();
+    let a = 2;
+    answer * a
+}
+
+pub fn zeros<Element: Default>(a: usize) -> Vec<Element> {
+    (0..a).map(|_| zero!()).collect()
+}
+"#,
+            "pub fn bin() -> i64 {\n    lib::answer() + lib::twice() + lib::zeros::<i64>(1)[0]\n}\n",
+        )
+    }
 }
@@ -1,12 +1,38 @@
+use crate::shell::Shell;
+use anyhow::bail;
 use camino::Utf8Path;
 use cargo_util::ProcessBuilder;
-use std::env;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+use syn::spanned::Spanned as _;
 
+/// Formats the code with `rustfmt`, or with the built-in formatter if `rustfmt` is unavailable.
 pub(crate) fn rustfmt(
     workspace_root: &Utf8Path,
     code: &str,
     edition: &str,
+    config_path: Option<&Path>,
+    shell: &mut Shell,
 ) -> anyhow::Result<String> {
+    let rustfmt_exe = rustfmt_exe()?;
+
+    if !is_available(&rustfmt_exe) {
+        if config_path.is_some() {
+            bail!(
+                "`--rustfmt-config` requires `{}`, which is unavailable",
+                rustfmt_exe.display(),
+            );
+        }
+        shell.warn(format!(
+            "`{}` is unavailable. formatting with the built-in formatter, which removes non-doc \
+             comments",
+            rustfmt_exe.display(),
+        ))?;
+        return unparse(code);
+    }
+
     let tempfile = tempfile::Builder::new()
         .prefix("cargo-equip-")
         .suffix(".rs")
@@ -15,15 +41,12 @@ pub(crate) fn rustfmt(
 
     cargo_util::paths::write(&tempfile, code)?;
 
-    let rustfmt_exe = crate::process::cargo_exe()?
-        .with_file_name("rustfmt")
-        .with_extension(env::consts::EXE_EXTENSION);
-
-    ProcessBuilder::new(rustfmt_exe)
-        .args(&["--edition", edition])
-        .arg(&tempfile)
-        .cwd(workspace_root)
-        .exec()?;
+    let mut rustfmt = ProcessBuilder::new(rustfmt_exe);
+    rustfmt.args(&["--edition", edition]);
+    if let Some(config_path) = config_path {
+        rustfmt.arg("--config-path").arg(config_path);
+    }
+    rustfmt.arg(&tempfile).cwd(workspace_root).exec()?;
 
     let formatted = cargo_util::paths::read(&tempfile)?;
 
@@ -31,3 +54,130 @@ pub(crate) fn rustfmt(
 
     Ok(formatted)
 }
+
+/// Returns the path to `rustfmt` in the same toolchain as `$CARGO`.
+pub(crate) fn rustfmt_exe() -> anyhow::Result<PathBuf> {
+    Ok(crate::process::cargo_exe()?
+        .with_file_name("rustfmt")
+        .with_extension(env::consts::EXE_EXTENSION))
+}
+
+pub(crate) fn is_available(rustfmt_exe: &Path) -> bool {
+    rustfmt_exe.exists()
+        && ProcessBuilder::new(rustfmt_exe)
+            .arg("--version")
+            .exec_with_output()
+            .is_ok()
+}
+
+/// Pretty-prints the code, keeping items with `#[cfg_attr(any(), rustfmt::skip)]` as they are.
+fn unparse(code: &str) -> anyhow::Result<String> {
+    let syn::File {
+        shebang,
+        attrs,
+        items,
+    } = crate::rust::parse_file(code)?;
+
+    let mut acc = shebang.map(|s| s + "\n").unwrap_or_default();
+    let mut file = syn::File {
+        shebang: None,
+        attrs,
+        items: vec![],
+    };
+
+    for item in items {
+        let skip = item_attrs(&item).iter().any(|attr| {
+            let tokens = attr.tokens.to_string().replace(' ', "");
+            tokens.contains("rustfmt::skip")
+        });
+        if skip {
+            if let Some(source_text) = item.span().source_text() {
+                if !(file.attrs.is_empty() && file.items.is_empty()) {
+                    acc += &prettyplease::unparse(&file);
+                    acc += "\n";
+                    file.attrs.clear();
+                    file.items.clear();
+                }
+                acc += &source_text;
+                acc += "\n";
+                continue;
+            }
+        }
+        file.items.push(item);
+    }
+    if !(file.attrs.is_empty() && file.items.is_empty()) {
+        acc += &prettyplease::unparse(&file);
+    }
+    return Ok(acc);
+
+    fn item_attrs(item: &syn::Item) -> &[syn::Attribute] {
+        match item {
+            syn::Item::Const(syn::ItemConst { attrs, .. })
+            | syn::Item::Enum(syn::ItemEnum { attrs, .. })
+            | syn::Item::ExternCrate(syn::ItemExternCrate { attrs, .. })
+            | syn::Item::Fn(syn::ItemFn { attrs, .. })
+            | syn::Item::ForeignMod(syn::ItemForeignMod { attrs, .. })
+            | syn::Item::Impl(syn::ItemImpl { attrs, .. })
+            | syn::Item::Macro(syn::ItemMacro { attrs, .. })
+            | syn::Item::Macro2(syn::ItemMacro2 { attrs, .. })
+            | syn::Item::Mod(syn::ItemMod { attrs, .. })
+            | syn::Item::Static(syn::ItemStatic { attrs, .. })
+            | syn::Item::Struct(syn::ItemStruct { attrs, .. })
+            | syn::Item::Trait(syn::ItemTrait { attrs, .. })
+            | syn::Item::TraitAlias(syn::ItemTraitAlias { attrs, .. })
+            | syn::Item::Type(syn::ItemType { attrs, .. })
+            | syn::Item::Union(syn::ItemUnion { attrs, .. })
+            | syn::Item::Use(syn::ItemUse { attrs, .. }) => attrs,
+            _ => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shell::Shell;
+    use camino::Utf8Path;
+    use pretty_assertions::assert_eq;
+    use std::io;
+
+    #[test]
+    fn rustfmt_config() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let config = &tempdir.path().join("rustfmt.toml");
+        cargo_util::paths::write(config, "hard_tabs = true\n")?;
+        let rustfmt = |config_path| {
+            super::rustfmt(
+                Utf8Path::from_path(tempdir.path()).unwrap(),
+                "fn main(){let x=1;}\n",
+                "2018",
+                config_path,
+                &mut Shell::from_stdout(Box::new(io::sink())),
+            )
+        };
+
+        assert_eq!("fn main() {\n    let x = 1;\n}\n", rustfmt(None)?);
+        assert_eq!("fn main() {\n\tlet x = 1;\n}\n", rustfmt(Some(config))?);
+        Ok(())
+    }
+
+    #[test]
+    fn unparse() -> anyhow::Result<()> {
+        let code = "#!/usr/bin/env rust\n\
+                    fn main(){let x=1;// comment\nprintln!(\"{}\",x);}\n\
+                    #[cfg_attr(any(), rustfmt::skip)]\n\
+                    mod lib{pub fn f(){}}\n\
+                    struct S{a:u32}\n";
+        assert_eq!(
+            "#!/usr/bin/env rust\n\
+             fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n\
+             \n\
+             #[cfg_attr(any(), rustfmt::skip)]\n\
+             mod lib{pub fn f(){}}\n\
+             struct S {\n    a: u32,\n}\n",
+            super::unparse(code)?,
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{session::Session, AfterBundling, Context, Opt, OptBundle, OptEquip};
+use anyhow::{bail, Context as _};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    ffi::OsString,
+    io::{BufRead, Write},
+    iter,
+    path::PathBuf,
+};
+use structopt::StructOpt as _;
+
+pub(crate) fn run(mut stdin: impl BufRead, ctx: Context<'_>) -> anyhow::Result<()> {
+    let Context {
+        cwd,
+        cache_dir,
+        shell,
+    } = ctx;
+
+    let mut session = Session::new(&cache_dir);
+    let mut shutdown = false;
+
+    shell.status("Listening", "on STDIN")?;
+
+    while let Some(message) = read_message(&mut stdin)? {
+        let request = match serde_json::from_slice::<Request>(&message) {
+            Ok(request) => request,
+            Err(err) => {
+                let response = error_response(&json!(null), PARSE_ERROR, &err.to_string());
+                write_message(shell.out(), &response)?;
+                continue;
+            }
+        };
+
+        let result = match &*request.method {
+            "bundle" if !shutdown => (|| {
+                let BundleParams {
+                    src,
+                    args,
+                    cwd: cwd_param,
+                } = serde_json::from_value(request.params.clone())
+                    .map_err(|e| Error(INVALID_PARAMS, e.to_string()))?;
+
+                let cwd = cwd.join(cwd_param.unwrap_or_default());
+
+                let opt = parse_args(&src, &args).map_err(|e| Error(INVALID_PARAMS, e))?;
+                let output = opt.output.clone();
+
+                let code = crate::equip(
+                    opt,
+                    AfterBundling::Check,
+                    &cwd,
+                    &cache_dir,
+                    &mut session,
+                    shell,
+                )
+                .and_then(|(code, _)| {
+                    if let Some(output) = output {
+                        cargo_util::paths::write(cwd.join(output), &code)?;
+                    }
+                    Ok(code)
+                })
+                .map_err(|e| Error(BUNDLE_FAILED, format!("{:?}", e)))?;
+
+                Ok(json!({ "code": code }))
+            })(),
+            "shutdown" => {
+                shutdown = true;
+                Ok(json!(null))
+            }
+            "exit" => return Ok(()),
+            "bundle" => Err(Error(
+                INVALID_REQUEST,
+                "the server is shutting down".to_owned(),
+            )),
+            method => Err(Error(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {:?}", method),
+            )),
+        };
+
+        if let Err(Error(_, message)) = &result {
+            shell.error(message.lines().next().unwrap_or_default())?;
+        }
+
+        if let Some(id) = &request.id {
+            let response = match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err(Error(code, message)) => error_response(id, code, &message),
+            };
+            write_message(shell.out(), &response)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_args(src: &str, args: &[String]) -> Result<OptBundle, String> {
+    let args = iter::once("")
+        .chain(iter::once("equip"))
+        .chain(args.iter().map(|s| &**s))
+        .map(OsString::from)
+        .chain([OsString::from("--src"), OsString::from(src)]);
+
+    let Opt::Equip(OptEquip { bundle, subcommand }) = Opt::from_iter_safe(args)
+        .map_err(|e| e.message.trim_start_matches("error: ").to_owned())?;
+
+    if subcommand.is_some() {
+        return Err("subcommands are not allowed in `args`".to_owned());
+    }
+    Ok(bundle)
+}
+
+fn read_message(mut rdr: impl BufRead) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut content_length = None;
+
+    loop {
+        let mut line = "".to_owned();
+        if rdr.read_line(&mut line)? == 0 {
+            return if content_length.is_none() {
+                Ok(None)
+            } else {
+                bail!("unexpected EOF")
+            };
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if line.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = Some(
+                    value
+                        .trim()
+                        .parse::<usize>()
+                        .with_context(|| format!("invalid `Content-Length`: {:?}", value))?,
+                );
+            }
+        } else {
+            bail!("invalid header: {:?}", line);
+        }
+    }
+
+    let mut content = vec![0; content_length.expect("should be set")];
+    rdr.read_exact(&mut content)?;
+    Ok(Some(content))
+}
+
+fn write_message(mut wtr: impl Write, message: &serde_json::Value) -> anyhow::Result<()> {
+    let message = message.to_string();
+    write!(wtr, "Content-Length: {}\r\n\r\n{}", message.len(), message)?;
+    wtr.flush()?;
+    Ok(())
+}
+
+fn error_response(id: &serde_json::Value, code: i64, message: &str) -> serde_json::Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const BUNDLE_FAILED: i64 = 1;
+
+struct Error(i64, String);
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct BundleParams {
+    src: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    cwd: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn read_message() -> anyhow::Result<()> {
+        let mut rdr =
+            &b"Content-Length: 2\r\n\r\n{}content-length:7\r\nContent-Type: x\r\n\r\n[1,2,3]"[..];
+        assert_eq!(Some(b"{}".to_vec()), super::read_message(&mut rdr)?);
+        assert_eq!(Some(b"[1,2,3]".to_vec()), super::read_message(&mut rdr)?);
+        assert_eq!(None, super::read_message(&mut rdr)?);
+
+        assert!(super::read_message(&b"Content-Length: 3\r\n\r\n{}"[..]).is_err());
+        assert!(super::read_message(&b"Content-Length: 2\r\n"[..]).is_err());
+        assert!(super::read_message(&b"Content-Length: x\r\n\r\n"[..]).is_err());
+        assert!(super::read_message(&b"invalid\r\n\r\n"[..]).is_err());
+        Ok(())
+    }
+}
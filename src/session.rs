@@ -0,0 +1,136 @@
+use crate::{processed_libs::ProcessedLibs, ra_proc_macro, toolchain, workspace};
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata as cm;
+use ra_ap_paths::AbsPathBuf;
+use ra_ap_proc_macro_api::ProcMacroServer;
+use std::{
+    collections::{hash_map, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
+
+/// Results which are expensive to compute and can be reused between bundlings.
+///
+/// `cargo equip` creates one for each run, and `cargo equip server` keeps one alive.
+pub(crate) struct Session {
+    metadata: HashMap<(PathBuf, bool), (Fingerprint, Rc<cm::Metadata>)>,
+    active_toolchains: HashMap<Utf8PathBuf, String>,
+    cfgs: HashMap<Utf8PathBuf, Rc<[cfg_expr::Expression]>>,
+    proc_macro_srv_exes: HashMap<(Utf8PathBuf, String), AbsPathBuf>,
+    proc_macro_srvs: HashMap<AbsPathBuf, ProcMacroServer>,
+    pub(crate) processed_libs: ProcessedLibs,
+}
+
+impl Session {
+    pub(crate) fn new(cache_dir: &Path) -> Self {
+        Self {
+            metadata: HashMap::new(),
+            active_toolchains: HashMap::new(),
+            cfgs: HashMap::new(),
+            proc_macro_srv_exes: HashMap::new(),
+            proc_macro_srvs: HashMap::new(),
+            processed_libs: ProcessedLibs::new(cache_dir),
+        }
+    }
+
+    pub(crate) fn metadata(
+        &mut self,
+        manifest_path: &Path,
+        cwd: &Path,
+        offline: bool,
+    ) -> anyhow::Result<Rc<cm::Metadata>> {
+        // Metadata retrieved with `--offline` may differ, so it is kept separately.
+        let key = (manifest_path.to_owned(), offline);
+        if let Some((fingerprint, metadata)) = self.metadata.get(&key) {
+            if fingerprint.is_fresh() {
+                return Ok(metadata.clone());
+            }
+        }
+        let metadata = Rc::new(workspace::cargo_metadata(manifest_path, cwd, offline)?);
+        let fingerprint = Fingerprint::new(manifest_path, &metadata);
+        self.metadata.insert(key, (fingerprint, metadata.clone()));
+        Ok(metadata)
+    }
+
+    pub(crate) fn active_toolchain(&mut self, manifest_dir: &Utf8Path) -> anyhow::Result<String> {
+        match self.active_toolchains.entry(manifest_dir.to_owned()) {
+            hash_map::Entry::Occupied(entry) => Ok(entry.get().clone()),
+            hash_map::Entry::Vacant(entry) => Ok(entry
+                .insert(toolchain::active_toolchain(manifest_dir)?)
+                .clone()),
+        }
+    }
+
+    pub(crate) fn cfgs(
+        &mut self,
+        manifest_dir: &Utf8Path,
+    ) -> anyhow::Result<Rc<[cfg_expr::Expression]>> {
+        match self.cfgs.entry(manifest_dir.to_owned()) {
+            hash_map::Entry::Occupied(entry) => Ok(entry.get().clone()),
+            hash_map::Entry::Vacant(entry) => Ok(entry
+                .insert(workspace::rustc_print_cfg(manifest_dir)?.into())
+                .clone()),
+        }
+    }
+
+    pub(crate) fn proc_macro_srv(
+        &mut self,
+        manifest_dir: &Utf8Path,
+        toolchain: &str,
+    ) -> anyhow::Result<&ProcMacroServer> {
+        let exe = match self
+            .proc_macro_srv_exes
+            .entry((manifest_dir.to_owned(), toolchain.to_owned()))
+        {
+            hash_map::Entry::Occupied(entry) => entry.get().clone(),
+            hash_map::Entry::Vacant(entry) => entry
+                .insert(toolchain::find_rust_analyzer_proc_macro_srv(
+                    manifest_dir,
+                    toolchain,
+                )?)
+                .clone(),
+        };
+        match self.proc_macro_srvs.entry(exe) {
+            hash_map::Entry::Occupied(entry) => Ok(entry.into_mut()),
+            hash_map::Entry::Vacant(entry) => {
+                let server = ra_proc_macro::spawn_server(entry.key())?;
+                Ok(entry.insert(server))
+            }
+        }
+    }
+}
+
+/// Modification times of the manifests and the lockfile.
+struct Fingerprint(Vec<(PathBuf, Option<SystemTime>)>);
+
+impl Fingerprint {
+    fn new(manifest_path: &Path, metadata: &cm::Metadata) -> Self {
+        let mut paths = vec![
+            manifest_path.to_owned(),
+            metadata.workspace_root.join("Cargo.toml").into(),
+            metadata.workspace_root.join("Cargo.lock").into(),
+        ];
+        for id in &metadata.workspace_members {
+            paths.push(metadata[id].manifest_path.clone().into());
+        }
+        Self(
+            paths
+                .into_iter()
+                .map(|path| {
+                    let mtime = mtime(&path);
+                    (path, mtime)
+                })
+                .collect(),
+        )
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.0.iter().all(|(path, t)| mtime(path) == *t)
+    }
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
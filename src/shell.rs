@@ -1,8 +1,9 @@
 use std::{
     fmt,
-    io::{self, Sink, Write},
+    io::{self, Write},
 };
-use termcolor::{Color, ColorSpec, NoColor, StandardStream, WriteColor};
+pub(crate) use termcolor::Color;
+use termcolor::{ColorSpec, NoColor, StandardStream, WriteColor};
 
 pub struct Shell {
     output: ShellOut,
@@ -43,6 +44,15 @@ impl Shell {
         self.print(status, message, Color::Green, true)
     }
 
+    pub(crate) fn status_with_color(
+        &mut self,
+        status: impl fmt::Display,
+        message: impl fmt::Display,
+        color: Color,
+    ) -> io::Result<()> {
+        self.print(status, message, color, true)
+    }
+
     pub(crate) fn warn(&mut self, message: impl fmt::Display) -> io::Result<()> {
         self.print("warning", message, Color::Yellow, false)
     }
@@ -60,8 +70,8 @@ impl Shell {
     ) -> io::Result<()> {
         return match &mut self.output {
             ShellOut::Stream { stderr, .. } => print(stderr, status, message, color, justified),
-            ShellOut::Write { .. } => {
-                print(NoColor::new(io::sink()), status, message, color, justified)
+            ShellOut::Write { stderr, .. } => {
+                print(NoColor::new(stderr), status, message, color, justified)
             }
         };
 
@@ -99,7 +109,7 @@ enum ShellOut {
     },
     Write {
         stdout: Box<dyn Write>,
-        stderr: Sink,
+        stderr: Box<dyn Write>,
     },
 }
 
@@ -122,7 +132,44 @@ impl ShellOut {
     fn write(stdout: Box<dyn Write>) -> Self {
         Self::Write {
             stdout,
-            stderr: io::sink(),
+            stderr: Box::new(io::sink()),
         }
     }
 }
+
+/// A `Write` for a `Shell` whose content can be read afterwards.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct Captured(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Captured {
+    pub(crate) fn shell(&self) -> Shell {
+        Shell::from_stdout(Box::new(self.clone()))
+    }
+
+    /// Returns a `Shell` that writes the status lines and the warnings here, and discards STDOUT.
+    pub(crate) fn stderr_shell(&self) -> Shell {
+        Shell {
+            output: ShellOut::Write {
+                stdout: Box::new(io::sink()),
+                stderr: Box::new(self.clone()),
+            },
+        }
+    }
+
+    pub(crate) fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+#[cfg(test)]
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
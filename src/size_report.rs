@@ -0,0 +1,303 @@
+use crate::{shell::Shell, SizeReportFormat};
+use prettytable::{format::FormatBuilder, row, Table};
+use proc_macro2::LineColumn;
+use quote::ToTokens;
+use serde::Serialize;
+use std::iter;
+use syn::{spanned::Spanned, Item, ItemMod};
+
+/// Where the bytes of the output come from.
+#[derive(Serialize)]
+pub(crate) struct SizeReport {
+    total: usize,
+    user_code: usize,
+    license_notices: usize,
+    /// Module declarations and re-exports generated by cargo-equip.
+    overhead: usize,
+    crates: Vec<Part>,
+    macros: Vec<Part>,
+    preludes: Vec<Part>,
+}
+
+#[derive(Serialize)]
+struct Part {
+    name: String,
+    size: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    items: Vec<Part>,
+}
+
+impl SizeReport {
+    pub(crate) fn new(code: &str, cargo_equip_mod_name: &syn::Ident) -> anyhow::Result<Self> {
+        let file = crate::rust::parse_file(code)?;
+        let offsets = Offsets::new(code);
+
+        let mut report = Self {
+            total: code.len(),
+            user_code: code.len(),
+            license_notices: 0,
+            overhead: 0,
+            crates: vec![],
+            macros: vec![],
+            preludes: vec![],
+        };
+
+        let cargo_equip_mod = file.items.iter().find_map(|item| match item {
+            Item::Mod(item) if item.ident == *cargo_equip_mod_name => Some(item),
+            _ => None,
+        });
+
+        if let Some(cargo_equip_mod) = cargo_equip_mod {
+            let mod_size = offsets.size(cargo_equip_mod);
+            report.user_code -= mod_size;
+            report.license_notices = cargo_equip_mod
+                .attrs
+                .iter()
+                .filter(|a| a.path.is_ident("doc"))
+                .map(|a| offsets.size(a))
+                .sum();
+            report.overhead = mod_size - report.license_notices;
+
+            for (name, parts) in [
+                ("crates", &mut report.crates),
+                ("macros", &mut report.macros),
+                ("preludes", &mut report.preludes),
+            ] {
+                for pseudo_crate in inline_mods(cargo_equip_mod)
+                    .filter(|m| m.ident == name)
+                    .flat_map(inline_mods)
+                {
+                    let size = offsets.size(pseudo_crate);
+                    report.overhead -= size;
+                    parts.push(Part {
+                        name: format!("{}::{}", name, pseudo_crate.ident),
+                        size,
+                        items: sorted(
+                            pseudo_crate
+                                .content
+                                .iter()
+                                .flat_map(|(_, items)| items)
+                                .map(|item| Part {
+                                    name: describe(item),
+                                    size: offsets.size(item),
+                                    items: vec![],
+                                })
+                                .collect(),
+                        ),
+                    });
+                }
+                *parts = sorted(std::mem::take(parts));
+            }
+        }
+
+        Ok(report)
+    }
+
+    pub(crate) fn print(&self, format: SizeReportFormat, shell: &mut Shell) -> anyhow::Result<()> {
+        match format {
+            SizeReportFormat::Table => {
+                let mut table = Table::new();
+                *table.get_format() = FormatBuilder::new().column_separator(' ').build();
+                table.set_titles(row!["PART", r->"BYTES", r->"%"]);
+
+                let percentage =
+                    |size: usize| format!("{:.1}", 100.0 * size as f64 / self.total.max(1) as f64);
+
+                let mut add_row = |name: &str, size: usize| {
+                    table.add_row(row![name, r->size, r->percentage(size)]);
+                };
+
+                add_row("(total)", self.total);
+                add_row("(user code)", self.user_code);
+                add_row("(license notices)", self.license_notices);
+                add_row("(overhead)", self.overhead);
+                for part in self.crates.iter().chain(&self.macros).chain(&self.preludes) {
+                    add_row(&part.name, part.size);
+                    for item in &part.items {
+                        add_row(&format!("    {}", item.name), item.size);
+                    }
+                }
+
+                for line in table.to_string().lines() {
+                    writeln!(shell.err(), "{}", line.trim_end())?;
+                }
+            }
+            SizeReportFormat::Json => {
+                writeln!(shell.err(), "{}", serde_json::to_string(self)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn inline_mods(item_mod: &ItemMod) -> impl Iterator<Item = &ItemMod> {
+    item_mod
+        .content
+        .iter()
+        .flat_map(|(_, items)| items)
+        .flat_map(|item| match item {
+            Item::Mod(item) => Some(item),
+            _ => None,
+        })
+}
+
+fn sorted(mut parts: Vec<Part>) -> Vec<Part> {
+    parts.sort_by(|p1, p2| p2.size.cmp(&p1.size).then_with(|| p1.name.cmp(&p2.name)));
+    parts
+}
+
+fn describe(item: &Item) -> String {
+    let tokens = |t: &dyn ToTokens| {
+        t.to_token_stream()
+            .to_string()
+            .replace(" :: ", "::")
+            .replace(":: ", "::")
+            .replace(" < ", "<")
+            .replace(" >", ">")
+            .replace(" ,", ",")
+    };
+    match item {
+        Item::Const(item) => format!("const {}", item.ident),
+        Item::Enum(item) => format!("enum {}", item.ident),
+        Item::ExternCrate(item) => format!("extern crate {}", item.ident),
+        Item::Fn(item) => format!("fn {}", item.sig.ident),
+        Item::ForeignMod(_) => "extern { .. }".to_owned(),
+        Item::Impl(item) => match &item.trait_ {
+            Some((_, path, _)) => format!("impl {} for {}", tokens(path), tokens(&item.self_ty)),
+            None => format!("impl {}", tokens(&item.self_ty)),
+        },
+        Item::Macro(item) => match &item.ident {
+            Some(ident) => format!("macro_rules! {}", ident),
+            None => format!("{}!", tokens(&item.mac.path)),
+        },
+        Item::Macro2(item) => format!("macro {}", item.ident),
+        Item::Mod(item) => format!("mod {}", item.ident),
+        Item::Static(item) => format!("static {}", item.ident),
+        Item::Struct(item) => format!("struct {}", item.ident),
+        Item::Trait(item) => format!("trait {}", item.ident),
+        Item::TraitAlias(item) => format!("trait {}", item.ident),
+        Item::Type(item) => format!("type {}", item.ident),
+        Item::Union(item) => format!("union {}", item.ident),
+        Item::Use(item) => format!("use {}", tokens(&item.tree)),
+        _ => "..".to_owned(),
+    }
+}
+
+/// Converts `LineColumn`s, which count `char`s, into byte offsets.
+struct Offsets<'a> {
+    code: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> Offsets<'a> {
+    fn new(code: &'a str) -> Self {
+        let line_starts = iter::once(0)
+            .chain(code.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { code, line_starts }
+    }
+
+    fn offset(&self, LineColumn { line, column }: LineColumn) -> usize {
+        let start = self.line_starts[line - 1];
+        self.code[start..]
+            .char_indices()
+            .nth(column)
+            .map_or(self.code.len(), |(i, _)| start + i)
+    }
+
+    fn size(&self, node: &impl Spanned) -> usize {
+        let span = node.span();
+        self.offset(span.end()) - self.offset(span.start())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Offsets, SizeReport};
+    use pretty_assertions::assert_eq;
+    use proc_macro2::LineColumn;
+
+    #[test]
+    fn new() -> anyhow::Result<()> {
+        let user_code = "fn main() {\n    println!(\"ça va\");\n}\n\n";
+        let notices = "/// # License and Copyright Notices\n/// ©\n";
+        let crate_a = "pub mod a { pub const S: &str = \"あいう\"; pub fn f() {} }";
+        let crate_b = "pub mod b { pub fn g() {} }";
+        let macros_a = "pub mod a { pub use crate::f; }";
+        let preludes_a = "pub mod a { pub use crate::__cargo_equip::crates::a; }";
+        let code = format!(
+            "{}{}pub mod __cargo_equip {{\n    pub mod crates {{\n        {}\n        {}\n    }}\n    \
+             pub(crate) mod macros {{\n        {}\n    }}\n    mod preludes {{\n        {}\n    }}\n}}\n",
+            user_code, notices, crate_a, crate_b, macros_a, preludes_a,
+        );
+
+        let report = SizeReport::new(
+            &code,
+            &syn::Ident::new("__cargo_equip", proc_macro2::Span::call_site()),
+        )?;
+        let sizes = |parts: &[super::Part]| {
+            parts
+                .iter()
+                .map(|p| (p.name.clone(), p.size))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(code.len(), report.total);
+        // The last newline is outside `mod __cargo_equip`.
+        assert_eq!(user_code.len() + 1, report.user_code);
+        assert_eq!(notices.len() - 2, report.license_notices);
+        assert_eq!(
+            vec![
+                ("crates::a".to_owned(), crate_a.len()),
+                ("crates::b".to_owned(), crate_b.len()),
+            ],
+            sizes(&report.crates),
+        );
+        assert_eq!(
+            vec![("macros::a".to_owned(), macros_a.len())],
+            sizes(&report.macros),
+        );
+        assert_eq!(
+            vec![("preludes::a".to_owned(), preludes_a.len())],
+            sizes(&report.preludes),
+        );
+        assert_eq!(
+            vec![
+                (
+                    "const S".to_owned(),
+                    "pub const S: &str = \"あいう\";".len()
+                ),
+                ("fn f".to_owned(), "pub fn f() {}".len()),
+            ],
+            sizes(&report.crates[0].items),
+        );
+        assert_eq!(
+            report.total,
+            report.user_code
+                + report.license_notices
+                + report.overhead
+                + [&report.crates, &report.macros, &report.preludes]
+                    .iter()
+                    .flat_map(|parts| parts.iter())
+                    .map(|p| p.size)
+                    .sum::<usize>(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn offsets() {
+        let code = "aé\nあいb\n";
+        let offsets = Offsets::new(code);
+        let offset = |line, column| offsets.offset(LineColumn { line, column });
+
+        assert_eq!(0, offset(1, 0));
+        assert_eq!(1, offset(1, 1));
+        assert_eq!(3, offset(1, 2));
+        assert_eq!(4, offset(2, 0));
+        assert_eq!(7, offset(2, 1));
+        assert_eq!(10, offset(2, 2));
+        assert_eq!(11, offset(2, 3));
+        assert_eq!(code.len(), offset(3, 0));
+    }
+}
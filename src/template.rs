@@ -0,0 +1,134 @@
+use anyhow::{bail, Context as _};
+use itertools::Itertools as _;
+use std::path::{Path, PathBuf};
+
+static PLACEHOLDERS: &[&str] = &[
+    "root_target",
+    "git_sha",
+    "bundled_crates",
+    "toolchain",
+    "date",
+];
+
+/// A template for `--header` or `--footer`.
+///
+/// `{{git_sha}}` is `HEAD` of the Git repository that contains the workspace root, not of each
+/// library.
+///
+/// ```text
+/// // {{root_target}} ({{git_sha}})
+/// // bundled {{bundled_crates}} with {{toolchain}} at {{date}}
+/// ```
+#[derive(Debug)]
+pub(crate) struct Template {
+    path: PathBuf,
+    content: String,
+}
+
+impl Template {
+    pub(crate) fn read(path: &Path) -> anyhow::Result<Self> {
+        let content = cargo_util::paths::read(path)?;
+        let template = Self {
+            path: path.to_owned(),
+            content,
+        };
+        template.expand(|name| Ok(PLACEHOLDERS.contains(&name).then(String::new)))?;
+        Ok(template)
+    }
+
+    /// Replaces each `{{name}}` with `value(name)`. `value` is called only for the names in the
+    /// template, and returns `None` for unknown ones.
+    pub(crate) fn expand(
+        &self,
+        mut value: impl FnMut(&str) -> anyhow::Result<Option<String>>,
+    ) -> anyhow::Result<String> {
+        let mut expanded = "".to_owned();
+        let mut rest = &*self.content;
+        while let Some((before, after)) = rest.split_once("{{") {
+            let (name, after) = after
+                .split_once("}}")
+                .with_context(|| format!("unclosed `{{{{` in `{}`", self.path.display()))?;
+            let name = name.trim();
+            let value =
+                value(name).with_context(|| format!("could not expand `{{{{{}}}}}`", name))?;
+            let Some(value) = value else {
+                bail!(
+                    "unknown placeholder `{{{{{}}}}}` in `{}`. available ones are {}",
+                    name,
+                    self.path.display(),
+                    PLACEHOLDERS
+                        .iter()
+                        .map(|p| format!("`{{{{{}}}}}`", p))
+                        .format(", "),
+                );
+            };
+            expanded += before;
+            expanded += &value;
+            rest = after;
+        }
+        expanded += rest;
+        if !expanded.ends_with('\n') {
+            expanded += "\n";
+        }
+        Ok(expanded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Template;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    fn template(content: &str) -> Template {
+        Template {
+            path: PathBuf::from("header.txt"),
+            content: content.to_owned(),
+        }
+    }
+
+    fn value(name: &str) -> anyhow::Result<Option<String>> {
+        Ok(match name {
+            "root_target" => Some("a".to_owned()),
+            "git_sha" => Some("0123abc".to_owned()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn expand() {
+        assert_eq!(
+            "// a (0123abc)\n",
+            template("// {{root_target}} ({{ git_sha }})")
+                .expand(value)
+                .unwrap(),
+        );
+        assert_eq!(
+            "// a\n// a\n",
+            template("// {{root_target}}\n// {{root_target}}\n")
+                .expand(value)
+                .unwrap(),
+        );
+        assert_eq!("// }}\n", template("// }}").expand(value).unwrap());
+        assert_eq!("\n", template("").expand(value).unwrap());
+    }
+
+    #[test]
+    fn expand_errors() {
+        let err = |content: &str| template(content).expand(value).unwrap_err().to_string();
+
+        assert_eq!(
+            "unknown placeholder `{{date}}` in `header.txt`. available ones are `{{root_target}}`, \
+             `{{git_sha}}`, `{{bundled_crates}}`, `{{toolchain}}`, `{{date}}`",
+            err("// {{date}}"),
+        );
+        assert_eq!("unclosed `{{` in `header.txt`", err("// {{root_target"));
+        assert_eq!(
+            "could not expand `{{git_sha}}`",
+            template("{{git_sha}}")
+                .expand(|_| Err(anyhow::anyhow!("not a git repository")))
+                .unwrap_err()
+                .to_string(),
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use crate::shell::{Color, Shell};
+use anyhow::{bail, Context as _};
+use std::{
+    fs::File,
+    io::Read as _,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use wait_timeout::ChildExt as _;
+
+pub(crate) struct Comparison {
+    pub(crate) tolerance: Option<f64>,
+    pub(crate) strict_whitespace: bool,
+}
+
+/// Runs `exe` with every `*.in` in `dir` and compares the outputs with the `*.out` files.
+pub(crate) fn test(
+    exe: &Path,
+    dir: &Path,
+    time_limit: Option<Duration>,
+    comparison: &Comparison,
+    shell: &mut Shell,
+) -> anyhow::Result<()> {
+    let cases = list_cases(dir)?;
+    if cases.is_empty() {
+        bail!("no `*.in` files in `{}`", dir.display());
+    }
+
+    let mut failures = 0;
+
+    for (input, expected) in &cases {
+        let name = input
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+
+        let expected = expected
+            .as_ref()
+            .map(|path| cargo_util::paths::read(path))
+            .transpose()?;
+
+        let Run {
+            status,
+            stdout,
+            stderr,
+            elapsed,
+        } = run(exe, input, time_limit)?;
+
+        let elapsed_ms = elapsed.as_millis();
+
+        let failure = if status.is_none() {
+            Some(format!("time limit exceeded ({} ms)", elapsed_ms))
+        } else if let Some(status) = status.filter(|s| !s.success()) {
+            Some(format!("runtime error, {} ({} ms)", status, elapsed_ms))
+        } else if let Some(expected) = &expected {
+            let actual = String::from_utf8_lossy(&stdout);
+            compare(expected, &actual, comparison)
+                .map(|diff| format!("wrong answer, {} ({} ms)", diff, elapsed_ms))
+        } else {
+            None
+        };
+
+        if let Some(failure) = failure {
+            failures += 1;
+            shell.status_with_color("Failed", format!("{}: {}", name, failure), Color::Red)?;
+            let stderr = String::from_utf8_lossy(&stderr);
+            for line in stderr.lines().take(10) {
+                writeln!(shell.err(), "             | {}", line)?;
+            }
+        } else if expected.is_some() {
+            shell.status("Passed", format!("{} ({} ms)", name, elapsed_ms))?;
+        } else {
+            shell.status_with_color(
+                "Ran",
+                format!("{} ({} ms, no `{}.out`)", name, elapsed_ms, name),
+                Color::Yellow,
+            )?;
+        }
+    }
+
+    if failures > 0 {
+        bail!("{}/{} test(s) failed", failures, cases.len());
+    }
+    shell.status("Finished", format!("{} test(s) passed", cases.len()))?;
+    Ok(())
+}
+
+fn list_cases(dir: &Path) -> anyhow::Result<Vec<(PathBuf, Option<PathBuf>)>> {
+    let mut cases = vec![];
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("could not read `{}`", dir.display()))?
+    {
+        let input = entry?.path();
+        if input.is_file() && input.extension().is_some_and(|e| e == "in") {
+            let output = Some(input.with_extension("out")).filter(|p| p.exists());
+            cases.push((input, output));
+        }
+    }
+    cases.sort();
+    Ok(cases)
+}
+
+pub(crate) struct Run {
+    /// `None` if the time limit was exceeded.
+    pub(crate) status: Option<ExitStatus>,
+    pub(crate) stdout: Vec<u8>,
+    pub(crate) stderr: Vec<u8>,
+    pub(crate) elapsed: Duration,
+}
+
+/// Runs `exe` with `input` as STDIN, killing it when it exceeds `time_limit`.
+pub(crate) fn run(exe: &Path, input: &Path, time_limit: Option<Duration>) -> anyhow::Result<Run> {
+    let start = Instant::now();
+
+    let mut child = Command::new(exe)
+        .stdin(File::open(input)?)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("could not execute `{}`", exe.display()))?;
+
+    let read_to_end = |mut rdr: Box<dyn std::io::Read + Send>| {
+        thread::spawn(move || {
+            let mut buf = vec![];
+            rdr.read_to_end(&mut buf).map(|_| buf)
+        })
+    };
+    let stdout = read_to_end(Box::new(child.stdout.take().expect("piped")));
+    let stderr = read_to_end(Box::new(child.stderr.take().expect("piped")));
+
+    let status = if let Some(time_limit) = time_limit {
+        let status = child.wait_timeout(time_limit.saturating_sub(start.elapsed()))?;
+        if status.is_none() {
+            child.kill()?;
+            child.wait()?;
+        }
+        status
+    } else {
+        Some(child.wait()?)
+    };
+    let elapsed = start.elapsed();
+
+    let join = |handle: thread::JoinHandle<std::io::Result<Vec<u8>>>| {
+        handle
+            .join()
+            .unwrap_or_else(|p| std::panic::resume_unwind(p))
+    };
+
+    Ok(Run {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+        elapsed,
+    })
+}
+
+/// Returns a description of the first difference.
+fn compare(expected: &str, actual: &str, comparison: &Comparison) -> Option<String> {
+    let tokens = |s: &str| -> Vec<(usize, String)> {
+        if comparison.strict_whitespace {
+            s.split_inclusive('\n')
+                .enumerate()
+                .map(|(i, l)| (i + 1, l.to_owned()))
+                .collect()
+        } else {
+            s.lines()
+                .enumerate()
+                .flat_map(|(i, l)| l.split_whitespace().map(move |t| (i + 1, t.to_owned())))
+                .collect()
+        }
+    };
+    let (expected, actual) = (tokens(expected), tokens(actual));
+
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some((_, e)), Some((_, a))) if e == a || within_tolerance(e, a, comparison) => {}
+            (e, a) => {
+                let line = a.or(e).map_or(0, |&(l, _)| l);
+                let show = |t: Option<&(usize, String)>| {
+                    t.map_or_else(|| "<EOF>".to_owned(), |(_, t)| format!("{:?}", t))
+                };
+                return Some(format!(
+                    "expected {} but got {} at line {}",
+                    show(e),
+                    show(a),
+                    line,
+                ));
+            }
+        }
+    }
+    None
+}
+
+fn within_tolerance(expected: &str, actual: &str, comparison: &Comparison) -> bool {
+    match (
+        comparison.tolerance,
+        expected.parse::<f64>(),
+        actual.parse::<f64>(),
+    ) {
+        (Some(tolerance), Ok(expected), Ok(actual)) => {
+            let diff = (expected - actual).abs();
+            diff <= tolerance || diff <= tolerance * expected.abs()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Comparison;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn compare() {
+        let exact = &Comparison {
+            tolerance: None,
+            strict_whitespace: false,
+        };
+        let strict = &Comparison {
+            tolerance: None,
+            strict_whitespace: true,
+        };
+        let tolerant = &Comparison {
+            tolerance: Some(1e-6),
+            strict_whitespace: false,
+        };
+
+        assert_eq!(None, super::compare("1 2\n3\n", "1  2\n3", exact));
+        assert_eq!(None, super::compare("1 2\n", "1\n2\n", exact));
+        assert_eq!(
+            Some(r#"expected "3" but got "4" at line 2"#.to_owned()),
+            super::compare("1 2\n3\n", "1 2\n4\n", exact),
+        );
+        assert_eq!(
+            Some(r#"expected "2" but got <EOF> at line 1"#.to_owned()),
+            super::compare("1 2\n", "1\n", exact),
+        );
+
+        assert_eq!(None, super::compare("1 2\n", "1 2\n", strict));
+        assert_eq!(
+            Some(r#"expected "1 2\n" but got "1  2\n" at line 1"#.to_owned()),
+            super::compare("1 2\n", "1  2\n", strict),
+        );
+        assert_eq!(
+            Some(r#"expected "1\n" but got "1" at line 1"#.to_owned()),
+            super::compare("1\n", "1", strict),
+        );
+
+        assert_eq!(None, super::compare("0.5\n", "0.5000001\n", tolerant));
+        assert_eq!(None, super::compare("1e9\n", "1000000500\n", tolerant));
+        assert_eq!(
+            Some(r#"expected "0.5" but got "0.50001" at line 1"#.to_owned()),
+            super::compare("0.5\n", "0.50001\n", tolerant),
+        );
+        assert_eq!(
+            Some(r#"expected "0.5" but got "0.5000001" at line 1"#.to_owned()),
+            super::compare("0.5\n", "0.5000001\n", exact),
+        );
+        assert_eq!(
+            Some(r#"expected "abc" but got "abd" at line 1"#.to_owned()),
+            super::compare("abc\n", "abd\n", tolerant),
+        );
+    }
+}
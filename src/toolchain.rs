@@ -28,9 +28,28 @@ pub(crate) fn find_rust_analyzer_proc_macro_srv(
 ) -> anyhow::Result<AbsPathBuf> {
     use crate::ra_proc_macro::MSRV;
 
-    let rustup_exe = &rustup_exe(manifest_dir)?;
+    let version = rustc_version(manifest_dir, toolchain)?;
 
-    let version = ProcessBuilder::new(rustup_exe)
+    ensure!(
+        version >= MSRV,
+        "Rust ≧{MSRV} is required for expanding procedural macros. Specify one with \
+         `--toolchain-for-proc-macro-srv`",
+    );
+
+    let rust_analyzer_proc_macro_srv = rust_analyzer_proc_macro_srv_path(manifest_dir, toolchain)?;
+
+    if !Path::new(rust_analyzer_proc_macro_srv.as_os_str()).try_exists()? {
+        anyhow::bail!(
+            "{} does not exist. Run `rustup component add rust-analyzer --toolchain {toolchain}`",
+            Path::new(rust_analyzer_proc_macro_srv.as_os_str()).display(),
+        );
+    }
+
+    Ok(rust_analyzer_proc_macro_srv)
+}
+
+pub(crate) fn rustc_version(manifest_dir: &Utf8Path, toolchain: &str) -> anyhow::Result<Version> {
+    ProcessBuilder::new(rustup_exe(manifest_dir)?)
         .args(&["run", toolchain, "rustc", "-V"])
         .cwd(manifest_dir)
         .read_stdout::<String>()?
@@ -40,15 +59,15 @@ pub(crate) fn find_rust_analyzer_proc_macro_srv(
                 .nth(1)
                 .and_then(|output| output.parse::<Version>().ok())
                 .ok_or_else(|| anyhow!("Could not parse {output:?}"))
-        })?;
-
-    ensure!(
-        version >= MSRV,
-        "Rust ≧{MSRV} is required for expanding procedural macros. Specify one with \
-         `--toolchain-for-proc-macro-srv`",
-    );
+        })
+}
 
-    let rust_analyzer_proc_macro_srv = ProcessBuilder::new(rustup_exe)
+/// Returns the path where `rust-analyzer-proc-macro-srv` should be, whether it exists or not.
+pub(crate) fn rust_analyzer_proc_macro_srv_path(
+    manifest_dir: &Utf8Path,
+    toolchain: &str,
+) -> anyhow::Result<AbsPathBuf> {
+    Ok(ProcessBuilder::new(rustup_exe(manifest_dir)?)
         .args(&["run", toolchain, "rustc", "--print", "sysroot"])
         .cwd(manifest_dir)
         .read_stdout::<String>()?
@@ -57,14 +76,5 @@ pub(crate) fn find_rust_analyzer_proc_macro_srv(
         .join("libexec")
         .join("rust-analyzer-proc-macro-srv")
         .with_extension(env::consts::EXE_EXTENSION)
-        .pipe(AbsPathBuf::assert);
-
-    if !Path::new(rust_analyzer_proc_macro_srv.as_os_str()).try_exists()? {
-        anyhow::bail!(
-            "{} does not exist. Run `rustup component add rust-analyzer --toolchain {toolchain}`",
-            Path::new(rust_analyzer_proc_macro_srv.as_os_str()).display(),
-        );
-    }
-
-    Ok(rust_analyzer_proc_macro_srv)
+        .pipe(AbsPathBuf::assert))
 }
@@ -0,0 +1,398 @@
+use crate::{
+    package_spec::PackageSpec,
+    shell::Shell,
+    workspace::{PackageExt as _, TargetExt as _},
+};
+use anyhow::bail;
+use cargo_metadata as cm;
+use itertools::Itertools as _;
+use petgraph::{graph::NodeIndex, Direction};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// Prints the crates to bundle as a tree, or as an inverted tree from the crates matching `invert`.
+pub(crate) fn print(
+    metadata: &cm::Metadata,
+    root_package: &cm::Package,
+    root: &cm::Target,
+    libs_to_bundle: &BTreeMap<&cm::PackageId, (&cm::Target, String)>,
+    unused_deps: Option<&HashSet<String>>,
+    invert: Option<&PackageSpec>,
+    shell: &mut Shell,
+) -> anyhow::Result<()> {
+    let resolve_nodes = metadata
+        .resolve
+        .as_ref()
+        .map(|cm::Resolve { nodes, .. }| &nodes[..])
+        .unwrap_or(&[])
+        .iter()
+        .map(|node| (&node.id, node))
+        .collect::<HashMap<_, _>>();
+
+    let (graph, indices) = crate::normal_non_host_dep_graph(&resolve_nodes, libs_to_bundle);
+
+    // cargo-udeps reports the unused dependencies by their names in `Cargo.toml`. The ones it
+    // reports may still be bundled for other crates.
+    let renames = root_package
+        .dependencies
+        .iter()
+        .flat_map(|cm::Dependency { rename, .. }| rename)
+        .collect::<HashSet<_>>();
+    let is_direct = |id: &cm::PackageId| {
+        *id == root_package.id
+            || resolve_nodes.get(&root_package.id).is_some_and(|n| {
+                n.deps.iter().any(|d| {
+                    let name_in_toml = if renames.contains(&d.name) {
+                        &d.name
+                    } else {
+                        &metadata[&d.pkg].name
+                    };
+                    d.pkg == *id && !unused_deps.is_some_and(|u| u.contains(name_in_toml))
+                })
+            })
+    };
+
+    // The features `dependent` enables: the ones in its declarations, `default` unless
+    // `default-features = false`, and `{dependency}/{feature}` in its own enabled features.
+    let enabled_features = |dependent: &cm::PackageId, dependency: &cm::PackageId| {
+        let (dependent_package, dependency_package) = (&metadata[dependent], &metadata[dependency]);
+        let resolved = &resolve_nodes[dependency].features;
+        let mut features = BTreeSet::new();
+        for node_dep in resolve_nodes[dependent]
+            .deps
+            .iter()
+            .filter(|d| d.pkg == *dependency)
+        {
+            for decl in dependent_package.dependencies.iter().filter(|d| {
+                d.name == dependency_package.name
+                    && match &d.rename {
+                        Some(rename) => node_dep.name == rename.replace('-', "_"),
+                        None => dependency_package
+                            .lib_like_target()
+                            .is_some_and(|t| node_dep.name == t.crate_name()),
+                    }
+            }) {
+                let name_in_toml = decl.rename.as_ref().unwrap_or(&decl.name);
+                features.extend(decl.features.iter().map(|s| &**s));
+                if decl.uses_default_features {
+                    features.insert("default");
+                }
+                for feature in &resolve_nodes[dependent].features {
+                    for value in dependent_package
+                        .features
+                        .get(feature)
+                        .into_iter()
+                        .flatten()
+                    {
+                        if let Some((dep, feature)) = value.split_once('/') {
+                            if dep.trim_end_matches('?') == name_in_toml {
+                                features.insert(feature);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        features
+            .into_iter()
+            .filter(|f| resolved.iter().any(|r| r == f))
+            .format(", ")
+            .to_string()
+    };
+
+    // `features` describes the edge from the parent, if any. Otherwise the resolved features are
+    // printed.
+    let describe = |id: &cm::PackageId, features: Option<String>| -> String {
+        let package = &metadata[id];
+        let (_, pseudo_extern_crate_name) = &libs_to_bundle[id];
+
+        let mut notes = vec![format!("as `{}`", pseudo_extern_crate_name)];
+        if let Some(features) = features {
+            notes.extend((!features.is_empty()).then_some(features));
+        } else if !resolve_nodes[id].features.is_empty() {
+            let features = resolve_nodes[id].features.iter().format(", ");
+            notes.push(format!("features: {}", features));
+        }
+        if unused_deps.is_some() && *id != root_package.id && is_direct(id) {
+            notes.push("kept by cargo-udeps".to_owned());
+        }
+        if *id != root_package.id {
+            notes.push(format!("`--exclude {}`", exclude_spec(metadata, package)));
+        }
+
+        format!(
+            "{} v{}{} [{}]",
+            package.name,
+            package.version,
+            if package.source.is_none() {
+                format!(" ({})", package.manifest_dir())
+            } else {
+                "".to_owned()
+            },
+            notes.join("; "),
+        )
+    };
+
+    let root_line = format!(
+        "{} v{} ({} `{}`)",
+        root_package.name, root_package.version, root.kind[0], root.name,
+    );
+
+    let mut lines = vec![];
+
+    if let Some(invert) = invert {
+        let starts = libs_to_bundle
+            .keys()
+            .filter(|id| invert.matches(&metadata[**id]))
+            .collect::<Vec<_>>();
+        if starts.is_empty() {
+            bail!("the spec did not match any crate to bundle");
+        }
+        for (i, id) in starts.into_iter().enumerate() {
+            if i > 0 {
+                lines.push("".to_owned());
+            }
+            let mut visited = HashSet::new();
+            lines.push(describe(id, None));
+            let children = dependents(&graph, indices[*id], is_direct(id));
+            print_children(&children, "", &mut visited, &mut lines, &|i| match i {
+                Node::Lib(i, dependency) => {
+                    let id = graph[i];
+                    let features = dependency.map(|dependency| {
+                        let features = enabled_features(id, dependency);
+                        if features.is_empty() {
+                            features
+                        } else {
+                            let name = &metadata[dependency].name;
+                            format!("features of `{}`: {}", name, features)
+                        }
+                    });
+                    let children = dependents(&graph, i, is_direct(id));
+                    (describe(id, features), Some(i), children)
+                }
+                Node::Root => (root_line.clone(), None, vec![]),
+            });
+        }
+    } else {
+        let children = libs_to_bundle
+            .keys()
+            .filter(|id| is_direct(id))
+            .map(|id| Node::Lib(indices[*id], Some(&root_package.id)))
+            .collect::<Vec<_>>();
+        lines.push(root_line.clone());
+        print_children(
+            &children,
+            "",
+            &mut HashSet::new(),
+            &mut lines,
+            &|i| match i {
+                Node::Lib(i, dependent) => {
+                    let id = graph[i];
+                    let features = dependent.map(|dependent| {
+                        let features = enabled_features(dependent, id);
+                        if features.is_empty() {
+                            features
+                        } else {
+                            format!("features: {}", features)
+                        }
+                    });
+                    let children = graph
+                        .neighbors_directed(i, Direction::Incoming)
+                        .sorted_by_key(|&i| graph[i])
+                        .map(|j| Node::Lib(j, Some(id)))
+                        .collect();
+                    (describe(id, features), Some(i), children)
+                }
+                Node::Root => unreachable!(),
+            },
+        );
+    }
+
+    if let Some(unused_deps) = unused_deps.filter(|u| !u.is_empty()) {
+        lines.push("".to_owned());
+        lines.push(format!(
+            "Removed by cargo-udeps: {}",
+            unused_deps
+                .iter()
+                .sorted()
+                .map(|s| format!("`{}`", s))
+                .format(", "),
+        ));
+    }
+
+    for line in lines {
+        writeln!(shell.out(), "{}", line)?;
+    }
+    return Ok(());
+
+    /// A crate with its parent in the tree.
+    #[derive(Clone, Copy)]
+    enum Node<'a> {
+        Lib(NodeIndex, Option<&'a cm::PackageId>),
+        Root,
+    }
+
+    fn dependents<'a>(
+        graph: &petgraph::Graph<&'a cm::PackageId, ()>,
+        i: NodeIndex,
+        used_by_root: bool,
+    ) -> Vec<Node<'a>> {
+        graph
+            .neighbors_directed(i, Direction::Outgoing)
+            .sorted_by_key(|&i| graph[i])
+            .map(|j| Node::Lib(j, Some(graph[i])))
+            .chain(used_by_root.then_some(Node::Root))
+            .collect()
+    }
+
+    fn print_children<'a>(
+        children: &[Node<'a>],
+        prefix: &str,
+        visited: &mut HashSet<NodeIndex>,
+        lines: &mut Vec<String>,
+        expand: &dyn Fn(Node<'a>) -> (String, Option<NodeIndex>, Vec<Node<'a>>),
+    ) {
+        for (i, &child) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            let (line, index, grandchildren) = expand(child);
+            let first_visit = index.is_none_or(|index| visited.insert(index));
+            lines.push(format!(
+                "{}{}{}{}",
+                prefix,
+                if last { "└── " } else { "├── " },
+                line,
+                if first_visit || grandchildren.is_empty() {
+                    ""
+                } else {
+                    " (*)"
+                },
+            ));
+            if first_visit {
+                let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                print_children(&grandchildren, &prefix, visited, lines, expand);
+            }
+        }
+    }
+}
+
+/// Returns the shortest spec that matches only the package.
+fn exclude_spec(metadata: &cm::Metadata, package: &cm::Package) -> String {
+    let count = |spec: &str| {
+        spec.parse::<PackageSpec>().map_or(usize::MAX, |spec| {
+            metadata.packages.iter().filter(|p| spec.matches(p)).count()
+        })
+    };
+
+    let by_name = package.name.clone();
+    let by_version = format!("{}:{}", package.name, package.version);
+
+    if count(&by_name) == 1 {
+        by_name
+    } else if count(&by_version) == 1 || package.source.is_none() {
+        by_version
+    } else {
+        let source = package.source.as_ref().expect("checked").repr.as_str();
+        let url = source
+            .split_once('+')
+            .map_or(source, |(_, url)| url)
+            .split(['?', '#'])
+            .next()
+            .unwrap_or_default();
+        format!("{}#{}", url, by_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{shell::Captured, workspace::MetadataExt as _};
+    use maplit::hashset;
+
+    #[test]
+    fn print() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let ws = &tempdir.path().join("ws");
+        for (path, content) in [
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"app\", \"mylib\", \"dep\"]\n",
+            ),
+            (
+                "app/Cargo.toml",
+                r#"[package]
+name = "app"
+version = "0.0.0"
+edition = "2018"
+
+[dependencies]
+dep = { path = "../dep", default-features = false }
+mylib = { path = "../mylib", features = ["fast"] }
+"#,
+            ),
+            ("app/src/main.rs", "fn main() {}\n"),
+            (
+                "mylib/Cargo.toml",
+                r#"[package]
+name = "mylib"
+version = "0.1.0"
+edition = "2018"
+
+[features]
+default = ["extra"]
+extra = ["dep/b"]
+fast = []
+
+[dependencies]
+dep = { path = "../dep", features = ["a"] }
+"#,
+            ),
+            ("mylib/src/lib.rs", ""),
+            (
+                "dep/Cargo.toml",
+                r#"[package]
+name = "dep"
+version = "0.1.0"
+edition = "2018"
+
+[features]
+default = []
+a = []
+b = []
+"#,
+            ),
+            ("dep/src/lib.rs", ""),
+        ] {
+            cargo_util::paths::create_dir_all(ws.join(path).parent().unwrap())?;
+            cargo_util::paths::write(ws.join(path), content)?;
+        }
+
+        let metadata =
+            crate::workspace::cargo_metadata(&ws.join("app").join("Cargo.toml"), ws, true)?;
+        let (root, root_package) = metadata.bin_target_by_name("app")?;
+
+        let print = |unused_deps, invert: Option<&str>| -> anyhow::Result<_> {
+            let libs_to_bundle =
+                crate::libs_to_bundle(&metadata, root_package, root, unused_deps, &[], &[])?;
+            let stdout = Captured::default();
+            super::print(
+                &metadata,
+                root_package,
+                root,
+                &libs_to_bundle,
+                unused_deps,
+                invert.map(|s| s.parse().unwrap()).as_ref(),
+                &mut stdout.shell(),
+            )?;
+            Ok(stdout
+                .to_string_lossy()
+                .replace(&*ws.to_string_lossy(), "[WS]"))
+        };
+
+        // `dep` is reported as unused by cargo-udeps, but `mylib` still needs it.
+        let unused_deps = hashset!("dep".to_owned());
+        insta::assert_snapshot!("print_normal", print(None, None)?);
+        insta::assert_snapshot!("print_udeps", print(Some(&unused_deps), None)?);
+        insta::assert_snapshot!("print_inverted", print(None, Some("dep"))?);
+        Ok(())
+    }
+}
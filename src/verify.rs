@@ -0,0 +1,173 @@
+use crate::{
+    shell::Shell,
+    testing::{self, Run},
+};
+use anyhow::{bail, Context as _};
+use itertools::Itertools as _;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The time limit for each run when the judge profile does not have `time-limit`.
+const DEFAULT_TIME_LIMIT: Duration = Duration::from_secs(10);
+
+/// Runs the original and the bundled executables on every `*.in` file in `dir` and compares their
+/// STDOUTs and exit statuses.
+pub(crate) fn verify_with(
+    dir: &Path,
+    original: &Path,
+    bundled: &Path,
+    time_limit: Option<Duration>,
+    shell: &mut Shell,
+) -> anyhow::Result<()> {
+    let time_limit = time_limit.unwrap_or(DEFAULT_TIME_LIMIT);
+
+    let inputs = list_inputs(dir)?;
+    if inputs.is_empty() {
+        shell.warn(format!("no `*.in` files in `{}`", dir.display()))?;
+        return Ok(());
+    }
+
+    let mut differences = vec![];
+
+    for input in &inputs {
+        let name = input.strip_prefix(dir).unwrap_or(input).display();
+        shell.status("Verifying", format!("with `{}`", name))?;
+
+        let original = testing::run(original, input, Some(time_limit))?;
+        let bundled = testing::run(bundled, input, Some(time_limit))?;
+
+        if original.status.is_none() || bundled.status.is_none() {
+            let exceeded = match (original.status, bundled.status) {
+                (None, None) => "both",
+                (None, Some(_)) => "the original",
+                (Some(_), _) => "the bundled",
+            };
+            differences.push(format!(
+                "- `{}`: {} exceeded the time limit ({} ms)\n",
+                name,
+                exceeded,
+                time_limit.as_millis(),
+            ));
+        } else if original.status != bundled.status {
+            let show = |run: &Run| run.status.map(|s| s.to_string()).unwrap_or_default();
+            differences.push(format!(
+                "- `{}`: the exit statuses differ (original: {}, bundled: {})\n",
+                name,
+                show(&original),
+                show(&bundled),
+            ));
+        } else if let Some((line, original, bundled)) = first_difference(&original, &bundled) {
+            differences.push(format!(
+                "- `{}`: the outputs differ at line {}\n    original: {:?}\n    bundled:  {:?}\n",
+                name, line, original, bundled,
+            ));
+        }
+    }
+
+    if !differences.is_empty() {
+        bail!(
+            "could not verify the bundled code on {}/{} input(s)\n\n{}",
+            differences.len(),
+            inputs.len(),
+            differences.iter().format(""),
+        );
+    }
+
+    shell.status("Verified", format!("{} input(s)", inputs.len()))?;
+    Ok(())
+}
+
+fn list_inputs(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut inputs = vec![];
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("could not read `{}`", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "in") {
+            inputs.push(path);
+        }
+    }
+    inputs.sort();
+    Ok(inputs)
+}
+
+fn first_difference(original: &Run, bundled: &Run) -> Option<(usize, String, String)> {
+    if original.stdout == bundled.stdout {
+        return None;
+    }
+    let original = String::from_utf8_lossy(&original.stdout);
+    let bundled = String::from_utf8_lossy(&bundled.stdout);
+    let (mut original, mut bundled) = (original.split('\n'), bundled.split('\n'));
+    let mut line = 1;
+    loop {
+        match (original.next(), bundled.next()) {
+            (Some(l), Some(r)) if l == r => line += 1,
+            (l, r) => {
+                let show = |s: Option<&str>| s.unwrap_or("<EOF>").to_owned();
+                return Some((line, show(l), show(r)));
+            }
+        }
+    }
+}
+
+// The executables are shell scripts.
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::shell::Captured;
+    use pretty_assertions::assert_eq;
+    use std::{os::unix::fs::PermissionsExt as _, path::Path};
+
+    #[test]
+    fn verify_with() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let write_exe = |name: &str, script: &str| -> anyhow::Result<_> {
+            let path = tempdir.path().join(name);
+            cargo_util::paths::write(&path, script)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+            Ok(path)
+        };
+        let original = &write_exe("original", "#!/bin/sh\ncat\n")?;
+        let bundled = &write_exe(
+            "bundled",
+            "#!/bin/sh\n\
+             input=$(cat)\n\
+             [ \"$input\" = exit ] && exit 1\n\
+             printf '%s\\n' \"$input\" | sed s/b/c/\n",
+        )?;
+        let verify_with = |dir: &Path| {
+            super::verify_with(
+                dir,
+                original,
+                bundled,
+                None,
+                &mut Captured::default().shell(),
+            )
+        };
+
+        let dir = &tempdir.path().join("tests");
+        cargo_util::paths::create_dir_all(dir)?;
+        // No inputs is not an error.
+        verify_with(dir)?;
+
+        cargo_util::paths::write(dir.join("1.in"), "a\n")?;
+        cargo_util::paths::write(dir.join("1.out"), "b\n")?;
+        verify_with(dir)?;
+
+        cargo_util::paths::write(dir.join("2.in"), "a\nb\n")?;
+        cargo_util::paths::write(dir.join("3.in"), "exit\n")?;
+        assert_eq!(
+            "could not verify the bundled code on 2/3 input(s)\n\
+             \n\
+             - `2.in`: the outputs differ at line 2\n    \
+             original: \"b\"\n    \
+             bundled:  \"c\"\n\
+             - `3.in`: the exit statuses differ (original: exit status: 0, bundled: exit status: 1)\n",
+            verify_with(dir).unwrap_err().to_string(),
+        );
+        Ok(())
+    }
+}
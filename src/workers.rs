@@ -0,0 +1,266 @@
+use anyhow::anyhow;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, PoisonError,
+    },
+    thread,
+};
+
+/// The number of threads for `num_jobs` jobs. It does not exceed `available_parallelism()`.
+fn num_workers(num_jobs: usize) -> usize {
+    thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(num_jobs)
+        .max(1)
+}
+
+/// Applies `f` to each item in a fixed number of threads, and returns the results in order.
+pub(crate) fn map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next = &AtomicUsize::new(0);
+    let f = &f;
+
+    let mut results = thread::scope(|scope| {
+        (0..num_workers(items.len()))
+            .map(|_| {
+                scope.spawn(move || {
+                    let mut results = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            break results;
+                        };
+                        results.push((i, f(item)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(crate::join)
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// A fixed number of threads that open libraries, scan them, and then process them.
+///
+/// `syn` and `proc_macro2` values cannot be sent to other threads. An opened library stays in the
+/// thread that opened it until it is processed there, so that each library is opened at most once.
+pub(crate) struct LibWorkers<P, K, S, R> {
+    open_tx: Option<mpsc::Sender<P>>,
+    scanned_rx: mpsc::Receiver<(P, usize, anyhow::Result<S>)>,
+    key_txs: Vec<mpsc::Sender<(P, K)>>,
+    processed_rx: mpsc::Receiver<(P, anyhow::Result<R>)>,
+    holders: HashMap<P, usize>,
+    num_processing: usize,
+}
+
+impl<P, K, S, R> LibWorkers<P, K, S, R>
+where
+    P: Copy + Eq + Hash + Send,
+    K: Send,
+    S: Send,
+    R: Send,
+{
+    pub(crate) fn spawn<'scope, E>(
+        scope: &'scope thread::Scope<'scope, '_>,
+        num_libs: usize,
+        open: &'scope (impl Fn(P) -> anyhow::Result<E> + Sync),
+        scan: &'scope (impl Fn(&E) -> S + Sync),
+        process: &'scope (impl Fn(P, E, &K) -> anyhow::Result<R> + Sync),
+    ) -> Self
+    where
+        P: 'scope,
+        K: 'scope,
+        S: 'scope,
+        R: 'scope,
+    {
+        let (open_tx, open_rx) = mpsc::channel::<P>();
+        let open_rx = Arc::new(Mutex::new(open_rx));
+        let (scanned_tx, scanned_rx) = mpsc::channel();
+        let (processed_tx, processed_rx) = mpsc::channel();
+
+        let key_txs = (0..num_workers(num_libs))
+            .map(|worker| {
+                let (key_tx, key_rx) = mpsc::channel::<(P, K)>();
+                let open_rx = open_rx.clone();
+                let scanned_tx = scanned_tx.clone();
+                let processed_tx = processed_tx.clone();
+
+                scope.spawn(move || {
+                    let mut opened = HashMap::new();
+
+                    loop {
+                        let next = open_rx
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .recv();
+                        let Ok(pkg) = next else {
+                            break;
+                        };
+                        let scanned = open(pkg).map(|lib| {
+                            let scanned = scan(&lib);
+                            opened.insert(pkg, lib);
+                            scanned
+                        });
+                        if scanned_tx.send((pkg, worker, scanned)).is_err() {
+                            return;
+                        }
+                    }
+                    drop(scanned_tx);
+
+                    for (pkg, key) in key_rx {
+                        let processed = opened
+                            .remove(&pkg)
+                            .map_or_else(|| open(pkg), Ok)
+                            .and_then(|lib| process(pkg, lib, &key));
+                        if processed_tx.send((pkg, processed)).is_err() {
+                            return;
+                        }
+                    }
+                });
+                key_tx
+            })
+            .collect();
+
+        Self {
+            open_tx: Some(open_tx),
+            scanned_rx,
+            key_txs,
+            processed_rx,
+            holders: HashMap::new(),
+            num_processing: 0,
+        }
+    }
+
+    /// Opens and scans the libraries. This can be called only once, before `process`.
+    pub(crate) fn scan(
+        &mut self,
+        pkgs: impl IntoIterator<Item = P>,
+    ) -> anyhow::Result<Vec<(P, S)>> {
+        let open_tx = self.open_tx.take().expect("should be called once");
+        let mut num_jobs = 0;
+        for pkg in pkgs {
+            let _ = open_tx.send(pkg);
+            num_jobs += 1;
+        }
+        drop(open_tx);
+
+        (0..num_jobs)
+            .map(|_| {
+                let (pkg, worker, scanned) = self.scanned_rx.recv().map_err(|_| panicked())?;
+                self.holders.insert(pkg, worker);
+                Ok((pkg, scanned?))
+            })
+            .collect()
+    }
+
+    /// Processes the library in the thread that opened it, or in any thread if it has not been
+    /// opened.
+    pub(crate) fn process(&mut self, pkg: P, key: K) {
+        self.open_tx = None;
+        let worker = self
+            .holders
+            .remove(&pkg)
+            .unwrap_or(self.num_processing % self.key_txs.len());
+        let _ = self.key_txs[worker].send((pkg, key));
+        self.num_processing += 1;
+    }
+
+    /// Waits for the libraries sent to `process`.
+    pub(crate) fn finish(self) -> anyhow::Result<HashMap<P, R>> {
+        let Self {
+            key_txs,
+            processed_rx,
+            num_processing,
+            ..
+        } = self;
+        drop(key_txs);
+
+        (0..num_processing)
+            .map(|_| {
+                let (pkg, processed) = processed_rx.recv().map_err(|_| panicked())?;
+                Ok((pkg, processed?))
+            })
+            .collect()
+    }
+}
+
+fn panicked() -> anyhow::Error {
+    anyhow!("a worker thread panicked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LibWorkers;
+    use maplit::{btreemap, hashmap};
+    use pretty_assertions::assert_eq;
+    use std::{
+        collections::BTreeMap,
+        sync::{Mutex, PoisonError},
+        thread,
+    };
+
+    #[test]
+    fn map() {
+        let items = (0..100).collect::<Vec<_>>();
+        assert_eq!(
+            items.iter().map(|i| i * 2).collect::<Vec<_>>(),
+            super::map(&items, |i| i * 2),
+        );
+        assert_eq!(Vec::<i32>::new(), super::map(&[], |i: &i32| *i));
+    }
+
+    #[test]
+    fn lib_workers() -> anyhow::Result<()> {
+        let opened = &Mutex::new(BTreeMap::<_, usize>::new());
+        let open = &|pkg: u32| -> anyhow::Result<_> {
+            *opened
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(pkg)
+                .or_default() += 1;
+            if pkg == 99 {
+                anyhow::bail!("could not open `{}`", pkg);
+            }
+            // Not `Send`.
+            Ok(std::rc::Rc::new(pkg))
+        };
+        let scan = &|lib: &std::rc::Rc<u32>| **lib % 2 == 0;
+        let process = &|pkg, lib: std::rc::Rc<u32>, key: &u32| {
+            assert_eq!(pkg, *lib);
+            Ok(pkg * key)
+        };
+
+        let processed = thread::scope(|scope| {
+            let mut workers = LibWorkers::spawn(scope, 4, open, scan, process);
+            let mut scanned = workers.scan([1, 2, 3])?;
+            scanned.sort_unstable();
+            assert_eq!(vec![(1, false), (2, true), (3, false)], scanned);
+            // `3` is opened but not processed, as when its output is cached.
+            for (pkg, key) in [(1, 10), (2, 10), (4, 10)] {
+                workers.process(pkg, key);
+            }
+            workers.finish()
+        })?;
+
+        assert_eq!(hashmap!(1 => 10, 2 => 20, 4 => 40), processed);
+        // Each library is opened once, even when it is both scanned and processed.
+        assert_eq!(
+            btreemap!(1 => 1, 2 => 1, 3 => 1, 4 => 1),
+            *opened.lock().unwrap_or_else(PoisonError::into_inner),
+        );
+
+        let err = thread::scope(|scope| {
+            let mut workers = LibWorkers::<_, u32, _, _>::spawn(scope, 4, open, scan, process);
+            workers.scan([1, 99]).map(drop)
+        })
+        .unwrap_err();
+        assert_eq!("could not open `99`", err.to_string());
+        Ok(())
+    }
+}
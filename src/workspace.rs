@@ -1,19 +1,26 @@
 mod license;
 
-use crate::{process::ProcessBuilderExt as _, shell::Shell, toolchain, User};
-use anyhow::{bail, Context as _};
+pub(crate) use self::license::{
+    cache_entries, refresh_cache, remove_cache_entries, CacheEntry, CacheEntryKind, LicensePolicy,
+};
+
+use crate::{
+    package_spec::PackageSpec, process::ProcessBuilderExt as _, shell::Shell, toolchain, User,
+};
+use anyhow::{anyhow, bail, Context as _};
 use camino::{Utf8Path, Utf8PathBuf};
 use cargo_metadata as cm;
 use cargo_util::ProcessBuilder;
+use fs2::FileExt as _;
 use if_chain::if_chain;
 use indoc::indoc;
 use itertools::Itertools as _;
-use krates::PkgSpec;
-use rand::Rng as _;
+
 use serde::Deserialize;
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     env,
+    fs::File,
     io::Cursor,
     path::{Path, PathBuf},
     str,
@@ -32,11 +39,17 @@ pub(crate) fn locate_project(cwd: &Path) -> anyhow::Result<PathBuf> {
         })
 }
 
-pub(crate) fn cargo_metadata(manifest_path: &Path, cwd: &Path) -> cm::Result<cm::Metadata> {
-    cm::MetadataCommand::new()
-        .manifest_path(manifest_path)
-        .current_dir(cwd)
-        .exec()
+pub(crate) fn cargo_metadata(
+    manifest_path: &Path,
+    cwd: &Path,
+    offline: bool,
+) -> cm::Result<cm::Metadata> {
+    let mut cmd = cm::MetadataCommand::new();
+    cmd.manifest_path(manifest_path).current_dir(cwd);
+    if offline {
+        cmd.other_options(vec!["--offline".to_owned()]);
+    }
+    cmd.exec()
 }
 
 pub(crate) fn resolve_behavior(
@@ -61,14 +74,32 @@ pub(crate) fn resolve_behavior(
     }
 }
 
+pub(crate) fn rustc_print_cfg(
+    manifest_dir: &Utf8Path,
+) -> anyhow::Result<Vec<cfg_expr::Expression>> {
+    let rustc_exe = crate::process::cargo_exe()?
+        .with_file_name("rustc")
+        .with_extension(env::consts::EXE_EXTENSION);
+
+    Ok(ProcessBuilder::new(rustc_exe)
+        .args(&["--print", "cfg"])
+        .cwd(manifest_dir)
+        .read_stdout::<String>()?
+        .lines()
+        .flat_map(cfg_expr::Expression::parse) // https://github.com/EmbarkStudios/cfg-expr/blob/25290dba689ce3f3ab589926ba545875f048c130/src/expr/parser.rs#L180-L195
+        .collect())
+}
+
 pub(crate) fn cargo_check_message_format_json(
     toolchain: &str,
     metadata: &cm::Metadata,
     package: &cm::Package,
     krate: &cm::Target,
+    offline: bool,
     shell: &mut Shell,
 ) -> anyhow::Result<Vec<cm::Message>> {
-    let messages = ProcessBuilder::new(toolchain::rustup_exe(package.manifest_dir())?)
+    let mut cargo = ProcessBuilder::new(toolchain::rustup_exe(package.manifest_dir())?);
+    cargo
         .arg("run")
         .arg(toolchain)
         .arg("cargo")
@@ -77,8 +108,17 @@ pub(crate) fn cargo_check_message_format_json(
         .arg("json")
         .arg("-p")
         .arg(format!("{}:{}", package.name, package.version))
-        .args(&krate.target_option())
-        .cwd(&metadata.workspace_root)
+        // A loose file is not a target of the package. The default targets are checked instead.
+        .args(&if package.targets.contains(krate) {
+            krate.target_option()
+        } else {
+            vec![]
+        })
+        .cwd(&metadata.workspace_root);
+    if offline {
+        cargo.arg("--offline");
+    }
+    let messages = cargo
         .try_inspect(|this| shell.status("Running", this))?
         .read_stdout::<Vec<u8>>()?;
 
@@ -106,38 +146,40 @@ pub(crate) fn list_out_dirs<'cm>(
         .collect()
 }
 
-pub(crate) fn cargo_check_using_current_lockfile_and_cache(
+/// Writes `code` to a scratch package in `<cache-dir>/check/`.
+///
+/// The scratch package is kept for each root package so that Cargo can reuse the build results of
+/// the dependencies in the shared target directory. It is locked until the returned value is
+/// dropped.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn scratch_package_using_current_lockfile_and_cache(
     metadata: &cm::Metadata,
     package: &cm::Package,
     target: &cm::Target,
-    exclude: &[PkgSpec],
+    exclude: &[PackageSpec],
     code: &str,
-) -> anyhow::Result<()> {
-    let package_name = {
-        let mut rng = rand::thread_rng();
-        let suf = (0..16)
-            .map(|_| match rng.gen_range(0..=35) {
-                n @ 0..=25 => b'a' + n,
-                n @ 26..=35 => b'0' + n - 26,
-                _ => unreachable!(),
-            })
-            .collect::<Vec<_>>();
-        let suf = str::from_utf8(&suf).expect("should be valid ASCII");
-        format!("cargo-equip-check-output-{}", suf)
-    };
+    edition: Option<&str>,
+    cache_dir: &Path,
+    shell: &mut Shell,
+) -> anyhow::Result<ScratchPackage> {
+    let package_name = format!(
+        "cargo-equip-check-output-{}",
+        &format!("{:x}", md5::compute(&package.id.repr))[..16],
+    );
     let crate_name = &*if target.is_lib() {
         package_name.replace('-', "_")
     } else {
         package_name.to_owned()
     };
 
-    let temp_pkg = tempfile::Builder::new()
-        .prefix(&package_name)
-        .rand_bytes(0)
-        .tempdir()?;
+    let scratch_pkg = &cache_dir.join("check").join(&package_name);
+    cargo_util::paths::create_dir_all(scratch_pkg)?;
 
-    let orig_manifest =
-        cargo_util::paths::read(package.manifest_path.as_ref())?.parse::<toml_edit::Document>()?;
+    let lock = File::create(scratch_pkg.join(".lock"))?;
+    if lock.try_lock_exclusive().is_err() {
+        shell.status("Blocking", "waiting for file lock on the scratch package")?;
+        lock.lock_exclusive()?;
+    }
 
     let mut temp_manifest = indoc! {r#"
         [package]
@@ -149,7 +191,7 @@ pub(crate) fn cargo_check_using_current_lockfile_and_cache(
     .unwrap();
 
     temp_manifest["package"]["name"] = toml_edit::value(package_name);
-    temp_manifest["package"]["edition"] = toml_edit::value(&*package.edition);
+    temp_manifest["package"]["edition"] = toml_edit::value(edition.unwrap_or(&package.edition));
     let mut tbl = toml_edit::Table::new();
     tbl["name"] = toml_edit::value(crate_name);
     tbl["path"] = toml_edit::value(format!("{}.rs", crate_name));
@@ -166,47 +208,128 @@ pub(crate) fn cargo_check_using_current_lockfile_and_cache(
             arr
         });
     }
-    temp_manifest["dependencies"] = orig_manifest["dependencies"].clone();
-    temp_manifest["dev-dependencies"] = orig_manifest["dev-dependencies"].clone();
-
-    let renames = package
-        .dependencies
+    // Dependencies are generated from the resolved metadata, so that `workspace = true` and
+    // optional ones enabled by features work. Only the active ones to be excluded are needed.
+    let node = metadata
+        .resolve
+        .as_ref()
+        .expect("`resolve` is `null`")
+        .nodes
         .iter()
-        .filter(|cm::Dependency { kind, .. }| {
-            [cm::DependencyKind::Normal, cm::DependencyKind::Development].contains(kind)
-        })
-        .flat_map(|cm::Dependency { rename, .. }| rename)
-        .collect::<HashSet<_>>();
+        .find(|cm::Node { id, .. }| *id == package.id)
+        .expect("should contain");
 
-    let modify_dependencies = |table: &mut toml_edit::Table| {
-        for name_in_toml in metadata
-            .resolve
-            .as_ref()
-            .expect("`resolve` is `null`")
-            .nodes
-            .iter()
-            .find(|cm::Node { id, .. }| *id == package.id)
-            .expect("should contain")
-            .deps
-            .iter()
-            .filter(|cm::NodeDep { pkg, .. }| !exclude.iter().any(|s| s.matches(&metadata[pkg])))
-            .map(|cm::NodeDep { name, pkg, .. }| {
-                if renames.contains(&name) {
-                    name
-                } else {
-                    &metadata[pkg].name
+    for dep in &package.dependencies {
+        if ![cm::DependencyKind::Normal, cm::DependencyKind::Development].contains(&dep.kind) {
+            continue;
+        }
+        let name_in_toml = dep.rename.as_ref().unwrap_or(&dep.name);
+
+        // The declaration is matched with the resolve graph itself instead of its version
+        // requirement and source, since `[patch]` and `[replace]` can change the source and
+        // prerelease versions do not match requirements like `*`. `NodeDep::name` is the name of
+        // the lib target unless the dependency is renamed, so it tells renames apart.
+        let resolved = node.deps.iter().find(
+            |cm::NodeDep {
+                 name,
+                 pkg,
+                 dep_kinds,
+                 ..
+             }| {
+                dep_kinds
+                    .iter()
+                    .any(|k| k.kind == dep.kind && k.target == dep.target)
+                    && match &dep.rename {
+                        Some(rename) => *name == rename.replace('-', "_"),
+                        None => {
+                            metadata[pkg].name == dep.name
+                                && metadata[pkg]
+                                    .lib_like_target()
+                                    .is_some_and(|t| *name == t.crate_name())
+                        }
+                    }
+            },
+        );
+        if !resolved.is_some_and(|cm::NodeDep { pkg, .. }| {
+            exclude.iter().any(|s| s.matches(&metadata[pkg]))
+        }) {
+            continue;
+        }
+
+        let mut value = toml_edit::InlineTable::default();
+        if let Some(path) = &dep.path {
+            value.get_or_insert("path", path.as_str());
+        } else if let Some(source) = dep.source.as_ref().and_then(|s| s.strip_prefix("git+")) {
+            let source = source.split('#').next().unwrap_or_default();
+            let (url, query) = source.split_once('?').unwrap_or((source, ""));
+            value.get_or_insert("git", url);
+            for (key, val) in query.split('&').flat_map(|kv| kv.split_once('=')) {
+                if ["branch", "tag", "rev"].contains(&key) {
+                    value.get_or_insert(key, val);
                 }
-            })
-        {
-            table.remove(name_in_toml);
+            }
+        } else if let Some(registry) = &dep.registry {
+            value.get_or_insert("registry-index", &**registry);
+        }
+        if dep.req != semver::VersionReq::STAR {
+            value.get_or_insert("version", dep.req.to_string());
+        }
+        if dep.rename.is_some() {
+            value.get_or_insert("package", &*dep.name);
         }
+        if !dep.uses_default_features {
+            value.get_or_insert("default-features", false);
+        }
+        if !dep.features.is_empty() {
+            value.get_or_insert(
+                "features",
+                dep.features
+                    .iter()
+                    .map(|s| &**s)
+                    .collect::<toml_edit::Array>(),
+            );
+        }
+
+        let implicit_table = || {
+            let mut table = toml_edit::Table::new();
+            table.set_implicit(true);
+            toml_edit::Item::Table(table)
+        };
+        let table = if let Some(target) = &dep.target {
+            temp_manifest["target"]
+                .or_insert(implicit_table())
+                .as_table_mut()
+                .expect("should be a table")
+                .entry(&target.to_string())
+                .or_insert(implicit_table())
+        } else {
+            &mut temp_manifest.root
+        };
+        let table = table[if dep.kind == cm::DependencyKind::Development {
+            "dev-dependencies"
+        } else {
+            "dependencies"
+        }]
+        .or_insert(toml_edit::table());
+        table[&**name_in_toml] = toml_edit::value(value);
+    }
+
+    // `[patch]` and `[replace]` are only read from the workspace root.
+    let root_manifest =
+        cargo_util::paths::read(metadata.workspace_root.join("Cargo.toml").as_ref())?
+            .parse::<toml_edit::Document>()?;
+
+    for key in ["patch", "replace"] {
+        temp_manifest[key] = root_manifest[key].clone();
+    }
 
+    let absolutize_paths = |table: &mut toml_edit::Table| {
         for (_, value) in table.iter_mut() {
             if !value["path"].is_none() {
                 if let toml_edit::Item::Value(value) = &mut value["path"] {
                     if let Some(possibly_rel_path) = value.as_str() {
-                        *value = package
-                            .manifest_dir()
+                        *value = metadata
+                            .workspace_root
                             .join(possibly_rel_path)
                             .into_string()
                             .into();
@@ -216,42 +339,132 @@ pub(crate) fn cargo_check_using_current_lockfile_and_cache(
         }
     };
 
-    if let toml_edit::Item::Table(table) = &mut temp_manifest["dependencies"] {
-        modify_dependencies(table);
+    if let toml_edit::Item::Table(patch) = &mut temp_manifest["patch"] {
+        for (_, table) in patch.iter_mut() {
+            if let toml_edit::Item::Table(table) = table {
+                absolutize_paths(table);
+            }
+        }
     }
-    if let toml_edit::Item::Table(table) = &mut temp_manifest["dev-dependencies"] {
-        modify_dependencies(table);
+    if let toml_edit::Item::Table(table) = &mut temp_manifest["replace"] {
+        absolutize_paths(table);
     }
 
-    cargo_util::paths::write(
-        temp_pkg.path().join("Cargo.toml"),
-        temp_manifest.to_string(),
-    )?;
-    cargo_util::paths::copy(
-        metadata.workspace_root.join("Cargo.lock"),
-        temp_pkg.path().join("Cargo.lock"),
+    cargo_util::paths::write_if_changed(scratch_pkg.join("Cargo.toml"), temp_manifest.to_string())?;
+    cargo_util::paths::write_if_changed(
+        scratch_pkg.join("Cargo.lock"),
+        cargo_util::paths::read_bytes(metadata.workspace_root.join("Cargo.lock").as_ref())?,
     )?;
-    cargo_util::paths::write(temp_pkg.path().join(format!("{}.rs", crate_name)), code)?;
-
-    ProcessBuilder::new(crate::process::cargo_exe()?)
-        .arg("check")
-        .arg("--target-dir")
-        .arg(&metadata.target_directory)
-        .arg("--manifest-path")
-        .arg(temp_pkg.path().join("Cargo.toml"))
-        .args(&if target.is_bin() {
-            vec!["--bin", crate_name]
+    cargo_util::paths::write_if_changed(scratch_pkg.join(format!("{}.rs", crate_name)), code)?;
+
+    Ok(ScratchPackage {
+        cargo_exe: crate::process::cargo_exe()?,
+        manifest_path: scratch_pkg.join("Cargo.toml"),
+        target_dir: metadata.target_directory.clone(),
+        workspace_root: metadata.workspace_root.clone(),
+        crate_name: crate_name.to_owned(),
+        target_option: if target.is_bin() {
+            vec!["--bin".to_owned(), crate_name.to_owned()]
         } else if target.is_example() {
-            vec!["--example", crate_name]
+            vec!["--example".to_owned(), crate_name.to_owned()]
         } else {
-            vec!["--lib"]
-        })
-        .arg("--offline")
-        .cwd(&metadata.workspace_root)
-        .exec()?;
+            vec!["--lib".to_owned()]
+        },
+        _lock: lock,
+    })
+}
+
+pub(crate) struct ScratchPackage {
+    cargo_exe: PathBuf,
+    manifest_path: PathBuf,
+    target_dir: Utf8PathBuf,
+    workspace_root: Utf8PathBuf,
+    crate_name: String,
+    target_option: Vec<String>,
+    _lock: File,
+}
+
+impl ScratchPackage {
+    pub(crate) fn cargo_check(&self) -> anyhow::Result<()> {
+        self.cargo("check").exec()?;
+        Ok(())
+    }
+
+    /// Checks the bundled code without printing anything, and returns whether it compiles.
+    pub(crate) fn cargo_check_quietly(&self) -> anyhow::Result<bool> {
+        let output = self.cargo("check").build_command().output()?;
+        Ok(output.status.success())
+    }
 
-    temp_pkg.close()?;
-    Ok(())
+    /// Builds the bundled code with the `release` profile and returns the path to the executable.
+    pub(crate) fn cargo_build_release(&self) -> anyhow::Result<Utf8PathBuf> {
+        let mut cargo = self.cargo("build");
+        cargo.arg("--release");
+        find_executable(&cargo, &self.crate_name, &[])
+    }
+
+    /// Builds the bundled code with the `release` profile and extra arguments for `rustc`, then
+    /// returns the path to the executable.
+    pub(crate) fn cargo_rustc_release(&self, rustc_args: &[String]) -> anyhow::Result<Utf8PathBuf> {
+        let mut cargo = self.cargo("rustc");
+        cargo.arg("--release");
+        find_executable(&cargo, &self.crate_name, rustc_args)
+    }
+
+    fn cargo(&self, subcommand: &str) -> ProcessBuilder {
+        let mut cargo = ProcessBuilder::new(&self.cargo_exe);
+        cargo
+            .arg(subcommand)
+            .arg("--target-dir")
+            .arg(&self.target_dir)
+            .arg("--manifest-path")
+            .arg(&self.manifest_path)
+            .args(&self.target_option)
+            .arg("--offline")
+            .cwd(&self.workspace_root);
+        cargo
+    }
+}
+
+/// Builds the target with the `release` profile and returns the path to the executable.
+pub(crate) fn cargo_build_release(
+    metadata: &cm::Metadata,
+    package: &cm::Package,
+    target: &cm::Target,
+) -> anyhow::Result<Utf8PathBuf> {
+    let mut cargo = ProcessBuilder::new(crate::process::cargo_exe()?);
+    cargo
+        .arg("build")
+        .arg("--release")
+        .arg("-p")
+        .arg(format!("{}:{}", package.name, package.version))
+        .args(&target.target_option())
+        .cwd(&metadata.workspace_root);
+    find_executable(&cargo, &target.name, &[])
+}
+
+fn find_executable(
+    cargo: &ProcessBuilder,
+    target_name: &str,
+    rustc_args: &[String],
+) -> anyhow::Result<Utf8PathBuf> {
+    let mut cargo = cargo.clone();
+    cargo.args(&["--message-format", "json-render-diagnostics"]);
+    if !rustc_args.is_empty() {
+        cargo.arg("--").args(rustc_args);
+    }
+    let messages = cargo.read_stdout::<Vec<u8>>()?;
+
+    cm::Message::parse_stream(Cursor::new(messages))
+        .flat_map(|message| match message {
+            Ok(cm::Message::CompilerArtifact(cm::Artifact {
+                target, executable, ..
+            })) if target.name == target_name => executable.map(Ok),
+            Ok(_) => None,
+            Err(err) => Some(Err(err.into())),
+        })
+        .last()
+        .with_context(|| format!("could not find the executable of `{}`", target_name))?
 }
 
 pub(crate) trait MetadataExt {
@@ -269,12 +482,18 @@ pub(crate) trait MetadataExt {
         &'a self,
         src_path: &Path,
     ) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)>;
+    fn loose_file_target<'a>(
+        &'a self,
+        src_path: &Path,
+        manifest_path: &Path,
+    ) -> anyhow::Result<(cm::Target, &'a cm::Package)>;
     fn libs_to_bundle<'a>(
         &'a self,
         package_id: &'a cm::PackageId,
         need_dev_deps: bool,
         cargo_udeps_outcome: &HashSet<String>,
-        exclude: &[PkgSpec],
+        exclude: &[PackageSpec],
+        cfgs: &[cfg_expr::Expression],
     ) -> anyhow::Result<BTreeMap<&'a cm::PackageId, (&'a cm::Target, String)>>;
     fn dep_lib_by_extern_crate_name(
         &self,
@@ -383,12 +602,71 @@ impl MetadataExt for cm::Metadata {
         }
     }
 
+    /// Returns an ad-hoc bin target for a file that is not a target, along with the package whose
+    /// dependencies it uses.
+    fn loose_file_target<'a>(
+        &'a self,
+        src_path: &Path,
+        manifest_path: &Path,
+    ) -> anyhow::Result<(cm::Target, &'a cm::Package)> {
+        if !src_path.is_file() {
+            bail!("`{}` is not a file", src_path.display());
+        }
+        let src_path = Utf8PathBuf::from_path_buf(src_path.to_owned())
+            .map_err(|p| anyhow!("the path is not UTF-8: {}", p.display()))?;
+
+        let manifest_path = cargo_util::paths::normalize_path(manifest_path);
+        let package = self
+            .packages
+            .iter()
+            .find(|p| p.manifest_path.as_std_path() == manifest_path)
+            .with_context(|| {
+                if self.root_package().is_none()
+                    && self.workspace_root.join("Cargo.toml").as_std_path() == manifest_path
+                {
+                    format!(
+                        "`{}` is a virtual manifest. specify the `Cargo.toml` of a member with \
+                         `--deps-from`, or bundle a target of a member with `--bin` instead of \
+                         `--file`",
+                        manifest_path.display(),
+                    )
+                } else {
+                    format!("`{}` is not a package manifest", manifest_path.display())
+                }
+            })?;
+
+        let mut target = package
+            .targets
+            .first()
+            .with_context(|| format!("`{}` has no targets", package.name))?
+            .clone();
+        target.name = src_path
+            .file_stem()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        target.kind = vec!["bin".to_owned()];
+        target.crate_types = vec!["bin".to_owned()];
+        target.required_features = vec![];
+        target.src_path = src_path;
+        target.edition = package.edition.clone();
+        Ok((target, package))
+    }
+
     fn libs_to_bundle<'a>(
         &'a self,
         package_id: &'a cm::PackageId,
         need_dev_deps: bool,
         cargo_udeps_outcome: &HashSet<String>,
-        exclude: &[PkgSpec],
+        exclude: &[PackageSpec],
+        cfgs: &[cfg_expr::Expression],
     ) -> anyhow::Result<BTreeMap<&'a cm::PackageId, (&'a cm::Target, String)>> {
         let package = &self[package_id];
 
@@ -401,20 +679,7 @@ impl MetadataExt for cm::Metadata {
             .flat_map(|cm::Dependency { rename, .. }| rename)
             .collect::<HashSet<_>>();
 
-        let preds = {
-            let rustc_exe = crate::process::cargo_exe()?
-                .with_file_name("rustc")
-                .with_extension(env::consts::EXE_EXTENSION);
-
-            ProcessBuilder::new(rustc_exe)
-                .args(&["--print", "cfg"])
-                .cwd(package.manifest_path.with_file_name(""))
-                .read_stdout::<String>()?
-                .lines()
-                .flat_map(cfg_expr::Expression::parse) // https://github.com/EmbarkStudios/cfg-expr/blob/25290dba689ce3f3ab589926ba545875f048c130/src/expr/parser.rs#L180-L195
-                .collect::<Vec<_>>()
-        };
-        let preds = preds
+        let preds = cfgs
             .iter()
             .flat_map(cfg_expr::Expression::predicates)
             .collect::<Vec<_>>();
@@ -665,7 +930,13 @@ pub(crate) trait PackageExt {
     fn lib_like_target(&self) -> Option<&cm::Target>;
     fn manifest_dir(&self) -> &Utf8Path;
     fn edition(&self) -> Edition;
-    fn read_license_text(&self, mine: &[User], cache_dir: &Path) -> anyhow::Result<Option<String>>;
+    fn chosen_license(&self) -> Option<String>;
+    fn read_license_text(
+        &self,
+        mine: &[User],
+        cache_dir: &Path,
+        offline: bool,
+    ) -> anyhow::Result<Option<String>>;
 }
 
 impl PackageExt for cm::Package {
@@ -695,8 +966,17 @@ impl PackageExt for cm::Package {
         self.edition.parse().expect("`edition` modified invalidly")
     }
 
-    fn read_license_text(&self, mine: &[User], cache_dir: &Path) -> anyhow::Result<Option<String>> {
-        license::read_non_unlicense_license_file(self, mine, cache_dir)
+    fn chosen_license(&self) -> Option<String> {
+        license::chosen_license(self)
+    }
+
+    fn read_license_text(
+        &self,
+        mine: &[User],
+        cache_dir: &Path,
+        offline: bool,
+    ) -> anyhow::Result<Option<String>> {
+        license::read_non_unlicense_license_file(self, mine, cache_dir, offline)
     }
 }
 
@@ -808,3 +1088,158 @@ pub(crate) enum ResolveBehavior {
     #[serde(rename = "2")]
     V2,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{package_spec::PackageSpec, shell::Shell, workspace::MetadataExt as _};
+    use pretty_assertions::assert_eq;
+    use std::io;
+
+    #[test]
+    fn loose_file_target() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let ws = &tempdir.path().join("ws");
+        for (path, content) in [
+            ("Cargo.toml", "[workspace]\nmembers = [\"app\"]\n"),
+            (
+                "app/Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.0.0\"\nedition = \"2018\"\n",
+            ),
+            ("app/src/main.rs", "fn main() {}\n"),
+            ("scratch/a-1.rs", "fn main() {}\n"),
+        ] {
+            cargo_util::paths::create_dir_all(ws.join(path).parent().unwrap())?;
+            cargo_util::paths::write(ws.join(path), content)?;
+        }
+        let file = &ws.join("scratch").join("a-1.rs");
+
+        let metadata = crate::workspace::cargo_metadata(&ws.join("Cargo.toml"), ws, true)?;
+        let err = metadata
+            .loose_file_target(file, &ws.join("Cargo.toml"))
+            .unwrap_err();
+        assert_eq!(
+            format!(
+                "`{}` is a virtual manifest. specify the `Cargo.toml` of a member with \
+                 `--deps-from`, or bundle a target of a member with `--bin` instead of `--file`",
+                ws.join("Cargo.toml").display(),
+            ),
+            err.to_string(),
+        );
+
+        let manifest_path = &ws.join("app").join("Cargo.toml");
+        let metadata = crate::workspace::cargo_metadata(manifest_path, ws, true)?;
+        let (target, package) = metadata.loose_file_target(file, manifest_path)?;
+        assert_eq!("app", package.name);
+        assert_eq!("a-1", target.name);
+        assert_eq!(["bin".to_owned()], *target.kind);
+        Ok(())
+    }
+
+    #[test]
+    fn scratch_package_using_current_lockfile_and_cache() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let ws = tempdir.path().join("ws");
+        for (path, content) in [
+            (
+                "Cargo.toml",
+                r#"[workspace]
+members = ["app", "acl", "other", "either", "pre"]
+
+[patch.crates-io]
+either = { path = "either" }
+"#,
+            ),
+            (
+                "app/Cargo.toml",
+                r#"[package]
+name = "app"
+version = "0.0.0"
+edition = "2018"
+
+[dependencies]
+ac-library-rs = { path = "../acl" }
+renamed = { package = "other-lib", path = "../other" }
+either = "1"
+pre = { path = "../pre" }
+"#,
+            ),
+            ("app/src/main.rs", "fn main() {}\n"),
+            (
+                "acl/Cargo.toml",
+                r#"[package]
+name = "ac-library-rs"
+version = "0.1.0"
+edition = "2018"
+
+[lib]
+name = "ac_library"
+"#,
+            ),
+            ("acl/src/lib.rs", ""),
+            (
+                "other/Cargo.toml",
+                "[package]\nname = \"other-lib\"\nversion = \"0.1.0\"\nedition = \"2018\"\n",
+            ),
+            ("other/src/lib.rs", ""),
+            (
+                "either/Cargo.toml",
+                "[package]\nname = \"either\"\nversion = \"1.99.0\"\nedition = \"2018\"\n",
+            ),
+            ("either/src/lib.rs", ""),
+            (
+                "pre/Cargo.toml",
+                "[package]\nname = \"pre\"\nversion = \"0.2.0-beta.1\"\nedition = \"2018\"\n",
+            ),
+            ("pre/src/lib.rs", ""),
+        ] {
+            cargo_util::paths::create_dir_all(ws.join(path).parent().unwrap())?;
+            cargo_util::paths::write(ws.join(path), content)?;
+        }
+
+        let metadata =
+            crate::workspace::cargo_metadata(&ws.join("app").join("Cargo.toml"), &ws, true)?;
+        let (target, package) = metadata.bin_target_by_name("app")?;
+        let exclude = ["ac-library-rs", "other-lib", "either", "pre"]
+            .iter()
+            .map(|s| s.parse::<PackageSpec>().unwrap())
+            .collect::<Vec<_>>();
+
+        let scratch = super::scratch_package_using_current_lockfile_and_cache(
+            &metadata,
+            package,
+            target,
+            &exclude,
+            "fn main() {}\n",
+            None,
+            &tempdir.path().join("cache"),
+            &mut Shell::from_stdout(Box::new(io::sink())),
+        )?;
+        let manifest =
+            cargo_util::paths::read(&scratch.manifest_path)?.parse::<toml_edit::Document>()?;
+        let deps = &manifest["dependencies"];
+
+        assert_eq!(
+            Some(&*ws.join("acl").to_string_lossy()),
+            deps["ac-library-rs"]["path"].as_str(),
+        );
+        assert_eq!(
+            Some(&*ws.join("other").to_string_lossy()),
+            deps["renamed"]["path"].as_str(),
+        );
+        assert_eq!(Some("other-lib"), deps["renamed"]["package"].as_str());
+        assert_eq!(Some("^1"), deps["either"]["version"].as_str());
+        assert_eq!(
+            Some(&*ws.join("either").to_string_lossy()),
+            manifest["patch"]["crates-io"]["either"]["path"].as_str(),
+        );
+        assert_eq!(
+            Some(&*ws.join("pre").to_string_lossy()),
+            deps["pre"]["path"].as_str(),
+        );
+        Ok(())
+    }
+}
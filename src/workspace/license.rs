@@ -1,32 +1,37 @@
 use crate::{
+    package_spec::PackageSpec,
     process::ProcessBuilderExt as _,
     workspace::{PackageExt as _, SourceExt as _},
     User,
 };
-use anyhow::{anyhow, Context as _};
+use anyhow::{anyhow, bail, Context as _};
 use cargo_metadata as cm;
 use cargo_util::ProcessBuilder;
+use itertools::Itertools as _;
 use maplit::btreeset;
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{btree_map, BTreeMap, BTreeSet},
-    path::Path,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
 };
 
 pub(super) fn read_non_unlicense_license_file(
     package: &cm::Package,
     mine: &[User],
     cache_dir: &Path,
+    offline: bool,
 ) -> anyhow::Result<Option<String>> {
     if !mine.is_empty() {
-        let users = users(package, cache_dir)?;
-        if mine.iter().any(|u| users.contains(u)) {
+        let users = users(package, cache_dir, offline)?;
+        if mine.iter().any(|m| users.iter().any(|u| m.owns(u))) {
             return Ok(None);
         }
     }
 
-    return read(package, cache_dir).map_err(|causes| {
+    read(package, cache_dir, offline).map_err(|causes| {
         let err = anyhow!(
             "could not read the license file of `{}`.\n\
              note: cargo-equip no longer reads `package.authors` to skip Copyright and License \
@@ -42,25 +47,17 @@ pub(super) fn read_non_unlicense_license_file(
         } else {
             err
         }
-    });
-
-    #[derive(Deserialize, Serialize)]
-    struct Owners {
-        #[serde(rename = "crates.io")]
-        crates_io: BTreeMap<String, BTreeMap<Version, BTreeSet<String>>>,
-        #[serde(rename = "github.com")]
-        github_com: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>,
-        #[serde(rename = "gitlab.com")]
-        gitlab_com: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>,
-    }
+    })
 }
 
-fn users(package: &cm::Package, cache_dir: &Path) -> anyhow::Result<BTreeSet<User>> {
+// Licenses are read in parallel. Read and write `owners.json` one at a time.
+static OWNERS_JSON: Mutex<()> = Mutex::new(());
+
+fn users(package: &cm::Package, cache_dir: &Path, offline: bool) -> anyhow::Result<BTreeSet<User>> {
     let path = &cache_dir.join("owners.json");
-    let cur_cache = if path.exists() {
-        serde_json::from_str(&cargo_util::paths::read(path)?)?
-    } else {
-        CachedUsers::default()
+    let cur_cache = {
+        let _lock = OWNERS_JSON.lock().unwrap_or_else(PoisonError::into_inner);
+        CachedUsers::read(path)?
     };
     let mut cache = cur_cache.clone();
 
@@ -72,6 +69,13 @@ fn users(package: &cm::Package, cache_dir: &Path) -> anyhow::Result<BTreeSet<Use
                 .or_default()
                 .entry(package.version.clone())
             {
+                btree_map::Entry::Vacant(_) if offline => {
+                    bail!(
+                        "`{}`: the owners are not cached in `{}`",
+                        package.id,
+                        path.display(),
+                    );
+                }
                 btree_map::Entry::Vacant(entry) => {
                     let owners = retrieve_owner_urls(&package.name, cache_dir)?
                         .flat_map(|url| {
@@ -79,10 +83,10 @@ fn users(package: &cm::Package, cache_dir: &Path) -> anyhow::Result<BTreeSet<Use
                                 .map(ToOwned::to_owned)
                         })
                         .collect();
-                    github_users(entry.insert(owners))
+                    crates_io_users(entry.insert(owners))
                 }
                 entry @ btree_map::Entry::Occupied(_) => {
-                    github_users(entry.or_insert_with(|| unreachable!()))
+                    crates_io_users(entry.or_insert_with(|| unreachable!()))
                 }
             }
         } else if let Some([username, _, rev]) = source
@@ -113,48 +117,53 @@ fn users(package: &cm::Package, cache_dir: &Path) -> anyhow::Result<BTreeSet<Use
                 .or_default()
                 .insert((*username).to_owned());
             btreeset!(User::GitlabCom((*username).to_owned()))
+        } else if let Some((host, username)) = host_and_owner(&source.repr) {
+            btreeset!(User::forge(host, username))
         } else {
             btreeset!()
         }
     } else {
-        btreeset!()
+        btreeset!(User::Path(package.manifest_dir().into()))
     };
 
     if cache != cur_cache {
+        let _lock = OWNERS_JSON.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut latest = CachedUsers::read(path)?;
+        latest.merge(cache);
         cargo_util::paths::create_dir_all(cache_dir)?;
-        cargo_util::paths::write(path, cache.to_json())?;
+        cargo_util::paths::write(path, latest.to_json())?;
     }
 
-    if users.is_empty() {
-        if let Some(repository) = &package.repository {
-            if let Some(username) = repository.strip_prefix("https://github.com/") {
-                users.insert(User::Github(username.to_owned()));
-            } else if let Some(username) = repository.strip_prefix("https://gitlab.com/") {
-                users.insert(User::GitlabCom(username.to_owned()));
-            }
+    // `package.repository` is not used, since it is self-declared. The owners of crates.io
+    // packages are only taken from crates.io.
+    if let Some(source) = package
+        .source
+        .as_ref()
+        .filter(|s| s.repr.starts_with("git+"))
+    {
+        if let Some((host, org)) = host_and_owner(&source.repr) {
+            users.insert(User::Org(host.to_owned(), org.to_owned()));
         }
     }
 
     return Ok(users);
 
-    #[derive(Default, Deserialize, Serialize, Clone, PartialEq)]
-    struct CachedUsers {
-        #[serde(rename = "crates.io")]
-        crates_io: BTreeMap<String, BTreeMap<Version, BTreeSet<String>>>,
-        #[serde(rename = "github.com")]
-        github_com: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>,
-        #[serde(rename = "gitlab.com")]
-        gitlab_com: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>,
-    }
-
-    impl CachedUsers {
-        fn to_json(&self) -> String {
-            serde_json::to_string(self).expect("should not fail")
-        }
+    /// Owners on crates.io are GitHub users or teams.
+    fn crates_io_users(names: &BTreeSet<String>) -> BTreeSet<User> {
+        names
+            .iter()
+            .flat_map(|name| [User::Github(name.clone()), User::CratesIo(name.clone())])
+            .collect()
     }
 
-    fn github_users(names: &BTreeSet<String>) -> BTreeSet<User> {
-        names.iter().cloned().map(User::Github).collect()
+    /// Parses `[git+]https://{host}/{owner}/..`.
+    fn host_and_owner(url: &str) -> Option<(&str, &str)> {
+        let url = url.strip_prefix("git+").unwrap_or(url);
+        let url = url.strip_prefix("https://")?;
+        let mut segments = url.split(|c| ['/', '?', '#'].contains(&c));
+        let host = segments.next()?;
+        let owner = segments.next().filter(|s| !s.is_empty())?;
+        Some((host, owner))
     }
 
     fn retrieve_owner_urls(
@@ -187,93 +196,526 @@ fn users(package: &cm::Package, cache_dir: &Path) -> anyhow::Result<BTreeSet<Use
     }
 }
 
-fn read(package: &cm::Package, cache_dir: &Path) -> Result<Option<String>, Vec<String>> {
-    let license = package
-        .license
-        .as_deref()
-        .ok_or_else(|| vec![format!("`{}`: missing `license`", package.id)])?;
+#[derive(Default, Deserialize, Serialize, Clone, PartialEq)]
+struct CachedUsers {
+    #[serde(rename = "crates.io")]
+    crates_io: BTreeMap<String, BTreeMap<Version, BTreeSet<String>>>,
+    #[serde(rename = "github.com")]
+    github_com: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>,
+    #[serde(rename = "gitlab.com")]
+    gitlab_com: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>,
+}
 
-    let license = normalize(license);
+impl CachedUsers {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        Ok(if path.exists() {
+            serde_json::from_str(&cargo_util::paths::read(path)?)?
+        } else {
+            Self::default()
+        })
+    }
 
-    let license = spdx::Expression::parse(license).map_err(|err| {
-        vec![
-            err.to_string(),
-            format!("`{}`: could not parse `license`", package.id),
-        ]
-    })?;
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("should not fail")
+    }
+
+    fn merge(&mut self, other: Self) {
+        merge(&mut self.crates_io, other.crates_io);
+        merge(&mut self.github_com, other.github_com);
+        merge(&mut self.gitlab_com, other.gitlab_com);
+
+        fn merge<K: Ord>(
+            this: &mut BTreeMap<String, BTreeMap<K, BTreeSet<String>>>,
+            other: BTreeMap<String, BTreeMap<K, BTreeSet<String>>>,
+        ) {
+            for (name, other) in other {
+                let this = this.entry(name).or_default();
+                for (k, users) in other {
+                    this.entry(k).or_default().extend(users);
+                }
+            }
+        }
+    }
+}
 
-    let is = |name| license.evaluate(|r| r.license.id() == spdx::license_id(name));
+/// An entry in `owners.json` or `license-files` in the cache directory.
+pub(crate) struct CacheEntry {
+    pub(crate) name: String,
+    /// The version for crates.io, otherwise the Git revision.
+    pub(crate) key: String,
+    pub(crate) kind: CacheEntryKind,
+}
+
+pub(crate) enum CacheEntryKind {
+    Owners {
+        host: &'static str,
+        users: BTreeSet<String>,
+    },
+    LicenseText {
+        path: PathBuf,
+    },
+}
 
-    if is("CC0-1.0") || is("Unlicense") {
-        return Ok(None);
+impl CacheEntry {
+    /// Matches the name, and the version if the entry records one.
+    pub(crate) fn matches(&self, spec: &PackageSpec) -> bool {
+        let version = match &self.kind {
+            CacheEntryKind::Owners { host, .. } if *host == "crates.io" => self.key.parse().ok(),
+            _ => None,
+        };
+        spec.matches_name_and_version(&self.name, version.as_ref())
     }
+}
 
-    let file_names = if is("MIT") {
-        &["LICENSE-MIT", "LICENSE"]
-    } else if is("Apache-2.0") {
-        &["LICENSE-APACHE", "LICENSE"]
-    } else {
-        return Err(vec![format!(
-            "`{}`: unsupported license: `{}`",
-            package.id, license,
-        )]);
+pub(crate) fn cache_entries(cache_dir: &Path) -> anyhow::Result<Vec<CacheEntry>> {
+    let cached_users = {
+        let _lock = OWNERS_JSON.lock().unwrap_or_else(PoisonError::into_inner);
+        CachedUsers::read(&cache_dir.join("owners.json"))?
     };
 
-    find(package.manifest_dir().as_ref(), file_names)
-        .unwrap_or_else(|| {
-            if let Some(source) = &package.source {
-                let (repository, sha1) = if let Some((repository, sha1)) = source.rev_git() {
-                    (repository, sha1.to_owned())
-                } else {
-                    let repository = package.repository.as_deref().with_context(|| {
-                        format!(
-                            "could not retrieve the license file of `{}`: missing `repository` \
-                             field",
-                            package.id,
-                        )
-                    })?;
-                    let sha1 = read_git_sha1(package)?;
-                    (repository, sha1)
+    let mut entries = vec![];
+
+    let mut add_owners = |host, owners: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>| {
+        for (name, owners) in owners {
+            for (key, users) in owners {
+                entries.push(CacheEntry {
+                    name: name.clone(),
+                    key,
+                    kind: CacheEntryKind::Owners { host, users },
+                });
+            }
+        }
+    };
+    add_owners(
+        "crates.io",
+        cached_users
+            .crates_io
+            .into_iter()
+            .map(|(name, owners)| {
+                let owners = owners.into_iter().map(|(v, u)| (v.to_string(), u));
+                (name, owners.collect())
+            })
+            .collect(),
+    );
+    add_owners("github.com", cached_users.github_com);
+    add_owners("gitlab.com", cached_users.gitlab_com);
+
+    entries.extend(cache_entries_of_license_files(cache_dir)?);
+    entries.sort_by(|e1, e2| (&e1.name, &e1.key).cmp(&(&e2.name, &e2.key)));
+    Ok(entries)
+}
+
+fn cache_entries_of_license_files(cache_dir: &Path) -> anyhow::Result<Vec<CacheEntry>> {
+    let mut entries = vec![];
+    let license_files = &cache_dir.join("license-files");
+    if license_files.exists() {
+        for package_dir in fs::read_dir(license_files)? {
+            let package_dir = package_dir?.path();
+            for file in fs::read_dir(&package_dir)? {
+                let path = file?.path();
+                entries.push(CacheEntry {
+                    name: file_name(&package_dir),
+                    key: file_name(&path),
+                    kind: CacheEntryKind::LicenseText { path },
+                });
+            }
+        }
+    }
+    return Ok(entries);
+
+    fn file_name(path: &Path) -> String {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Removes the entries that satisfy `pred`, and returns them.
+pub(crate) fn remove_cache_entries(
+    cache_dir: &Path,
+    pred: impl Fn(&CacheEntry) -> bool,
+) -> anyhow::Result<Vec<CacheEntry>> {
+    let _lock = OWNERS_JSON.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let path = &cache_dir.join("owners.json");
+    let mut cached_users = CachedUsers::read(path)?;
+    let mut removed = vec![];
+
+    retain(
+        &mut cached_users.crates_io,
+        "crates.io",
+        &pred,
+        &mut removed,
+    );
+    retain(
+        &mut cached_users.github_com,
+        "github.com",
+        &pred,
+        &mut removed,
+    );
+    retain(
+        &mut cached_users.gitlab_com,
+        "gitlab.com",
+        &pred,
+        &mut removed,
+    );
+    if !removed.is_empty() {
+        cargo_util::paths::write(path, cached_users.to_json())?;
+    }
+
+    for entry in cache_entries_of_license_files(cache_dir)? {
+        if pred(&entry) {
+            if let CacheEntryKind::LicenseText { path } = &entry.kind {
+                cargo_util::paths::remove_file(path)?;
+                let package_dir = path.with_file_name("");
+                if fs::read_dir(&package_dir)?.next().is_none() {
+                    fs::remove_dir(package_dir)?;
+                }
+            }
+            removed.push(entry);
+        }
+    }
+
+    return Ok(removed);
+
+    fn retain<K: Ord + ToString>(
+        owners: &mut BTreeMap<String, BTreeMap<K, BTreeSet<String>>>,
+        host: &'static str,
+        pred: impl Fn(&CacheEntry) -> bool,
+        removed: &mut Vec<CacheEntry>,
+    ) {
+        for (name, owners) in &mut *owners {
+            owners.retain(|key, users| {
+                let entry = CacheEntry {
+                    name: name.clone(),
+                    key: key.to_string(),
+                    kind: CacheEntryKind::Owners {
+                        host,
+                        users: users.clone(),
+                    },
                 };
+                let remove = pred(&entry);
+                if remove {
+                    removed.push(entry);
+                }
+                !remove
+            });
+        }
+        owners.retain(|_, owners| !owners.is_empty());
+    }
+}
+
+/// Removes the cached owners and license text of `package`, then retrieves them again.
+///
+/// The removed entries are restored if the retrieval fails.
+pub(crate) fn refresh_cache(package: &cm::Package, cache_dir: &Path) -> anyhow::Result<()> {
+    let keys = [
+        Some(package.version.to_string()),
+        package
+            .source
+            .as_ref()
+            .and_then(|s| s.rev_git())
+            .map(|(_, rev)| rev.to_owned()),
+        read_git_sha1(package).ok(),
+    ];
+    let is_target =
+        |entry: &CacheEntry| entry.name == package.name && keys.contains(&Some(entry.key.clone()));
+
+    let license_texts = cache_entries_of_license_files(cache_dir)?
+        .into_iter()
+        .filter(is_target)
+        .map(|entry| match entry.kind {
+            CacheEntryKind::LicenseText { path } => {
+                let text = cargo_util::paths::read(&path)?;
+                Ok((path, text))
+            }
+            CacheEntryKind::Owners { .. } => unreachable!(),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-                let cache_path = &cache_dir
-                    .join("license-files")
-                    .join(&package.name)
-                    .join(&sha1);
+    let removed = remove_cache_entries(cache_dir, is_target)?;
 
-                if cache_path.exists() {
-                    return cargo_util::paths::read(cache_path);
+    let result = users(package, cache_dir, false).and_then(|_| {
+        read(package, cache_dir, false)
+            .map_err(|causes| anyhow!("{}", causes.iter().rev().join(": ")))
+    });
+
+    if result.is_err() {
+        let _lock = OWNERS_JSON.lock().unwrap_or_else(PoisonError::into_inner);
+        let path = &cache_dir.join("owners.json");
+        let mut cached_users = CachedUsers::read(path)?;
+        for entry in removed {
+            if let CacheEntryKind::Owners { host, users } = entry.kind {
+                match host {
+                    "crates.io" => {
+                        let version = entry.key.parse()?;
+                        cached_users
+                            .crates_io
+                            .entry(entry.name)
+                            .or_default()
+                            .insert(version, users);
+                    }
+                    "github.com" => {
+                        let owners = cached_users.github_com.entry(entry.name).or_default();
+                        owners.insert(entry.key, users);
+                    }
+                    _ => {
+                        let owners = cached_users.gitlab_com.entry(entry.name).or_default();
+                        owners.insert(entry.key, users);
+                    }
                 }
+            }
+        }
+        cargo_util::paths::write(path, cached_users.to_json())?;
+        for (path, text) in license_texts {
+            cargo_util::paths::create_dir_all(path.with_file_name(""))?;
+            cargo_util::paths::write(path, text)?;
+        }
+    }
 
-                let content =
-                    find_in_git_repos(repository, &sha1, file_names)?.with_context(|| {
-                        format!(
-                            "could not retrieve the license file of `{}`: could not find {:?}",
-                            package.id, file_names,
-                        )
-                    })?;
+    result.map(drop)
+}
 
-                cargo_util::paths::create_dir_all(cache_path.with_file_name(""))?;
-                cargo_util::paths::write(cache_path, &content)?;
+fn read(
+    package: &cm::Package,
+    cache_dir: &Path,
+    offline: bool,
+) -> Result<Option<String>, Vec<String>> {
+    let licenses = choose_licenses(package)?
+        .into_iter()
+        .map(|i| LICENSES[i])
+        .filter(|(_, file_names)| !file_names.is_empty())
+        .collect::<Vec<_>>();
+
+    // A conjunction requires every notice, so the shared `LICENSE` is not enough for it.
+    let mut texts = vec![];
+    for &(license, file_names) in &licenses {
+        let text = if licenses.len() > 1 {
+            let file_names = &file_names[..file_names.len() - 1];
+            read_license_file(package, file_names, Some(license), cache_dir, offline).map_err(
+                |e| {
+                    vec![
+                        e.to_string(),
+                        format!(
+                            "`{}`: `{}` requires a separate license file for each license, but \
+                             none was found for `{}`",
+                            package.id,
+                            licenses.iter().map(|(l, _)| l).join(" AND "),
+                            license,
+                        ),
+                    ]
+                },
+            )?
+        } else {
+            read_license_file(package, file_names, None, cache_dir, offline)
+                .map_err(|e| vec![e.to_string()])?
+        };
+        texts.push(text);
+    }
+    Ok((!texts.is_empty()).then(|| texts.join("\n")))
+}
 
-                Ok(content)
+/// Reads one of `file_names` in the package, the cache, or the repository.
+///
+/// The cache file is named after the revision, and `license` is appended to it if given.
+fn read_license_file(
+    package: &cm::Package,
+    file_names: &[&str],
+    license: Option<&str>,
+    cache_dir: &Path,
+    offline: bool,
+) -> anyhow::Result<String> {
+    find(package.manifest_dir().as_ref(), file_names).unwrap_or_else(|| {
+        if let Some(source) = &package.source {
+            let (repository, sha1) = if let Some((repository, sha1)) = source.rev_git() {
+                (repository, sha1.to_owned())
             } else {
-                let repository = package
-                    .manifest_dir()
-                    .ancestors()
-                    .find(|p| p.join(".git").is_dir())
+                let repository = package.repository.as_deref().with_context(|| {
+                    format!(
+                        "could not retrieve the license file of `{}`: missing `repository` \
+                             field",
+                        package.id,
+                    )
+                })?;
+                let sha1 = read_git_sha1(package)?;
+                (repository, sha1)
+            };
+
+            let cache_path = &cache_dir.join("license-files").join(&package.name).join(
+                if let Some(license) = license {
+                    format!("{}-{}", sha1, license)
+                } else {
+                    sha1.clone()
+                },
+            );
+
+            if cache_path.exists() {
+                return cargo_util::paths::read(cache_path);
+            }
+
+            if offline {
+                return find_in_git_checkouts(&sha1, file_names)
+                    .transpose()?
                     .with_context(|| {
                         format!(
-                            "could not find a license file in `{}`",
-                            package.manifest_dir()
+                            "`{}`: could not find {:?} in `{}` or the Git checkouts of Cargo",
+                            package.id,
+                            file_names,
+                            cache_path.with_file_name("").display(),
                         )
-                    })?;
-                find(repository.as_ref(), file_names)
-                    .with_context(|| format!("could not find a license file in `{}`", repository))?
+                    });
             }
+
+            let content = find_in_git_repos(repository, &sha1, file_names)?.with_context(|| {
+                format!(
+                    "could not retrieve the license file of `{}`: could not find {:?}",
+                    package.id, file_names,
+                )
+            })?;
+
+            cargo_util::paths::create_dir_all(cache_path.with_file_name(""))?;
+            cargo_util::paths::write(cache_path, &content)?;
+
+            Ok(content)
+        } else {
+            let repository = package
+                .manifest_dir()
+                .ancestors()
+                .find(|p| p.join(".git").is_dir())
+                .with_context(|| {
+                    format!(
+                        "could not find a license file in `{}`",
+                        package.manifest_dir()
+                    )
+                })?;
+            find(repository.as_ref(), file_names)
+                .with_context(|| format!("could not find a license file in `{}`", repository))?
+        }
+    })
+}
+
+/// Licenses cargo-equip can comply with, from the least burdensome one.
+///
+/// The ones without file names do not require notices. The last file name is the shared fallback.
+static LICENSES: &[(&str, &[&str])] = &[
+    ("CC0-1.0", &[]),
+    ("Unlicense", &[]),
+    ("0BSD", &[]),
+    ("MIT", &["LICENSE-MIT", "LICENSE"]),
+    ("ISC", &["LICENSE-ISC", "LICENSE"]),
+    ("BSD-2-Clause", &["LICENSE-BSD", "LICENSE"]),
+    ("BSD-3-Clause", &["LICENSE-BSD", "LICENSE"]),
+    ("Zlib", &["LICENSE-ZLIB", "LICENSE"]),
+    ("Apache-2.0", &["LICENSE-APACHE", "LICENSE"]),
+];
+
+/// Returns the least burdensome licenses that satisfy `package.license`, joined with `AND`.
+pub(super) fn chosen_license(package: &cm::Package) -> Option<String> {
+    let licenses = choose_licenses(package).ok()?;
+    Some(licenses.iter().map(|&i| LICENSES[i].0).join(" AND "))
+}
+
+fn choose_licenses(package: &cm::Package) -> Result<Vec<usize>, Vec<String>> {
+    let license = package
+        .license
+        .as_deref()
+        .ok_or_else(|| vec![format!("`{}`: missing `license`", package.id)])?;
+
+    let license = spdx::Expression::parse(normalize(license)).map_err(|err| {
+        vec![
+            err.to_string(),
+            format!("`{}`: could not parse `license`", package.id),
+        ]
+    })?;
+
+    choose(&license).ok_or_else(|| {
+        vec![format!(
+            "`{}`: unsupported license: `{}`",
+            package.id, license,
+        )]
+    })
+}
+
+/// Returns the indices in `LICENSES` of the fewest and least burdensome licenses with which
+/// `license` is satisfied.
+fn choose(license: &spdx::Expression) -> Option<Vec<usize>> {
+    let satisfied = |chosen: &[usize]| {
+        license.evaluate(|r| {
+            r.license.id().is_some()
+                && chosen
+                    .iter()
+                    .any(|&i| r.license.id() == spdx::license_id(LICENSES[i].0))
         })
-        .map(Some)
-        .map_err(|e| vec![e.to_string()])
+    };
+
+    // The shortest prefix of `LICENSES` that satisfies it, without the ones it does not need.
+    let mut chosen = (1..=LICENSES.len())
+        .map(|n| (0..n).collect::<Vec<_>>())
+        .find(|chosen| satisfied(chosen))?;
+    for i in (0..chosen.len()).rev() {
+        let mut fewer = chosen.clone();
+        fewer.remove(i);
+        if satisfied(&fewer) {
+            chosen = fewer;
+        }
+    }
+    Some(chosen)
+}
+
+/// `--allow-license` and `--deny-license`.
+#[derive(Debug, Default)]
+pub(crate) struct LicensePolicy {
+    allow: Vec<spdx::Licensee>,
+    deny: Vec<spdx::Licensee>,
+}
+
+impl LicensePolicy {
+    pub(crate) fn new(allow: &[String], deny: &[String]) -> anyhow::Result<Self> {
+        let parse = |licenses: &[String]| {
+            licenses
+                .iter()
+                .map(|license| {
+                    spdx::Licensee::parse(license)
+                        .map_err(|e| anyhow!("{}", e))
+                        .with_context(|| format!("could not parse `{}`", license))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Returns the reason if `package` can only be used under licenses outside the policy.
+    ///
+    /// Packages with missing or unparsable `license` are rejected.
+    pub(crate) fn check(&self, package: &cm::Package) -> Result<(), String> {
+        self.check_license(package.license.as_deref())
+    }
+
+    fn check_license(&self, license: Option<&str>) -> Result<(), String> {
+        let license = license.ok_or_else(|| "missing `license`".to_owned())?;
+
+        let license = spdx::Expression::parse(normalize(license))
+            .map_err(|e| format!("could not parse `license`: {}", e))?;
+
+        let allowed = |req: &spdx::LicenseReq| {
+            (self.allow.is_empty() || self.allow.iter().any(|l| l.satisfies(req)))
+                && !self.deny.iter().any(|l| l.satisfies(req))
+        };
+
+        if license.evaluate(allowed) {
+            Ok(())
+        } else {
+            Err(format!("licensed under `{}`", license))
+        }
+    }
 }
 
 fn normalize(license: &str) -> &str {
@@ -331,6 +773,20 @@ fn find_in_git_repos(url: &str, sha1: &str, file_names: &[&str]) -> anyhow::Resu
     result
 }
 
+/// Looks for the files in `$CARGO_HOME/git/checkouts/*/<short ID of the revision>`.
+fn find_in_git_checkouts(sha1: &str, file_names: &[&str]) -> Option<anyhow::Result<String>> {
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(dirs_next::home_dir()?.join(".cargo")))?;
+    let short_id = sha1.get(..7)?;
+    fs::read_dir(cargo_home.join("git").join("checkouts"))
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path().join(short_id))
+        .filter(|checkout| checkout.is_dir())
+        .find_map(|checkout| find(&checkout, file_names))
+}
+
 fn find(dir: &Path, file_names: &[&str]) -> Option<anyhow::Result<String>> {
     let path = &file_names
         .iter()
@@ -338,3 +794,129 @@ fn find(dir: &Path, file_names: &[&str]) -> Option<anyhow::Result<String>> {
         .find(|path| path.exists())?;
     Some(cargo_util::paths::read(path))
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn choose() {
+        fn test(license: &str, expected: Option<&[&str]>) {
+            let license = spdx::Expression::parse(super::normalize(license)).unwrap();
+            let chosen = super::choose(&license).map(|chosen| {
+                chosen
+                    .iter()
+                    .map(|&i| super::LICENSES[i].0)
+                    .collect::<Vec<_>>()
+            });
+            assert_eq!(expected.map(<[_]>::to_vec), chosen, "{}", license);
+        }
+
+        test("MIT", Some(&["MIT"]));
+        test("MIT OR Apache-2.0", Some(&["MIT"]));
+        test("MIT/Apache-2.0", Some(&["MIT"]));
+        test("Apache-2.0 OR CC0-1.0", Some(&["CC0-1.0"]));
+        test("MIT AND Apache-2.0", Some(&["MIT", "Apache-2.0"]));
+        test("(MIT OR Apache-2.0) AND ISC", Some(&["MIT", "ISC"]));
+        test("Apache-2.0 AND (MIT OR Zlib)", Some(&["MIT", "Apache-2.0"]));
+        test("MIT AND Unlicense", Some(&["Unlicense", "MIT"]));
+        test("GPL-3.0", None);
+        test("MIT AND GPL-3.0", None);
+        test("MIT OR GPL-3.0", Some(&["MIT"]));
+    }
+
+    #[test]
+    fn read_conjunction() -> anyhow::Result<()> {
+        let tempdir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+
+        let read = |dir: &str, files: &[(&str, &str)]| -> anyhow::Result<_> {
+            let pkg = tempdir.path().join(dir);
+            cargo_util::paths::create_dir_all(pkg.join("src"))?;
+            cargo_util::paths::write(
+                pkg.join("Cargo.toml"),
+                r#"[package]
+name = "lib"
+version = "0.0.0"
+edition = "2018"
+license = "MIT AND Apache-2.0"
+
+[workspace]
+"#,
+            )?;
+            cargo_util::paths::write(pkg.join("src").join("lib.rs"), "")?;
+            for (name, content) in files {
+                cargo_util::paths::write(pkg.join(name), content)?;
+            }
+            let metadata = crate::workspace::cargo_metadata(&pkg.join("Cargo.toml"), &pkg, true)?;
+            Ok(super::read(
+                &metadata.packages[0],
+                &tempdir.path().join("cache"),
+                true,
+            ))
+        };
+
+        assert_eq!(
+            Ok(Some("MIT text\nApache text".to_owned())),
+            read(
+                "both",
+                &[
+                    ("LICENSE-MIT", "MIT text"),
+                    ("LICENSE-APACHE", "Apache text")
+                ]
+            )?,
+        );
+
+        let err = read("shared", &[("LICENSE", "MIT text")])?.unwrap_err();
+        assert!(err[1].contains("none was found for `MIT`"), "{:?}", err);
+
+        let err = read("mit", &[("LICENSE-MIT", "MIT text"), ("LICENSE", "")])?.unwrap_err();
+        assert!(
+            err[1].contains("none was found for `Apache-2.0`"),
+            "{:?}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn license_policy_check() {
+        let policy = |allow: &[&str], deny: &[&str]| {
+            let to_vec = |ls: &[&str]| ls.iter().map(|&l| l.to_owned()).collect::<Vec<_>>();
+            super::LicensePolicy::new(&to_vec(allow), &to_vec(deny)).unwrap()
+        };
+        let allowed = |policy: &super::LicensePolicy, license: Option<&str>| {
+            policy.check_license(license).is_ok()
+        };
+
+        let permissive = policy(&["MIT", "Apache-2.0"], &[]);
+        assert!(allowed(&permissive, Some("MIT")));
+        assert!(allowed(&permissive, Some("MIT OR GPL-3.0")));
+        assert!(allowed(&permissive, Some("MIT/Apache-2.0")));
+        assert!(allowed(&permissive, Some("MIT AND Apache-2.0")));
+        assert!(!allowed(&permissive, Some("MIT AND ISC")));
+        assert!(!allowed(&permissive, Some("GPL-3.0")));
+        assert!(!allowed(&permissive, None));
+        assert!(!allowed(&permissive, Some("not a license")));
+
+        let no_gpl = policy(&[], &["GPL-3.0"]);
+        assert!(allowed(&no_gpl, Some("ISC")));
+        assert!(allowed(&no_gpl, Some("MIT OR GPL-3.0")));
+        assert!(!allowed(&no_gpl, Some("GPL-3.0")));
+        assert!(!allowed(&no_gpl, Some("MIT AND GPL-3.0")));
+
+        let both = policy(&["MIT", "GPL-3.0"], &["GPL-3.0"]);
+        assert!(allowed(&both, Some("MIT OR GPL-3.0")));
+        assert!(!allowed(&both, Some("GPL-3.0")));
+
+        assert_eq!(
+            Err("licensed under `GPL-3.0`".to_owned()),
+            no_gpl.check_license(Some("GPL-3.0")),
+        );
+        assert_eq!(
+            Err("missing `license`".to_owned()),
+            no_gpl.check_license(None),
+        );
+    }
+}